@@ -1,12 +1,12 @@
 use anyhow::Result;
 use clap::Parser;
-use gcn_static_patcher::{find_app_dir, load_mod_data, run_cli_mode, Args};
+use gcn_static_patcher::{find_app_dir, load_mod_data, print_error, run_cli_mode, run_command, warning_log, Args};
 
 fn main() -> Result<()> {
   // Initialize logging
   let log_file_path = find_app_dir().join("patcher.log");
-  println!("Log file path: {:?}", log_file_path);
-  fern::Dispatch::new()
+  eprintln!("Log file path: {:?}", log_file_path);
+  let formatted_log = fern::Dispatch::new()
     .format(|out, message, record| {
       out.finish(format_args!(
         "{}[{}][{}] {}",
@@ -17,19 +17,24 @@ fn main() -> Result<()> {
       ))
     })
     .level(log::LevelFilter::Info)
-    .chain(std::io::stdout())
-    .chain(fern::log_file(log_file_path)?)
+    .chain(std::io::stderr())
+    .chain(fern::log_file(log_file_path)?);
+  fern::Dispatch::new()
+    .chain(formatted_log)
+    .chain(warning_log())
     .apply()?;
 
 
   let args = Args::parse();
+  if let Some(command) = &args.command {
+    return run_command(command, args.message_format);
+  }
 
   let mod_path = std::env::current_dir()?
     .join(&args.mod_file);
 
-  let mod_data = load_mod_data(mod_path);
-
-  let mod_data = mod_data?;
+  let mod_data = load_mod_data(mod_path)
+    .inspect_err(|e| print_error(args.message_format, None, e))?;
   run_cli_mode(&args, mod_data)?;
 
   Ok(())
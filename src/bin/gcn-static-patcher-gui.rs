@@ -20,7 +20,10 @@ use gcn_static_patcher::{
   Progress,
   find_app_dir,
   handle_patch_for_file,
+  run_command,
   load_mod_data,
+  print_error,
+  warning_log,
   run_cli_mode,
 };
 
@@ -29,8 +32,8 @@ fn main() -> Result<()> {
   let log_dir = log_dir();
   fs::create_dir_all(&log_dir)?;
   let log_file_path = log_dir.join("patcher.log");
-  println!("Log file path: {:?}", log_file_path);
-  fern::Dispatch::new()
+  eprintln!("Log file path: {:?}", log_file_path);
+  let formatted_log = fern::Dispatch::new()
     .format(|out, message, record| {
       out.finish(format_args!(
         "{}[{}][{}] {}",
@@ -41,11 +44,17 @@ fn main() -> Result<()> {
       ))
     })
     .level(log::LevelFilter::Info)
-    .chain(std::io::stdout())
-    .chain(fern::log_file(log_file_path)?)
+    .chain(std::io::stderr())
+    .chain(fern::log_file(log_file_path)?);
+  fern::Dispatch::new()
+    .chain(formatted_log)
+    .chain(warning_log())
     .apply()?;
 
   let args = Args::parse();
+  if let Some(command) = &args.command {
+    return run_command(command, args.message_format);
+  }
 
   let mut mod_path = std::env::current_dir()?
     .join(&args.mod_file);
@@ -57,7 +66,8 @@ fn main() -> Result<()> {
   let mod_data = load_mod_data(mod_path);
 
   if args.input_file.is_some() {
-    let mod_data = mod_data?;
+    let mod_data = mod_data
+      .inspect_err(|e| print_error(args.message_format, None, e))?;
     run_cli_mode(&args, mod_data)?;
   } else {
     let mod_data = mod_data.ok();
@@ -129,6 +139,8 @@ struct PatcherApp {
   mod_data_tx: Sender<ModData>,
   ignore_hash: bool,
   overwrite_output: bool,
+  repatch: bool,
+  rebuild: bool,
 }
 
 impl PatcherApp {
@@ -137,6 +149,8 @@ impl PatcherApp {
     let (mod_data_tx, mod_data_rx) = mpsc::channel();
     let ignore_hash = args.ignore_hash;
     let overwrite_output = args.overwrite;
+    let repatch = args.repatch;
+    let rebuild = args.rebuild;
     Self {
       mod_data,
       progress: Progress::new(0, 0, "Idle".to_string()),
@@ -146,6 +160,8 @@ impl PatcherApp {
       mod_data_tx,
       ignore_hash,
       overwrite_output,
+      repatch,
+      rebuild,
     }
   }
 }
@@ -178,6 +194,8 @@ impl eframe::App for PatcherApp {
           ui.add_space(15.0);
           ui.checkbox(&mut self.overwrite_output, "Overwrite existing");
           ui.checkbox(&mut self.ignore_hash, "Ignore hash check");
+          ui.checkbox(&mut self.repatch, "Re-patch from original");
+          ui.checkbox(&mut self.rebuild, "Rebuild ISO");
 
           if self.ignore_hash {
             ui.colored_label(egui::Color32::from_rgb(200, 20, 20), "Warning: Modified inputs may cause the patch to fail or the game to crash");
@@ -246,6 +264,8 @@ impl PatcherApp {
         mod_data_clone.config.expected_dol_hash = None;
       }
       mod_data_clone.overwrite_output = self.overwrite_output;
+      mod_data_clone.repatch = self.repatch;
+      mod_data_clone.rebuild = self.rebuild;
     }
 
     // Spawn a new thread to handle the patching
@@ -266,9 +286,9 @@ impl PatcherApp {
       match result {
         Ok(out_path) => {
           match out_path {
-            PatchResult::Dol(path) | PatchResult::Iso(path) => {
-              info!("Patched DOL file created at: {:?}", path);
-              let message = format!("Done! {:?}", path);
+            PatchResult::Dol(output) | PatchResult::Iso(output) => {
+              info!("Patched DOL file created at: {:?}", output.path);
+              let message = format!("Done! {:?}", output.path);
               progress_tx.send(Progress::new(1, 1, message)).ok();
               ctx_clone.request_repaint();
             }
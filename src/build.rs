@@ -0,0 +1,278 @@
+//! Building ISOs from a directory in the layout of Dolphin's "Extract Entire Disc",
+//! as written by [`crate::extract`]. The FST is generated from `files/`, `sys/fst.bin` is ignored.
+use crate::binstream::{BinStreamReadable, BinStreamWritable};
+use crate::embedded::{read_embedded, TAG_REVERT_ISO};
+use crate::error::PatchError;
+use crate::gcdisc::{FSTEntry, GCDiscHeader, APPLOADER_HEADER_SIZE, APPLOADER_OFFSET, BI2_OFFSET, BI2_SIZE, DISC_HEADER_SIZE, FST};
+use crate::patch_iso::RebuildLayout;
+use crate::progress::Progress;
+use anyhow::Result;
+use log::{info, warn};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Banner shown in the GameCube menu
+const BANNER_FILE_NAME: &str = "opening.bnr";
+
+/// `game_extracted` -> `game_extracted.iso`
+pub fn default_build_path(in_dir: &Path) -> PathBuf {
+  let name = in_dir.file_name().unwrap_or_default().to_string_lossy();
+  in_dir.with_file_name(format!("{}.iso", name))
+}
+
+/// Build an ISO from `in_dir`. The DOL, FST and files are laid out like a rebuilt ISO,
+/// in the order of their sorted paths with the banner first.
+pub fn build_iso<F>(
+  progress_update: F,
+  in_dir: &Path,
+  out_path: &Path,
+  overwrite: bool,
+) -> Result<()> where
+  F: Fn(Progress),
+{
+  if !overwrite && out_path.exists() {
+    return Err(PatchError::OutputExists { path: out_path.to_path_buf() }.into());
+  }
+
+  info!("Reading {:?}", in_dir);
+  let sys_dir = in_dir.join("sys");
+  let boot_bytes = read_sys_file(&sys_dir, "boot.bin")?;
+  let bi2_bytes = read_sys_file(&sys_dir, "bi2.bin")?;
+  let apploader_bytes = read_sys_file(&sys_dir, "apploader.img")?;
+  let dol_bytes = read_sys_file(&sys_dir, "main.dol")?;
+  if boot_bytes.len() != DISC_HEADER_SIZE as usize {
+    return Err(anyhow::anyhow!("sys/boot.bin is {} bytes, expected {}", boot_bytes.len(), DISC_HEADER_SIZE));
+  }
+  if bi2_bytes.len() != BI2_SIZE as usize {
+    return Err(anyhow::anyhow!("sys/bi2.bin is {} bytes, expected {}", bi2_bytes.len(), BI2_SIZE));
+  }
+  if apploader_bytes.len() < APPLOADER_HEADER_SIZE as usize {
+    return Err(anyhow::anyhow!("sys/apploader.img is too small to be an apploader"));
+  }
+  // ISO revert records describe the layout of the disc the DOL was patched into
+  if read_embedded(&dol_bytes)?.is_some_and(|embedded| embedded.get(TAG_REVERT_ISO).is_some()) {
+    warn!("sys/main.dol was patched into an ISO, the built ISO can't be reverted");
+  }
+
+  let mut disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&boot_bytes))?;
+  info!("Disk name: {}", disc_header.name_string());
+  let mut fst = FST {
+    root: FSTEntry::Directory { name: String::new(), children: Vec::new() },
+  };
+  let mut sources = Vec::new();
+  read_dir_tree(&in_dir.join("files"), "", &mut fst.root, &mut sources)?;
+  // patching writes a mod's banner at the start of the user area, where original discs have it
+  sources.sort_by_key(|(path, _, _)| !path.eq_ignore_ascii_case(BANNER_FILE_NAME));
+  // offsets don't change the size of the FST
+  let fst_size = {
+    let mut fst_bytes_vec = Vec::new();
+    fst.write_to_stream(&mut Cursor::new(&mut fst_bytes_vec))?;
+    fst_bytes_vec.len() as u32
+  };
+
+  let mut layout = RebuildLayout::new(APPLOADER_OFFSET + apploader_bytes.len() as u32, dol_bytes.len() as u32, fst_size);
+  let mut file_offsets = Vec::with_capacity(sources.len());
+  for (path, _, length) in &sources {
+    let file_offset = layout.place_file(*length);
+    if let Some(FSTEntry::File { offset, .. }) = fst.root.find_path_mut(path) {
+      *offset = file_offset;
+    }
+    file_offsets.push(file_offset);
+  }
+  let size = layout.finish(&mut disc_header)?;
+  info!("Built ISO is {} bytes, {} files", size, sources.len());
+
+  let mut fst_bytes = Vec::new();
+  fst.write_to_stream(&mut Cursor::new(&mut fst_bytes))?;
+  let mut disc_header_bytes = Vec::new();
+  disc_header.write_to_stream(&mut Cursor::new(&mut disc_header_bytes))?;
+
+  let output_file = fs::File::options()
+    .create(true).write(true).read(true).truncate(true)
+    .open(out_path)?;
+  output_file.set_len(size as u64)?;
+  let mut output_file_mmap = unsafe { memmap2::MmapOptions::new().map_mut(&output_file)? };
+  let system_writes = [
+    (0, &disc_header_bytes),
+    (BI2_OFFSET, &bi2_bytes),
+    (APPLOADER_OFFSET, &apploader_bytes),
+    (layout.dol_offset, &dol_bytes),
+    (layout.fst_offset, &fst_bytes),
+  ];
+  for (offset, data) in system_writes {
+    let offset = offset as usize;
+    output_file_mmap[offset..offset + data.len()].copy_from_slice(data);
+  }
+
+  let total_length: u64 = sources.iter().map(|(_, _, length)| *length as u64).sum();
+  let mut processed_bytes = 0;
+  let mut last_update = 0;
+  progress_update(Progress::new(0, total_length, "Writing ISO".to_string()));
+  for ((path, source, length), offset) in sources.iter().zip(file_offsets) {
+    info!("Writing {}...", path);
+    let data = fs::read(source)
+      .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", source, e))?;
+    if data.len() != *length as usize {
+      return Err(anyhow::anyhow!("{:?} changed while building the ISO", source));
+    }
+    let offset = offset as usize;
+    output_file_mmap[offset..offset + data.len()].copy_from_slice(&data);
+    processed_bytes += data.len() as u64;
+    // only update ever 1MB to avoid spamming the UI
+    if processed_bytes - last_update >= 1024 * 1024 {
+      last_update = processed_bytes;
+      progress_update(Progress::new(processed_bytes, total_length, "Writing ISO".to_string()));
+    }
+  }
+
+  info!("Closing files...");
+  output_file_mmap.flush()?;
+  progress_update(Progress::new(0, 0, "Done building ISO".to_string()));
+  Ok(())
+}
+
+fn read_sys_file(sys_dir: &Path, name: &str) -> Result<Vec<u8>> {
+  let path = sys_dir.join(name);
+  fs::read(&path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))
+}
+
+/// Add the contents of `dir` to the FST directory `entry`, sorted by name ignoring case.
+/// Files are added to `sources` as `(FST path, path on disk, length)`.
+fn read_dir_tree(dir: &Path, prefix: &str, entry: &mut FSTEntry, sources: &mut Vec<(String, PathBuf, u32)>) -> Result<()> {
+  let mut dir_entries = fs::read_dir(dir)
+    .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", dir, e))?
+    .collect::<std::io::Result<Vec<_>>>()?;
+  dir_entries.sort_by_key(|dir_entry| dir_entry.file_name().to_ascii_lowercase());
+  let mut previous_name: Option<String> = None;
+  for dir_entry in dir_entries {
+    let name = dir_entry.file_name().into_string()
+      .map_err(|name| anyhow::anyhow!("{:?} is not a valid file name", name))?;
+    let path = format!("{}{}", prefix, name);
+    // the game looks up paths ignoring case
+    if previous_name.as_ref().is_some_and(|previous_name| previous_name.eq_ignore_ascii_case(&name)) {
+      return Err(anyhow::anyhow!("{} is in files/ twice with different case", path));
+    }
+    let metadata = fs::metadata(dir_entry.path())?;
+    if metadata.is_dir() {
+      let mut child = FSTEntry::Directory { name: name.clone(), children: Vec::new() };
+      read_dir_tree(&dir_entry.path(), &format!("{}/", path), &mut child, sources)?;
+      entry.add_child(child)?;
+    } else {
+      let length = u32::try_from(metadata.len())
+        .map_err(|_| anyhow::anyhow!("{} is too large for a GameCube disc", path))?;
+      entry.add_child(FSTEntry::File { name: name.clone(), offset: 0, length })?;
+      sources.push((path, dir_entry.path(), length));
+    }
+    previous_name = Some(name);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::extract::extract_iso;
+
+  /// A directory in the system's temporary directory, removed when dropped
+  pub(crate) struct TestDir(pub PathBuf);
+
+  impl TestDir {
+    pub(crate) fn new(name: &str) -> TestDir {
+      let path = std::env::temp_dir().join(format!("gcn-static-patcher-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&path);
+      fs::create_dir_all(&path).unwrap();
+      TestDir(path)
+    }
+  }
+
+  impl Drop for TestDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  /// Files of the test tree, `(path, contents)`
+  pub(crate) fn test_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+      ("Audio/music.dsp", vec![1; 0x40]),
+      ("Audio/Voice/line.dsp", vec![2; 0x21]),
+      ("empty.bin", Vec::new()),
+      ("opening.bnr", vec![3; 0x100]),
+      ("zz.bin", vec![4; 0x10]),
+    ]
+  }
+
+  /// An extracted disc: a DOL with one text section and [`test_files`]
+  pub(crate) fn write_test_tree(dir: &Path) {
+    let mut boot = vec![0; DISC_HEADER_SIZE as usize];
+    boot[..6].copy_from_slice(b"GTST01");
+    boot[0x20..0x29].copy_from_slice(b"Test Game");
+    let mut apploader = vec![5; 0x60];
+    apploader[0x14..0x18].copy_from_slice(&0x30u32.to_be_bytes());
+    apploader[0x18..0x1C].copy_from_slice(&0x10u32.to_be_bytes());
+    let mut dol = vec![0; 0x100];
+    dol[0x00..0x04].copy_from_slice(&0x100u32.to_be_bytes());
+    dol[0x48..0x4C].copy_from_slice(&0x8000_3100u32.to_be_bytes());
+    dol[0x90..0x94].copy_from_slice(&0x20u32.to_be_bytes());
+    dol[0xE0..0xE4].copy_from_slice(&0x8000_3100u32.to_be_bytes());
+    dol.extend_from_slice(&[6; 0x20]);
+
+    let sys_dir = dir.join("sys");
+    fs::create_dir_all(&sys_dir).unwrap();
+    for (name, data) in [("boot.bin", boot), ("bi2.bin", vec![7; BI2_SIZE as usize]), ("apploader.img", apploader), ("main.dol", dol)] {
+      fs::write(sys_dir.join(name), data).unwrap();
+    }
+    for (path, data) in test_files() {
+      let path = dir.join("files").join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, data).unwrap();
+    }
+  }
+
+  #[test]
+  fn built_iso_extracts_to_the_same_files() {
+    let dir = TestDir::new("build");
+    write_test_tree(&dir.0.join("in"));
+    let iso_path = dir.0.join("test.iso");
+    build_iso(|_| {}, &dir.0.join("in"), &iso_path, false).unwrap();
+
+    let iso = fs::read(&iso_path).unwrap();
+    let disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&iso)).unwrap();
+    assert_eq!(disc_header.name_string(), "GTST01: Test Game");
+    assert_eq!(disc_header.dol_offset % 0x100, 0);
+    assert_eq!(disc_header.fst_offset % 0x100, 0);
+    assert_eq!(disc_header.user_pos % 0x8000, 0);
+    assert_eq!(iso.len() as u32, disc_header.user_pos + disc_header.user_len);
+    // the banner is at the start of the user area
+    let banner = disc_header.user_pos as usize;
+    assert_eq!(iso[banner..banner + 0x100], [3; 0x100]);
+
+    extract_iso(|_| {}, &iso_path, &dir.0.join("out"), None, false).unwrap();
+    for name in ["bi2.bin", "apploader.img", "main.dol"] {
+      assert_eq!(fs::read(dir.0.join("out/sys").join(name)).unwrap(), fs::read(dir.0.join("in/sys").join(name)).unwrap(), "{}", name);
+    }
+    for (path, data) in test_files() {
+      assert_eq!(fs::read(dir.0.join("out/files").join(path)).unwrap(), data, "{}", path);
+    }
+
+    // existing outputs are only replaced with overwrite
+    let error = build_iso(|_| {}, &dir.0.join("in"), &iso_path, false).unwrap_err();
+    assert!(matches!(error.downcast_ref::<PatchError>(), Some(PatchError::OutputExists { .. })));
+    build_iso(|_| {}, &dir.0.join("in"), &iso_path, true).unwrap();
+    assert_eq!(fs::read(&iso_path).unwrap(), iso);
+  }
+
+  #[test]
+  fn files_that_differ_only_in_case_are_errors() {
+    let dir = TestDir::new("build-case");
+    write_test_tree(&dir.0);
+    fs::write(dir.0.join("files/ZZ.BIN"), [1]).unwrap();
+    // on case-insensitive file systems the write replaced zz.bin
+    if fs::read_dir(dir.0.join("files")).unwrap().count() == 5 {
+      return;
+    }
+    let error = build_iso(|_| {}, &dir.0, &dir.0.join("test.iso"), false).unwrap_err();
+    assert!(error.to_string().contains("twice with different case"), "{}", error);
+  }
+}
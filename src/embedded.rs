@@ -0,0 +1,162 @@
+//! Data the patcher embeds after the sections of a patched DOL.
+//! The game never loads it, since no section points at it.
+use crate::binstream::{BinStreamRead, BinStreamReadable, BinStreamWrite};
+use crate::dol::DolHeader;
+use crate::manifest::PatchManifest;
+use anyhow::Result;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+const MAGIC: [u8; 8] = *b"GSPEMBED";
+const VERSION: u32 = 1;
+/// Embedded data starts at the first 32-byte boundary after the DOL's sections
+const ALIGN: usize = 32;
+
+/// Revert record for the DOL
+pub const TAG_REVERT_DOL: [u8; 4] = *b"RDOL";
+/// Revert record for the ISO the DOL was written into
+pub const TAG_REVERT_ISO: [u8; 4] = *b"RISO";
+/// The [`PatchManifest`] as TOML
+pub const TAG_MANIFEST: [u8; 4] = *b"MNFT";
+/// Which mods were applied, see [`PatchSignature`]
+pub const TAG_SIGNATURE: [u8; 4] = *b"SIGN";
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddedData {
+  pub entries: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl EmbeddedData {
+  pub fn get(&self, tag: [u8; 4]) -> Option<&[u8]> {
+    self.entries.iter()
+      .find(|(entry_tag, _)| *entry_tag == tag)
+      .map(|(_, data)| &data[..])
+  }
+
+  pub fn push(&mut self, tag: [u8; 4], data: Vec<u8>) {
+    self.entries.push((tag, data));
+  }
+
+  fn to_bytes(&self) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut stream = Cursor::new(&mut bytes);
+    stream.write_all(&MAGIC)?;
+    stream.write_u32(VERSION)?;
+    stream.write_u32(self.entries.len() as u32)?;
+    for (tag, data) in &self.entries {
+      stream.write_all(tag)?;
+      stream.write_u32(data.len() as u32)?;
+      stream.write_all(data)?;
+    }
+    Ok(bytes)
+  }
+
+  /// Length of a DOL of `dol_len` bytes once this data is appended to it
+  pub fn appended_len(&self, dol_len: usize) -> Result<usize> {
+    Ok(dol_len.next_multiple_of(ALIGN) + self.to_bytes()?.len())
+  }
+
+  /// Append the data to a patched DOL
+  pub fn append_to(&self, dol_bytes: &mut Vec<u8>) -> Result<()> {
+    dol_bytes.resize(dol_bytes.len().next_multiple_of(ALIGN), 0);
+    dol_bytes.extend_from_slice(&self.to_bytes()?);
+    Ok(())
+  }
+}
+
+/// Identifies the mods a file was patched with, so it isn't patched a second time
+#[derive(Debug, Clone)]
+pub struct PatchSignature {
+  pub patcher_version: String,
+  /// Name and version of each mod, in the order they were applied
+  pub mods: Vec<(String, String)>,
+}
+
+impl PatchSignature {
+  pub fn from_manifest(manifest: &PatchManifest) -> PatchSignature {
+    PatchSignature {
+      patcher_version: manifest.patcher_version.clone(),
+      mods: manifest.mods.iter()
+        .map(|m| (m.mod_name.clone(), m.version.clone()))
+        .collect(),
+    }
+  }
+
+  /// `Mod A v1.0, Mod B v2.1 (patcher 0.3.0)`
+  pub fn describe(&self) -> String {
+    let mods: Vec<String> = self.mods.iter()
+      .map(|(name, version)| format!("{} v{}", name, version))
+      .collect();
+    format!("{} (patcher {})", mods.join(", "), self.patcher_version)
+  }
+
+  pub fn to_bytes(&self) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut stream = Cursor::new(&mut bytes);
+    write_short_string(&mut stream, &self.patcher_version)?;
+    stream.write_u32(self.mods.len() as u32)?;
+    for (name, version) in &self.mods {
+      write_short_string(&mut stream, name)?;
+      write_short_string(&mut stream, version)?;
+    }
+    Ok(bytes)
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<PatchSignature> {
+    let mut stream = Cursor::new(bytes);
+    let patcher_version = read_short_string(&mut stream)?;
+    let count = stream.read_u32()?;
+    let mut mods = Vec::new();
+    for _ in 0..count {
+      mods.push((read_short_string(&mut stream)?, read_short_string(&mut stream)?));
+    }
+    Ok(PatchSignature { patcher_version, mods })
+  }
+}
+
+fn write_short_string<T: BinStreamWrite>(stream: &mut T, value: &str) -> Result<()> {
+  stream.write_u16(value.len() as u16)?;
+  stream.write_string(value)?;
+  Ok(())
+}
+
+fn read_short_string<T: BinStreamRead>(stream: &mut T) -> Result<String> {
+  let len = stream.read_u16()?;
+  let bytes = read_exact_bytes(stream, len as u32)?;
+  Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Find the data embedded in a DOL. Returns `None` for DOLs that were not made by the patcher.
+pub fn read_embedded(dol_bytes: &[u8]) -> Result<Option<EmbeddedData>> {
+  let dol_header = DolHeader::read_from_stream(&mut Cursor::new(dol_bytes))?;
+  // usually right after the sections, but DOLs can have padding the patcher kept
+  let start = (dol_header.total_length() as usize).next_multiple_of(ALIGN);
+  let Some(offset) = (start..dol_bytes.len())
+    .step_by(ALIGN)
+    .find(|&offset| dol_bytes[offset..].starts_with(&MAGIC))
+  else {
+    return Ok(None);
+  };
+
+  let mut stream = Cursor::new(dol_bytes);
+  stream.seek(SeekFrom::Start((offset + MAGIC.len()) as u64))?;
+  let version = stream.read_u32()?;
+  if version != VERSION {
+    return Err(anyhow::anyhow!("Unsupported embedded patcher data version {}", version));
+  }
+  let count = stream.read_u32()?;
+  let mut data = EmbeddedData::default();
+  for _ in 0..count {
+    let mut tag = [0u8; 4];
+    tag.copy_from_slice(&read_exact_bytes(&mut stream, 4)?);
+    let len = stream.read_u32()?;
+    data.push(tag, read_exact_bytes(&mut stream, len)?);
+  }
+  Ok(Some(data))
+}
+
+fn read_exact_bytes<T: BinStreamRead>(stream: &mut T, len: u32) -> Result<Vec<u8>> {
+  let mut buf = vec![0u8; len as usize];
+  stream.read_exact(&mut buf)
+    .map_err(|e| anyhow::anyhow!("Embedded patcher data is truncated: {}", e))?;
+  Ok(buf)
+}
@@ -0,0 +1,308 @@
+//! Errors a frontend may want to tell apart, to suggest a fix for them.
+//! They are returned inside [`anyhow::Error`], use `downcast_ref::<PatchError>()` to get them back.
+use crate::patch_config::ArenaBound;
+use crate::patch_dol::format_hex_bytes;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashedFile {
+  Dol,
+  Iso,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum PatchError {
+  ModNotFound {
+    path: PathBuf,
+  },
+  /// The mod file can't be read, or isn't an ELF
+  InvalidModElf {
+    path: PathBuf,
+    message: String,
+  },
+  /// The mod ELF has no `.patcher_config` section
+  MissingModConfig,
+  InvalidModConfig {
+    message: String,
+  },
+  OutputExists {
+    path: PathBuf,
+  },
+  /// Can be bypassed with the "Ignore Hash" option
+  HashMismatch {
+    file: HashedFile,
+    expected: String,
+    actual: String,
+  },
+  /// The input was made by the patcher, `can_repatch` if it holds the original to patch instead
+  AlreadyPatched {
+    path: PathBuf,
+    /// Mods and patcher version it was patched with, unknown for older versions of the patcher
+    patched_with: Option<String>,
+    can_repatch: bool,
+  },
+  /// Not in the mod, or in the game's symbols if any were given
+  MissingSymbol {
+    name: String,
+  },
+  /// A generated branch can't reach its target, can be allowed with `long_branch_veneers`
+  BranchOutOfRange {
+    from: u32,
+    to: u32,
+  },
+  /// A patch's `expect` didn't match, usually a different revision of the game
+  UnexpectedData {
+    address: u32,
+    expected: Vec<u8>,
+    actual: Vec<u8>,
+  },
+  /// No DOL section ends right before a mod segment, so it can't be placed
+  NoSectionForSegment {
+    kind: &'static str,
+    start: u32,
+    end: u32,
+  },
+  /// Zero-filled memory of a mod would clear part of the game
+  ModBssOverlap {
+    start: u32,
+    end: u32,
+    /// The game's DOL section or BSS
+    region: String,
+    region_start: u32,
+    region_end: u32,
+  },
+  /// The mods are placed at an end of the arena that none of their `arena_patches` sets
+  MissingArenaSite {
+    bound: ArenaBound,
+  },
+  /// An arena patch site with `align = 0`
+  ZeroArenaAlign {
+    address: u32,
+  },
+  /// The game's heap would be smaller than a mod's `min_heap_size`
+  HeapTooSmall {
+    mod_name: String,
+    arena_lo: u32,
+    arena_hi: u32,
+    required: u32,
+    available: u32,
+  },
+  /// Two mods patch the same arena bound site differently
+  ArenaPatchConflict {
+    address: u32,
+  },
+  /// Writes of two mods overlap with different data
+  WriteConflict {
+    mod_name: String,
+    description: String,
+    address: u32,
+    other_mod_name: String,
+    other_description: String,
+    other_address: u32,
+  },
+  /// A mod placed at the top of memory would end above its ceiling
+  AboveMemoryCeiling {
+    mod_name: String,
+    end: u32,
+    ceiling: u32,
+  },
+  /// Overlapping or out of range regions in the patched DOL, can be bypassed with `--ignore-memory-map`
+  InvalidMemoryMap {
+    problems: Vec<String>,
+  },
+  /// Gecko codes that depend on runtime state and can't be applied to the DOL
+  UnsupportedGeckoCodes {
+    /// One entry per code line, with its line number and the reason
+    lines: Vec<String>,
+  },
+  /// A file the mod replaces or deletes is not in the ISO
+  FileNotInFst {
+    path: String,
+    /// `replace` or `delete`
+    action: &'static str,
+  },
+  /// No unused space in the ISO is large enough for the patched DOL
+  NoIsoGap {
+    required: u32,
+    /// Size of the largest gap
+    available: u32,
+  },
+  /// A rebuilt ISO would not fit on a GameCube disc
+  DiscTooLarge {
+    required: u32,
+    available: u32,
+  },
+}
+
+impl PatchError {
+  /// Stable identifier of the error, for scripts
+  pub fn code(&self) -> &'static str {
+    match self {
+      PatchError::ModNotFound { .. } => "mod_not_found",
+      PatchError::InvalidModElf { .. } => "invalid_mod_elf",
+      PatchError::MissingModConfig => "missing_mod_config",
+      PatchError::InvalidModConfig { .. } => "invalid_mod_config",
+      PatchError::OutputExists { .. } => "output_exists",
+      PatchError::HashMismatch { .. } => "hash_mismatch",
+      PatchError::AlreadyPatched { .. } => "already_patched",
+      PatchError::MissingSymbol { .. } => "missing_symbol",
+      PatchError::BranchOutOfRange { .. } => "branch_out_of_range",
+      PatchError::UnexpectedData { .. } => "unexpected_data",
+      PatchError::NoSectionForSegment { .. } => "no_section_for_segment",
+      PatchError::ModBssOverlap { .. } => "mod_bss_overlap",
+      PatchError::MissingArenaSite { .. } => "missing_arena_site",
+      PatchError::ZeroArenaAlign { .. } => "zero_arena_align",
+      PatchError::HeapTooSmall { .. } => "heap_too_small",
+      PatchError::ArenaPatchConflict { .. } => "arena_patch_conflict",
+      PatchError::WriteConflict { .. } => "write_conflict",
+      PatchError::AboveMemoryCeiling { .. } => "above_memory_ceiling",
+      PatchError::InvalidMemoryMap { .. } => "invalid_memory_map",
+      PatchError::UnsupportedGeckoCodes { .. } => "unsupported_gecko_codes",
+      PatchError::FileNotInFst { .. } => "file_not_in_fst",
+      PatchError::NoIsoGap { .. } => "no_iso_gap",
+      PatchError::DiscTooLarge { .. } => "disc_too_large",
+    }
+  }
+}
+
+impl fmt::Display for PatchError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PatchError::ModNotFound { path } => write!(f, "Mod file not found: {:?}", path),
+      PatchError::InvalidModElf { path, message } => write!(f, "Failed to load mod ELF file {:?}: {}", path, message),
+      PatchError::MissingModConfig => write!(f, ".patcher_config section not found in mod ELF"),
+      PatchError::InvalidModConfig { message } => {
+        write!(f, "Failed to parse patcher config from ELF section: {}", message)
+      }
+      PatchError::OutputExists { path } => write!(f, "Output file already exists: {:?}", path),
+      PatchError::HashMismatch { file, expected, actual } => write!(
+        f,
+        "Input {} hash does not match expected hash. Expected: {}, Got: {}. Check \"Ignore Hash\" option to bypass this check.",
+        match file {
+          HashedFile::Dol => "DOL",
+          HashedFile::Iso => "ISO",
+        },
+        expected,
+        actual
+      ),
+      PatchError::AlreadyPatched { path, patched_with: Some(patched_with), can_repatch: true } => write!(
+        f,
+        "{:?} was already patched with {}. Check \"Re-patch from original\" option to patch the original stored in it instead.",
+        path,
+        patched_with
+      ),
+      PatchError::AlreadyPatched { path, patched_with: Some(patched_with), can_repatch: false } => write!(
+        f,
+        "{:?} was already patched with {}. Use the original file instead.",
+        path,
+        patched_with
+      ),
+      PatchError::AlreadyPatched { path, patched_with: None, .. } => write!(
+        f,
+        "{:?} was already patched by an older version of the patcher. Use the original file instead.",
+        path
+      ),
+      PatchError::MissingSymbol { name } => write!(f, "Missing symbol {}", name),
+      PatchError::BranchOutOfRange { from, to } => write!(
+        f,
+        "Branch from 0x{:08X} to 0x{:08X} is out of range, set long_branch_veneers to allow it",
+        from,
+        to
+      ),
+      PatchError::UnexpectedData { address, expected, actual } => write!(
+        f,
+        "Unexpected data at 0x{:08X}. Expected: {}, Got: {}. The DOL may be a different revision.",
+        address,
+        format_hex_bytes(expected),
+        format_hex_bytes(actual)
+      ),
+      PatchError::NoSectionForSegment { kind, start, end } => write!(
+        f,
+        "No available DOL {} section found for mod segment 0x{:08X} - 0x{:08X}",
+        kind,
+        start,
+        end
+      ),
+      PatchError::ModBssOverlap { start, end, region, region_start, region_end } => write!(
+        f,
+        "Mod BSS 0x{:08X} - 0x{:08X} overlaps the game's {} 0x{:08X} - 0x{:08X}",
+        start,
+        end,
+        region,
+        region_start,
+        region_end
+      ),
+      PatchError::MissingArenaSite { bound } => write!(
+        f,
+        "No arena {} patch site in arena_patches, it is needed for the mods' placement",
+        match bound {
+          ArenaBound::Lo => "lo",
+          ArenaBound::Hi => "hi",
+        }
+      ),
+      PatchError::ZeroArenaAlign { address } => {
+        write!(f, "Arena patch at 0x{:08X} has an alignment of 0", address)
+      }
+      PatchError::HeapTooSmall { mod_name, arena_lo, arena_hi, required, available } => write!(
+        f,
+        "The game's arena 0x{:08X} - 0x{:08X} would only have 0x{:X} bytes, less than min_heap_size 0x{:X} of {}",
+        arena_lo,
+        arena_hi,
+        available,
+        required,
+        mod_name
+      ),
+      PatchError::ArenaPatchConflict { address } => {
+        write!(f, "Mods disagree about the arena patch at 0x{:08X}", address)
+      }
+      PatchError::WriteConflict { mod_name, description, address, other_mod_name, other_description, other_address } => write!(
+        f,
+        "{} of {} at 0x{:08X} conflicts with {} of {} at 0x{:08X}",
+        description,
+        mod_name,
+        address,
+        other_description,
+        other_mod_name,
+        other_address
+      ),
+      PatchError::AboveMemoryCeiling { mod_name, end, ceiling } => write!(
+        f,
+        "{} ends at 0x{:08X}, above the memory ceiling 0x{:08X}",
+        mod_name,
+        end,
+        ceiling
+      ),
+      PatchError::InvalidMemoryMap { problems } => write!(
+        f,
+        "Invalid memory map for the patched DOL:\n  {}\nUse --ignore-memory-map to patch anyway.",
+        problems.join("\n  ")
+      ),
+      PatchError::UnsupportedGeckoCodes { lines } => write!(
+        f,
+        "Could not bake the following Gecko code lines into the DOL:\n  {}",
+        lines.join("\n  ")
+      ),
+      PatchError::FileNotInFst { path, action } => {
+        write!(f, "Could not find file {} in FST to {} it", path, action)
+      }
+      PatchError::NoIsoGap { required, available } => write!(
+        f,
+        "Could not find a suitable gap in the ISO to fit the patched DOL. Needs 0x{:X} bytes, the largest gap is 0x{:X} bytes",
+        required,
+        available
+      ),
+      PatchError::DiscTooLarge { required, available } => write!(
+        f,
+        "The rebuilt ISO would be {} bytes, more than the {} bytes that fit on a GameCube disc",
+        required,
+        available
+      ),
+    }
+  }
+}
+
+impl std::error::Error for PatchError {}
@@ -0,0 +1,201 @@
+//! Extracting ISOs to a directory in the layout of Dolphin's "Extract Entire Disc":
+//! `sys/` holds the disc header, bi2, apploader, DOL and FST, `files/` holds the file system
+use crate::binstream::BinStreamReadable;
+use crate::dol::DolHeader;
+use crate::error::PatchError;
+use crate::gcdisc::{apploader_end, GCDiscHeader, APPLOADER_OFFSET, BI2_OFFSET, BI2_SIZE, DISC_HEADER_SIZE, FST};
+use crate::progress::Progress;
+use crate::revert::find_patched_dol;
+use anyhow::Result;
+use log::info;
+use std::fs;
+use std::io::{Cursor, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// `game.iso` -> `game_extracted`
+pub fn default_extract_path(in_path: &Path) -> PathBuf {
+  let stem = in_path.file_stem().unwrap_or_default().to_string_lossy();
+  in_path.with_file_name(format!("{}_extracted", stem))
+}
+
+/// Extract everything, or only `path`. Paths are relative to the output directory,
+/// e.g. `sys/main.dol` or `files/Audio`. FST paths may leave out the `files/`.
+pub fn extract_iso<F>(
+  progress_update: F,
+  in_path: &Path,
+  out_dir: &Path,
+  path: Option<&str>,
+  overwrite: bool,
+) -> Result<()> where
+  F: Fn(Progress),
+{
+  if !overwrite && out_dir.exists() {
+    return Err(PatchError::OutputExists { path: out_dir.to_path_buf() }.into());
+  }
+
+  info!("Reading ISO {:?}", in_path);
+  let input_file = fs::File::open(in_path)?;
+  let input_file_mmap = unsafe { memmap2::MmapOptions::new().map(&input_file)? };
+  let entries = list_entries(&input_file_mmap)?;
+  let entries: Vec<(String, Range<usize>)> = match path {
+    Some(path) => {
+      let path = path.trim_matches('/');
+      let selected = select_entries(&entries, path);
+      let selected = if selected.is_empty() {
+        select_entries(&entries, &format!("files/{}", path))
+      } else {
+        selected
+      };
+      if selected.is_empty() {
+        return Err(anyhow::anyhow!("Could not find {} in the ISO", path));
+      }
+      selected
+    }
+    None => entries,
+  };
+
+  let length: usize = entries.iter().map(|(_, range)| range.len()).sum();
+  let mut processed_bytes = 0;
+  let mut last_update = 0;
+  progress_update(Progress::new(0, length as u64, "Extracting ISO".to_string()));
+  for (entry_path, range) in &entries {
+    if entry_path.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+      return Err(anyhow::anyhow!("Refusing to extract {:?}, it is not a plain path", entry_path));
+    }
+    let out_path = entry_path.split('/').fold(out_dir.to_path_buf(), |path, part| path.join(part));
+    info!("Extracting {} ({} bytes)", entry_path, range.len());
+    if let Some(parent) = out_path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::write(&out_path, &input_file_mmap[range.clone()])
+      .map_err(|e| anyhow::anyhow!("Failed to write {:?}: {}", out_path, e))?;
+    processed_bytes += range.len();
+    // only update ever 1MB to avoid spamming the UI
+    if processed_bytes - last_update >= 1024 * 1024 {
+      last_update = processed_bytes;
+      progress_update(Progress::new(processed_bytes as u64, length as u64, "Extracting ISO".to_string()));
+    }
+  }
+  info!("Extracted {} files", entries.len());
+  progress_update(Progress::new(0, 0, "Done extracting ISO".to_string()));
+  Ok(())
+}
+
+/// Every file to extract, with its path in the output directory and where it is in the ISO
+fn list_entries(iso_bytes: &[u8]) -> Result<Vec<(String, Range<usize>)>> {
+  let mut reader = Cursor::new(iso_bytes);
+  let disc_header = GCDiscHeader::read_from_stream(&mut reader)?;
+  info!("Disk name: {}", disc_header.name_string());
+  reader.seek(SeekFrom::Start(disc_header.fst_offset as u64))?;
+  let fst = FST::read_from_stream(&mut reader)?;
+  reader.seek(SeekFrom::Start(disc_header.dol_offset as u64))?;
+  let dol_header = DolHeader::read_from_stream(&mut reader)?;
+  // patched DOLs are in the FST, with the data the patcher embeds after their sections
+  let dol_length = find_patched_dol(iso_bytes)?
+    .map(|dol_bytes| dol_bytes.len() as u32)
+    .unwrap_or_else(|| dol_header.total_length());
+
+  let range = |offset: u32, length: u32| offset as usize..offset as usize + length as usize;
+  let mut entries = vec![
+    ("sys/boot.bin".to_string(), range(0, DISC_HEADER_SIZE)),
+    ("sys/bi2.bin".to_string(), range(BI2_OFFSET, BI2_SIZE)),
+    ("sys/apploader.img".to_string(), APPLOADER_OFFSET as usize..apploader_end(iso_bytes)? as usize),
+    ("sys/main.dol".to_string(), range(disc_header.dol_offset, dol_length)),
+    ("sys/fst.bin".to_string(), range(disc_header.fst_offset, disc_header.fst_size)),
+  ];
+  for (path, offset, length) in fst.root.files() {
+    entries.push((format!("files/{}", path), range(offset, length)));
+  }
+  if let Some((path, _)) = entries.iter().find(|(_, range)| range.end > iso_bytes.len()) {
+    return Err(anyhow::anyhow!("{} is outside of the ISO, the ISO may be damaged", path));
+  }
+  Ok(entries)
+}
+
+/// The entry at `path`, or everything in the directory at `path`. Names are compared ignoring
+/// case, like the game does.
+fn select_entries(entries: &[(String, Range<usize>)], path: &str) -> Vec<(String, Range<usize>)> {
+  let path = path.to_ascii_lowercase();
+  let dir = format!("{}/", path);
+  entries.iter()
+    .filter(|(entry_path, _)| {
+      let entry_path = entry_path.to_ascii_lowercase();
+      entry_path == path || entry_path.starts_with(&dir)
+    })
+    .cloned()
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::build::build_iso;
+  use crate::build::tests::{test_files, write_test_tree, TestDir};
+
+  /// Builds the test tree into an ISO and extracts `path` from it
+  fn extract(name: &str, path: Option<&str>) -> (TestDir, Result<()>) {
+    let dir = TestDir::new(name);
+    write_test_tree(&dir.0.join("in"));
+    build_iso(|_| {}, &dir.0.join("in"), &dir.0.join("test.iso"), false).unwrap();
+    let result = extract_iso(|_| {}, &dir.0.join("test.iso"), &dir.0.join("out"), path, false);
+    (dir, result)
+  }
+
+  /// Paths of all extracted files, relative to the output directory
+  fn extracted_files(dir: &Path) -> Vec<String> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+      for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+          dirs.push(path);
+        } else {
+          paths.push(path.to_string_lossy().to_string());
+        }
+      }
+    }
+    let prefix = format!("{}/", dir.to_string_lossy());
+    let mut paths: Vec<String> = paths.iter().map(|path| path.strip_prefix(&prefix).unwrap().replace('\\', "/")).collect();
+    paths.sort();
+    paths
+  }
+
+  #[test]
+  fn everything_is_extracted() {
+    let (dir, result) = extract("extract-all", None);
+    result.unwrap();
+    let mut expected = vec!["sys/apploader.img", "sys/bi2.bin", "sys/boot.bin", "sys/fst.bin", "sys/main.dol"];
+    let files: Vec<String> = test_files().iter().map(|(path, _)| format!("files/{}", path)).collect();
+    expected.extend(files.iter().map(String::as_str));
+    expected.sort();
+    assert_eq!(extracted_files(&dir.0.join("out")), expected);
+  }
+
+  #[test]
+  fn directories_are_extracted_ignoring_case() {
+    let (dir, result) = extract("extract-dir", Some("FILES/audio/"));
+    result.unwrap();
+    assert_eq!(extracted_files(&dir.0.join("out")), ["files/Audio/Voice/line.dsp", "files/Audio/music.dsp"]);
+  }
+
+  #[test]
+  fn files_may_leave_out_the_files_prefix() {
+    let (dir, result) = extract("extract-file", Some("audio/voice/LINE.dsp"));
+    result.unwrap();
+    assert_eq!(extracted_files(&dir.0.join("out")), ["files/Audio/Voice/line.dsp"]);
+    assert_eq!(fs::read(dir.0.join("out/files/Audio/Voice/line.dsp")).unwrap(), [2; 0x21]);
+
+    let (dir, result) = extract("extract-sys", Some("sys/main.dol"));
+    result.unwrap();
+    assert_eq!(extracted_files(&dir.0.join("out")), ["sys/main.dol"]);
+  }
+
+  #[test]
+  fn missing_paths_are_errors() {
+    let (dir, result) = extract("extract-missing", Some("Audio/music"));
+    assert_eq!(result.unwrap_err().to_string(), "Could not find Audio/music in the ISO");
+    assert!(!dir.0.join("out").exists());
+  }
+}
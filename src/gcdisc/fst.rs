@@ -76,32 +76,81 @@ impl FSTEntry {
     ranges
   }
 
-  pub fn find_mut(&mut self, path: &[&str]) -> Option<&mut FSTEntry> {
-    if path.is_empty() {
-      return None;
-    }
-    let head = path[0];
-    let tail = &path[1..];
+  pub fn name(&self) -> &str {
     match self {
-      FSTEntry::Directory { name, children } => {
-        if head != name {
-          return None;
+      FSTEntry::Directory { name, .. } => name,
+      FSTEntry::File { name, .. } => name,
+    }
+  }
+
+  /// Find an entry by its `/` separated path, relative to this directory.
+  /// Names are compared ignoring case, like the game does.
+  pub fn find_path_mut(&mut self, path: &str) -> Option<&mut FSTEntry> {
+    let mut entry = self;
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+      let FSTEntry::Directory { children, .. } = entry else {
+        return None;
+      };
+      entry = children.iter_mut().find(|child| child.name().eq_ignore_ascii_case(part))?;
+    }
+    Some(entry)
+  }
+
+  /// Remove the entry at `path`, relative to this directory
+  pub fn remove_path(&mut self, path: &str) -> Option<FSTEntry> {
+    let path = path.trim_matches('/');
+    let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let FSTEntry::Directory { children, .. } = self.find_path_mut(parent_path)? else {
+      return None;
+    };
+    let index = children.iter().position(|child| child.name().eq_ignore_ascii_case(name))?;
+    Some(children.remove(index))
+  }
+
+  /// Add a file at `path`, relative to this directory, creating the directories it is in
+  pub fn add_file(&mut self, path: &str, offset: u32, length: u32) -> Result<()> {
+    let path = path.trim_matches('/');
+    let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut dir = self;
+    for part in parent_path.split('/').filter(|part| !part.is_empty()) {
+      let FSTEntry::Directory { children, .. } = dir else {
+        return Err(anyhow::anyhow!("Cannot add {}, a parent is a file", path));
+      };
+      let index = match children.iter().position(|child| child.name().eq_ignore_ascii_case(part)) {
+        Some(index) => index,
+        None => {
+          children.push(FSTEntry::Directory { name: part.to_string(), children: Vec::new() });
+          children.len() - 1
         }
+      };
+      dir = &mut children[index];
+    }
+    let FSTEntry::Directory { children, .. } = dir else {
+      return Err(anyhow::anyhow!("Cannot add {}, a parent is a file", path));
+    };
+    if children.iter().any(|child| child.name().eq_ignore_ascii_case(name)) {
+      return Err(anyhow::anyhow!("Cannot add {}, it already exists", path));
+    }
+    children.push(FSTEntry::File { name: name.to_string(), offset, length });
+    Ok(())
+  }
+
+  /// Every file below this directory as `(path, offset, length)`, with `/` separated paths
+  pub fn files(&self) -> Vec<(String, u32, u32)> {
+    fn collect(entry: &FSTEntry, prefix: &str, files: &mut Vec<(String, u32, u32)>) {
+      if let FSTEntry::Directory { children, .. } = entry {
         for child in children {
-          if let Some(found) = child.find_mut(tail) {
-            return Some(found);
+          let path = format!("{}{}", prefix, child.name());
+          match child {
+            FSTEntry::Directory { .. } => collect(child, &format!("{}/", path), files),
+            FSTEntry::File { offset, length, .. } => files.push((path, *offset, *length)),
           }
         }
-        None
-      }
-      FSTEntry::File { name, .. } => {
-        if head == name && tail.is_empty() {
-          Some(self)
-        } else {
-          None
-        }
       }
     }
+    let mut files = Vec::new();
+    collect(self, "", &mut files);
+    files
   }
 
   pub fn count(&self) -> u32 {
@@ -411,3 +460,55 @@ impl BinStreamWritable for FSTEntryData {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_root() -> FSTEntry {
+    let mut root = FSTEntry::Directory { name: String::new(), children: Vec::new() };
+    root.add_file("opening.bnr", 0x8000, 0x1960).unwrap();
+    root.add_file("Audio/Music.dsp", 0x10000, 0x400).unwrap();
+    root
+  }
+
+  #[test]
+  fn paths_ignore_case() {
+    let mut root = test_root();
+    assert!(matches!(root.find_path_mut("audio/MUSIC.DSP"), Some(FSTEntry::File { offset: 0x10000, .. })));
+    assert!(matches!(root.find_path_mut("/AUDIO/"), Some(FSTEntry::Directory { name, .. }) if name == "Audio"));
+    assert!(root.find_path_mut("Audio/missing.dsp").is_none());
+    assert!(root.find_path_mut("opening.bnr/child").is_none());
+
+    assert_eq!(root.remove_path("AUDIO/music.dsp").unwrap().name(), "Music.dsp");
+    assert!(root.remove_path("Audio/Music.dsp").is_none());
+    assert_eq!(root.files(), [("opening.bnr".to_string(), 0x8000, 0x1960)]);
+  }
+
+  #[test]
+  fn adding_files_creates_their_directories() {
+    let mut root = test_root();
+    root.add_file("audio/Voice/line.dsp", 0, 0x20).unwrap();
+    root.add_file("/Mods/new.bin/", 0, 0x10).unwrap();
+    assert_eq!(root.files(), [
+      ("opening.bnr".to_string(), 0x8000, 0x1960),
+      ("Audio/Music.dsp".to_string(), 0x10000, 0x400),
+      ("Audio/Voice/line.dsp".to_string(), 0, 0x20),
+      ("Mods/new.bin".to_string(), 0, 0x10),
+    ]);
+    // the existing directory is used, whatever the case of the path
+    assert_eq!(root.count(), 8);
+  }
+
+  #[test]
+  fn adding_files_below_files_or_twice_is_an_error() {
+    let mut root = test_root();
+    let error = root.add_file("opening.bnr/child.bin", 0, 0x10).unwrap_err();
+    assert!(error.to_string().contains("a parent is a file"));
+    let error = root.add_file("audio/music.DSP", 0, 0x10).unwrap_err();
+    assert!(error.to_string().contains("it already exists"));
+    let error = root.add_file("AUDIO", 0, 0x10).unwrap_err();
+    assert!(error.to_string().contains("it already exists"));
+    assert_eq!(root.count(), 4);
+  }
+}
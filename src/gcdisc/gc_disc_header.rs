@@ -1,4 +1,16 @@
 use crate::binstream::{BinStreamRead, BinStreamReadable, BinStreamWritable, BinStreamWrite};
+use anyhow::Result;
+use std::io::{Cursor, Seek, SeekFrom};
+
+/// The disc header and the boot info that follows it, `boot.bin`
+pub const DISC_HEADER_SIZE: u32 = 0x440;
+/// `bi2.bin` follows the disc header
+pub const BI2_OFFSET: u32 = 0x440;
+pub const BI2_SIZE: u32 = 0x2000;
+/// The apploader follows bi2
+pub const APPLOADER_OFFSET: u32 = 0x2440;
+/// Header of the apploader, with its entry point and sizes
+pub(crate) const APPLOADER_HEADER_SIZE: u32 = 0x20;
 
 #[derive(Clone, Debug)]
 pub struct GCDiscHeader {
@@ -109,4 +121,16 @@ impl BinStreamWritable for GCDiscHeader {
 
     Ok(())
   }
-}
\ No newline at end of file
+}
+/// End of the apploader, read from its header
+pub fn apploader_end(iso_bytes: &[u8]) -> Result<u32> {
+  let mut reader = Cursor::new(iso_bytes);
+  reader.seek(SeekFrom::Start(APPLOADER_OFFSET as u64 + 0x14))?;
+  let apploader_size = reader.read_u32()?;
+  let trailer_size = reader.read_u32()?;
+  let end = APPLOADER_OFFSET as u64 + APPLOADER_HEADER_SIZE as u64 + apploader_size as u64 + trailer_size as u64;
+  if end > iso_bytes.len() as u64 {
+    return Err(anyhow::anyhow!("The apploader is larger than the ISO, the ISO may be damaged"));
+  }
+  Ok(end as u32)
+}
@@ -0,0 +1,290 @@
+use crate::error::PatchError;
+use crate::patch_config::ModData;
+use anyhow::Result;
+use log::info;
+use std::fs;
+
+/// A Gecko code that can be applied to the DOL ahead of time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeckoPatch {
+  /// 00/02/04/06 codes, writes `data` to `address`
+  Write { address: u32, data: Vec<u8> },
+  /// C2 codes, runs `code` instead of the instruction at `address`.
+  /// The last instruction of `code` is replaced with a branch back.
+  InsertAsm { address: u32, code: Vec<u8> },
+}
+
+/// Load every Gecko code list that applies to the DOL with the given hash
+pub fn load_gecko_codes(mod_data: &ModData, dol_hash: &str) -> Result<Vec<GeckoPatch>> {
+  let mut patches = Vec::new();
+  for config in &mod_data.config.gecko_codes {
+    if config.dol_hash.as_deref().is_some_and(|expected_hash| expected_hash != dol_hash) {
+      continue;
+    }
+    let bytes = mod_data.read_file_or_section(&config.file, &config.section)
+      .map_err(|e| anyhow::anyhow!("Failed to load Gecko codes: {}", e))?;
+    patches.extend(parse_gecko_codes(&bytes, &config.names)?);
+  }
+  for path in &mod_data.gecko_code_files {
+    info!("Loading Gecko codes {:?}", path);
+    let bytes = fs::read(path)
+      .map_err(|e| anyhow::anyhow!("Failed to read Gecko codes {:?}: {}", path, e))?;
+    patches.extend(parse_gecko_codes(&bytes, &[])?);
+  }
+  if !patches.is_empty() {
+    info!("Loaded {} Gecko codes", patches.len());
+  }
+  Ok(patches)
+}
+
+struct CodeLine {
+  /// Line number in the source text, or the index of the line in a .gct
+  line: usize,
+  code: u32,
+  value: u32,
+}
+
+/// Parse a Gecko code list: a `.gct` file, or text with one `XXXXXXXX YYYYYYYY` pair per line.
+/// Text may contain Dolphin style `$Name` headers; when `names` is not empty, only codes with
+/// those names are used.
+pub fn parse_gecko_codes(bytes: &[u8], names: &[String]) -> Result<Vec<GeckoPatch>> {
+  let lines = if bytes.starts_with(&[0x00, 0xD0, 0xC0, 0xDE, 0x00, 0xD0, 0xC0, 0xDE]) {
+    parse_gct(bytes)
+  } else {
+    parse_text(&String::from_utf8_lossy(bytes), names)?
+  };
+
+  let mut patches = Vec::new();
+  let mut unsupported = Vec::new();
+  let mut i = 0;
+  while i < lines.len() {
+    let line = &lines[i];
+    i += 1;
+    let code_type = (line.code >> 24) & 0xFE;
+    let address = 0x8000_0000 | (line.code & 0x01FF_FFFF);
+    match code_type {
+      0x00 => {
+        let count = (line.value >> 16) + 1;
+        patches.push(GeckoPatch::Write {
+          address,
+          data: vec![line.value as u8; count as usize],
+        });
+      }
+      0x02 => {
+        let count = (line.value >> 16) + 1;
+        let data = (0..count).flat_map(|_| (line.value as u16).to_be_bytes()).collect();
+        patches.push(GeckoPatch::Write { address, data });
+      }
+      0x04 => {
+        patches.push(GeckoPatch::Write { address, data: line.value.to_be_bytes().to_vec() });
+      }
+      0x06 | 0xC2 => {
+        let (length, name) = if code_type == 0x06 {
+          (line.value as usize, "string write")
+        } else {
+          (line.value as usize * 8, "insert asm")
+        };
+        let line_count = length.div_ceil(8);
+        if i + line_count > lines.len() {
+          return Err(anyhow::anyhow!("Gecko {} code on line {} is missing data", name, line.line));
+        }
+        let mut data: Vec<u8> = lines[i..i + line_count].iter()
+          .flat_map(|l| l.code.to_be_bytes().into_iter().chain(l.value.to_be_bytes()))
+          .collect();
+        data.truncate(length);
+        i += line_count;
+        if code_type == 0x06 {
+          patches.push(GeckoPatch::Write { address, data });
+        } else if data.is_empty() {
+          return Err(anyhow::anyhow!("Gecko insert asm code on line {} is empty", line.line));
+        } else {
+          patches.push(GeckoPatch::InsertAsm { address, code: data });
+        }
+      }
+      // full terminator, a no-op without conditionals
+      0xE0 => {}
+      // end of code list
+      0xF0 => break,
+      _ => {
+        let reason = match code_type {
+          _ if line.code & 0x1000_0000 != 0 && code_type < 0xC0 => "pointer codes depend on runtime state",
+          0x08 => "serial writes are not supported",
+          0x20..=0x3E | 0xE2 => "conditional codes are evaluated at runtime",
+          0x40..=0x5E => "base address and pointer codes depend on runtime state",
+          0x60..=0x7E => "repeat and goto codes are evaluated at runtime",
+          0x80..=0xBE => "gecko register codes are evaluated at runtime",
+          0xC0 => "execute asm codes run every frame",
+          0xD2 => "pointer codes depend on runtime state",
+          _ => "unsupported code type",
+        };
+        unsupported.push(format!("line {}: {:08X} {:08X} ({})", line.line, line.code, line.value, reason));
+        // skip the data lines of multi-line codes, so they aren't reported as codes themselves
+        let data_lines = match (line.code >> 24) & 0xFE {
+          0x08 | 0x18 => 1,
+          0x16 => (line.value as usize).div_ceil(8),
+          0xC0 | 0xD2 => line.value as usize,
+          0xF2 | 0xF4 => (line.value & 0xFF) as usize,
+          _ => 0,
+        };
+        i = (i + data_lines).min(lines.len());
+      }
+    }
+  }
+
+  if !unsupported.is_empty() {
+    return Err(PatchError::UnsupportedGeckoCodes { lines: unsupported }.into());
+  }
+  Ok(patches)
+}
+
+fn parse_gct(bytes: &[u8]) -> Vec<CodeLine> {
+  bytes[8..].chunks_exact(8)
+    .enumerate()
+    .map(|(i, chunk)| CodeLine {
+      line: i + 1,
+      code: u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+      value: u32::from_be_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+    })
+    .collect()
+}
+
+fn parse_text(text: &str, names: &[String]) -> Result<Vec<CodeLine>> {
+  let mut lines = Vec::new();
+  // codes before the first header are always used
+  let mut enabled = true;
+  let mut found_names = Vec::new();
+  for (index, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('*') || line.starts_with('#') || line.starts_with('[') {
+      continue;
+    }
+    if let Some(header) = line.strip_prefix('$') {
+      // `$Name [Author]`
+      let name = header.split(" [").next().unwrap_or(header).trim();
+      enabled = names.is_empty() || names.iter().any(|n| n == name);
+      found_names.push(name.to_string());
+      continue;
+    }
+    if !enabled {
+      continue;
+    }
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let parsed = match parts.as_slice() {
+      [code, value] => u32::from_str_radix(code, 16).ok().zip(u32::from_str_radix(value, 16).ok()),
+      _ => None,
+    };
+    let Some((code, value)) = parsed else {
+      return Err(anyhow::anyhow!("Invalid Gecko code on line {}: {}", index + 1, line));
+    };
+    lines.push(CodeLine { line: index + 1, code, value });
+  }
+  for name in names {
+    if !found_names.contains(name) {
+      return Err(anyhow::anyhow!("Gecko code {} not found", name));
+    }
+  }
+  Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+  }
+
+  #[test]
+  fn parses_text_codes() {
+    let text = "\
+04003100 60000000
+00003104 000100FF
+02003108 0001ABCD
+06003110 00000005
+48656C6C 6F000000
+C2003120 00000001
+38600001 00000000
+";
+    let patches = parse_gecko_codes(text.as_bytes(), &[]).unwrap();
+    assert_eq!(patches, vec![
+      GeckoPatch::Write { address: 0x8000_3100, data: vec![0x60, 0, 0, 0] },
+      GeckoPatch::Write { address: 0x8000_3104, data: vec![0xFF, 0xFF] },
+      GeckoPatch::Write { address: 0x8000_3108, data: vec![0xAB, 0xCD, 0xAB, 0xCD] },
+      GeckoPatch::Write { address: 0x8000_3110, data: b"Hello".to_vec() },
+      GeckoPatch::InsertAsm { address: 0x8000_3120, code: vec![0x38, 0x60, 0, 1, 0, 0, 0, 0] },
+    ]);
+  }
+
+  #[test]
+  fn parses_gct() {
+    let mut gct = vec![0x00, 0xD0, 0xC0, 0xDE, 0x00, 0xD0, 0xC0, 0xDE];
+    for word in [0x0500_3100u32, 0x4E80_0020, 0xF000_0000, 0, 0x2000_0000, 0] {
+      gct.extend_from_slice(&word.to_be_bytes());
+    }
+    // the 25th address bit selects 0x81000000, everything after the end code is ignored
+    let patches = parse_gecko_codes(&gct, &[]).unwrap();
+    assert_eq!(patches, vec![GeckoPatch::Write { address: 0x8100_3100, data: vec![0x4E, 0x80, 0, 0x20] }]);
+  }
+
+  #[test]
+  fn filters_by_name() {
+    let text = "\
+04003000 00000001
+$Infinite Health [someone]
+04003100 00000002
+$Moon Jump
+04003200 00000003
+";
+    let write = |address: u32, value: u8| GeckoPatch::Write { address, data: vec![0, 0, 0, value] };
+    let all = parse_gecko_codes(text.as_bytes(), &[]).unwrap();
+    assert_eq!(all, vec![write(0x8000_3000, 1), write(0x8000_3100, 2), write(0x8000_3200, 3)]);
+    let selected = parse_gecko_codes(text.as_bytes(), &names(&["Moon Jump"])).unwrap();
+    assert_eq!(selected, vec![write(0x8000_3000, 1), write(0x8000_3200, 3)]);
+    let missing = parse_gecko_codes(text.as_bytes(), &names(&["Walk Through Walls"]));
+    assert!(missing.unwrap_err().to_string().contains("Walk Through Walls"));
+  }
+
+  #[test]
+  fn rejects_unsupported_codes() {
+    let text = "\
+04003100 60000000
+20003104 00000001
+14003108 00000002
+C0000000 00000000
+08003100 00000001
+20010002 00000004
+";
+    let error = parse_gecko_codes(text.as_bytes(), &[]).unwrap_err();
+    let Some(PatchError::UnsupportedGeckoCodes { lines }) = error.downcast_ref::<PatchError>() else {
+      panic!("unexpected error: {}", error);
+    };
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("line 2: 20003104 00000001 (conditional codes"));
+    assert!(lines[1].starts_with("line 3: 14003108 00000002 (pointer codes"));
+    assert!(lines[2].starts_with("line 4: C0000000 00000000 (execute asm codes"));
+    assert!(lines[3].starts_with("line 5: 08003100 00000001 (serial writes"));
+
+    // the asm of an execute asm code is a single listed line, not codes of its own
+    let text = "\
+C0000000 00000002
+3C608000 80630000
+2C030000 4E800020
+04003100 60000000
+";
+    let error = parse_gecko_codes(text.as_bytes(), &[]).unwrap_err();
+    let Some(PatchError::UnsupportedGeckoCodes { lines }) = error.downcast_ref::<PatchError>() else {
+      panic!("unexpected error: {}", error);
+    };
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("line 1: C0000000 00000002 (execute asm codes"));
+  }
+
+  #[test]
+  fn rejects_malformed_codes() {
+    assert!(parse_gecko_codes(b"04003100 6000000G", &[]).is_err());
+    assert!(parse_gecko_codes(b"04003100", &[]).is_err());
+    // the string write needs a second line of data
+    assert!(parse_gecko_codes(b"06003100 00000010\n41414141 41414141", &[]).is_err());
+    assert!(parse_gecko_codes(b"C2003100 00000000", &[]).is_err());
+  }
+}
@@ -0,0 +1,291 @@
+//! Describes DOLs, ISOs, mods and the patch manifest embedded in patched files,
+//! as text or as JSON for scripts
+use crate::binstream::BinStreamReadable;
+use crate::dol::{DolHeader, SectionInfo};
+use crate::embedded::{read_embedded, TAG_MANIFEST};
+use crate::gcdisc::{FSTEntry, GCDiscHeader, FST};
+use crate::load_mod_data;
+use crate::manifest::{PatchManifest, MANIFEST_FILE_NAME};
+use crate::mod_image::{elf_regions, ElfRegion};
+use crate::patch_config::ModConfig;
+use crate::patch_iso::find_gaps;
+use crate::revert::find_patched_dol;
+use anyhow::Result;
+use object::{Object, ObjectKind};
+use serde::Serialize;
+use std::fs;
+use std::io::{Cursor, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DolReport {
+  pub text: Vec<SectionReport>,
+  pub data: Vec<SectionReport>,
+  pub bss_addr: u32,
+  pub bss_size: u32,
+  pub entry_point: u32,
+  /// Whether the patcher embedded data after the sections
+  pub patched: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionReport {
+  /// Slot in the DOL header
+  pub index: usize,
+  pub offset: u32,
+  pub address: u32,
+  pub size: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IsoReport {
+  pub name: String,
+  pub dol_offset: u32,
+  pub fst_offset: u32,
+  pub fst_size: u32,
+  pub fst_max_size: u32,
+  pub user_pos: u32,
+  pub user_len: u32,
+  pub dol: DolReport,
+  pub fst: FstNode,
+  /// Unused space between files, as `[start, end)` offsets
+  pub gaps: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum FstNode {
+  Directory { name: String, children: Vec<FstNode> },
+  File { name: String, offset: u32, length: u32 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModReport {
+  pub config: ModConfig,
+  pub relocatable: bool,
+  /// The ELF's entry point, relocatable mods use `mod_entry_symbol` instead
+  pub entry: Option<u32>,
+  pub segments: Vec<ElfRegion>,
+}
+
+pub fn inspect_dol(path: &Path, json: bool) -> Result<()> {
+  let dol_bytes = fs::read(path)?;
+  let dol_header = DolHeader::read_from_stream(&mut Cursor::new(&dol_bytes[..]))?;
+  let patched = read_embedded(&dol_bytes)?.is_some();
+  if json {
+    return print_json(&dol_report(&dol_header, patched));
+  }
+  print!("{:?}", dol_header);
+  if patched {
+    println!("Patched, see `inspect manifest` for details");
+  }
+  Ok(())
+}
+
+pub fn inspect_iso(path: &Path, json: bool) -> Result<()> {
+  let file = fs::File::open(path)?;
+  let mmap = unsafe { memmap2::MmapOptions::new().map(&file)? };
+  let mut reader = Cursor::new(&mmap[..]);
+  let disc_header = GCDiscHeader::read_from_stream(&mut reader)?;
+  reader.seek(SeekFrom::Start(disc_header.fst_offset as u64))?;
+  let fst = FST::read_from_stream(&mut reader)?;
+  let dol_start = disc_header.dol_offset as usize;
+  let dol_header = DolHeader::read_from_stream(&mut Cursor::new(&mmap[dol_start..dol_start + 0x100]))?;
+  let patched = find_patched_dol(&mmap)?.is_some();
+  let gaps = find_gaps(&disc_header, &fst);
+
+  if json {
+    return print_json(&IsoReport {
+      name: disc_header.name_string(),
+      dol_offset: disc_header.dol_offset,
+      fst_offset: disc_header.fst_offset,
+      fst_size: disc_header.fst_size,
+      fst_max_size: disc_header.fst_max_size,
+      user_pos: disc_header.user_pos,
+      user_len: disc_header.user_len,
+      dol: dol_report(&dol_header, patched),
+      fst: fst_node(&fst.root),
+      gaps,
+    });
+  }
+  println!("Disk name: {}", disc_header.name_string());
+  println!("DOL offset: 0x{:08X}", disc_header.dol_offset);
+  println!("FST offset: 0x{:08X}, size: 0x{:08X}, max size: 0x{:08X}", disc_header.fst_offset, disc_header.fst_size, disc_header.fst_max_size);
+  println!("User area: 0x{:08X}, length: 0x{:08X}", disc_header.user_pos, disc_header.user_len);
+  println!();
+  print!("{:?}", dol_header);
+  if patched {
+    println!("Patched, see `inspect manifest` for details");
+  }
+  println!();
+  println!("FST:");
+  print!("{:?}", fst);
+  println!();
+  println!("Gaps:");
+  for (start, end) in gaps {
+    println!("  0x{:08X} - 0x{:08X} ({} bytes)", start, end, end - start);
+  }
+  Ok(())
+}
+
+pub fn inspect_mod(path: &Path, json: bool) -> Result<()> {
+  let mod_data = load_mod_data(path.to_path_buf())?;
+  let mod_file = mod_data.parse_elf()
+    .map_err(|e| anyhow::anyhow!("Failed to parse mod ELF file: {}", e))?;
+  let relocatable = mod_file.kind() == ObjectKind::Relocatable;
+  let report = ModReport {
+    config: mod_data.config.clone(),
+    relocatable,
+    entry: (!relocatable).then(|| mod_file.entry() as u32),
+    segments: elf_regions(&mod_file),
+  };
+  if json {
+    return print_json(&report);
+  }
+  let config = toml::to_string(&report.config)
+    .map_err(|e| anyhow::anyhow!("Failed to serialize mod config: {}", e))?;
+  println!("{}", config);
+  if let Some(entry) = report.entry {
+    println!("Executable mod, entry 0x{:08X}", entry);
+  } else {
+    println!("Relocatable mod, linked by the patcher");
+  }
+  for region in &report.segments {
+    let kind = if region.executable { "text" } else { "data" };
+    println!(
+      "  {} {}: 0x{:08X} - 0x{:08X} ({} bytes, {} in file)",
+      kind, region.name, region.address, region.address + region.size, region.size, region.file_size
+    );
+  }
+  Ok(())
+}
+
+pub fn inspect_manifest(path: &Path, json: bool) -> Result<()> {
+  let manifest = read_patch_manifest(path)?;
+  if json {
+    return print_json(&manifest);
+  }
+  print_patch_manifest(&manifest);
+  Ok(())
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+  let text = serde_json::to_string_pretty(value)
+    .map_err(|e| anyhow::anyhow!("Failed to serialize JSON: {}", e))?;
+  println!("{}", text);
+  Ok(())
+}
+
+fn dol_report(dol_header: &DolHeader, patched: bool) -> DolReport {
+  DolReport {
+    text: section_reports(&dol_header.text),
+    data: section_reports(&dol_header.data),
+    bss_addr: dol_header.bss_addr,
+    bss_size: dol_header.bss_size,
+    entry_point: dol_header.entry_point,
+    patched,
+  }
+}
+
+fn section_reports(sections: &[SectionInfo]) -> Vec<SectionReport> {
+  sections.iter()
+    .enumerate()
+    .filter(|(_, section)| section.size > 0)
+    .map(|(index, section)| SectionReport {
+      index,
+      offset: section.offset,
+      address: section.loading,
+      size: section.size,
+    })
+    .collect()
+}
+
+fn fst_node(entry: &FSTEntry) -> FstNode {
+  match entry {
+    FSTEntry::Directory { name, children } => FstNode::Directory {
+      name: name.clone(),
+      children: children.iter().map(fst_node).collect(),
+    },
+    FSTEntry::File { name, offset, length } => FstNode::File {
+      name: name.clone(),
+      offset: *offset,
+      length: *length,
+    },
+  }
+}
+
+/// Read the manifest of a patched DOL or ISO
+pub fn read_patch_manifest(path: &Path) -> Result<PatchManifest> {
+  let ext = path.extension()
+    .and_then(|s| s.to_str())
+    .map(|s| s.to_lowercase());
+  let text = match ext.as_deref() {
+    Some("dol") => read_dol_manifest(&fs::read(path)?)?,
+    Some("iso") | Some("gcm") => {
+      let file = fs::File::open(path)?;
+      let mmap = unsafe { memmap2::MmapOptions::new().map(&file)? };
+      read_iso_manifest(&mmap)?
+    }
+    _ => return Err(anyhow::anyhow!("Unsupported file type: {:?}", ext)),
+  };
+  let Some(text) = text else {
+    return Err(anyhow::anyhow!("No patch manifest found in {:?}, it was not patched by this patcher", path));
+  };
+  PatchManifest::from_toml(&text)
+}
+
+fn read_dol_manifest(dol_bytes: &[u8]) -> Result<Option<String>> {
+  Ok(read_embedded(dol_bytes)?
+    .and_then(|embedded| embedded.get(TAG_MANIFEST).map(|bytes| String::from_utf8_lossy(bytes).to_string())))
+}
+
+fn read_iso_manifest(iso_bytes: &[u8]) -> Result<Option<String>> {
+  let mut reader = Cursor::new(iso_bytes);
+  let disc_header = GCDiscHeader::read_from_stream(&mut reader)?;
+  reader.seek(SeekFrom::Start(disc_header.fst_offset as u64))?;
+  let fst = FST::read_from_stream(&mut reader)?;
+  // the root's name is whatever the string table starts with, so only look at its children
+  let manifest_file = match &fst.root {
+    FSTEntry::Directory { children, .. } => children.iter()
+      .find(|child| matches!(child, FSTEntry::File { name, .. } if name == MANIFEST_FILE_NAME)),
+    FSTEntry::File { .. } => None,
+  };
+  if let Some(FSTEntry::File { offset, length, .. }) = manifest_file {
+    let bytes = &iso_bytes[*offset as usize..(*offset + *length) as usize];
+    return Ok(Some(String::from_utf8_lossy(bytes).to_string()));
+  }
+  // the file may have been removed by a tool that rebuilt the ISO, the DOL has a copy
+  match find_patched_dol(iso_bytes)? {
+    Some(dol_bytes) => read_dol_manifest(dol_bytes),
+    None => Ok(None),
+  }
+}
+
+/// Print the manifest of a patched file in a readable form
+fn print_patch_manifest(manifest: &PatchManifest) {
+  println!("Patched at {} with patcher {}", manifest.timestamp, manifest.patcher_version);
+  if let Some(input_iso_hash) = &manifest.input_iso_hash {
+    println!("Input ISO md5: {}", input_iso_hash);
+  }
+  println!("Input DOL md5: {}", manifest.input_dol_hash);
+  println!("Output DOL md5: {}", manifest.output_dol_hash);
+  println!("Arena: 0x{:08X} - 0x{:08X}", manifest.arena_lo, manifest.arena_hi);
+  for patch in &manifest.arena_patches {
+    println!("  0x{:08X} ({} bytes): {}", patch.address, patch.size, patch.description);
+  }
+  for applied_mod in &manifest.mods {
+    println!();
+    println!("{} v{} for {}", applied_mod.mod_name, applied_mod.version, applied_mod.game_name);
+    println!(
+      "  Placed at the {:?} of the arena: 0x{:08X} - 0x{:08X}, entry 0x{:08X}",
+      applied_mod.placement, applied_mod.start, applied_mod.end, applied_mod.entry
+    );
+    for segment in &applied_mod.segments {
+      let kind = if segment.executable { "text" } else { "data" };
+      println!("  {} segment 0x{:08X} ({} bytes)", kind, segment.address, segment.size);
+    }
+    for patch in &applied_mod.patches {
+      println!("  0x{:08X} ({} bytes): {}", patch.address, patch.size, patch.description);
+    }
+  }
+}
@@ -5,12 +5,31 @@ mod dol;
 mod binstream;
 mod gcdisc;
 mod patch_config;
+mod mod_image;
+mod symbol_map;
+mod ppc_asm;
+mod gecko;
+mod trampoline;
+mod memory_map;
+mod manifest;
+mod embedded;
+mod revert;
+mod inspect;
+mod plan;
+mod message;
+mod error;
+mod extract;
+mod build;
 
 pub use patch_config::{ModConfig, ModData};
 pub use progress::Progress;
+pub use error::{HashedFile, PatchError};
+pub use manifest::PatchManifest;
+pub use plan::PatchPlan;
+pub use message::{print_error, warning_log, MessageFormat};
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::{error, info};
 use object::{Object, ObjectSection};
 use std::path::PathBuf;
@@ -19,6 +38,11 @@ use std::fs;
 
 use crate::patch_dol::patch_dol_file;
 use crate::patch_iso::patch_iso_file;
+use crate::message::{print_command_result, print_progress, print_result};
+use crate::extract::{default_extract_path, extract_iso};
+use crate::build::{build_iso, default_build_path};
+use crate::inspect::{inspect_dol, inspect_iso, inspect_manifest, inspect_mod};
+use crate::revert::{default_revert_path, revert_file};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -41,39 +65,150 @@ pub struct Args {
   /// Overwrite existing output files
   #[arg(long)]
   pub overwrite: bool,
+  /// Symbol map of the game (Dolphin .map, CodeWarrior .map or symbols.txt).
+  /// Can be passed multiple times.
+  #[arg(long, value_name = "FILE")]
+  pub symbol_map: Vec<PathBuf>,
+  /// Gecko code list (text or .gct) to bake into the DOL.
+  /// Can be passed multiple times.
+  #[arg(long, value_name = "FILE")]
+  pub gecko: Vec<PathBuf>,
+  /// Patch even if mod sections overlap the game, the arena or are outside of MEM1
+  /// (the game will likely crash)
+  #[arg(long)]
+  pub ignore_memory_map: bool,
+  /// Another mod file (.elf) to apply along with the main one.
+  /// Can be passed multiple times, mods are applied in order.
+  #[arg(long, value_name = "FILE")]
+  pub extra_mod: Vec<PathBuf>,
+  /// If the input was already patched, patch the original stored in it instead of refusing it
+  #[arg(long)]
+  pub repatch: bool,
+  /// Check the mod against the input and print every byte range that would change,
+  /// without writing any files
+  #[arg(long)]
+  pub dry_run: bool,
+  /// Lay out the whole ISO again instead of fitting the patched DOL into unused space.
+  /// Rebuilt ISOs can't be reverted.
+  #[arg(long)]
+  pub rebuild: bool,
+  /// How to print progress and the result
+  #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+  pub message_format: MessageFormat,
+
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// Rebuild the original DOL or ISO from a patched one
+  Revert {
+    /// Patched file (.dol or .iso)
+    #[arg(value_name = "FILE")]
+    input_file: PathBuf,
+    /// Output file path. If not provided, output will be next to input file.
+    #[arg(short, long, value_name = "FILE")]
+    output_file: Option<PathBuf>,
+    /// Overwrite existing output files
+    #[arg(long)]
+    overwrite: bool,
+  },
+  /// Extract the files of an ISO, in the layout of Dolphin's "Extract Entire Disc"
+  Extract {
+    /// ISO to extract
+    #[arg(value_name = "FILE")]
+    input_file: PathBuf,
+    /// Directory to extract into. If not provided, it will be next to the input file.
+    #[arg(short, long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+    /// Only extract this file or directory, e.g. `sys/main.dol`, `files/opening.bnr` or `Audio`
+    #[arg(long, value_name = "PATH")]
+    path: Option<String>,
+    /// Extract into the output directory even if it already exists
+    #[arg(long)]
+    overwrite: bool,
+  },
+  /// Build an ISO from a directory in the layout of Dolphin's "Extract Entire Disc"
+  ///
+  /// The directory can be written by `extract`. The FST is generated from `files/`.
+  Build {
+    /// Directory with `sys/` and `files/`
+    #[arg(value_name = "DIR")]
+    input_dir: PathBuf,
+    /// Output file path. If not provided, it will be next to the input directory.
+    #[arg(short, long, value_name = "FILE")]
+    output_file: Option<PathBuf>,
+    /// Overwrite existing output files
+    #[arg(long)]
+    overwrite: bool,
+  },
+  /// Describe a DOL, ISO, mod or patched file
+  Inspect {
+    #[command(subcommand)]
+    target: InspectTarget,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum InspectTarget {
+  /// Sections, BSS and entry point of a DOL
+  Dol(InspectArgs),
+  /// Disc header, DOL, file system and free space of an ISO
+  Iso(InspectArgs),
+  /// Config and segments of a mod (.elf)
+  Mod(InspectArgs),
+  /// Which mods a patched DOL or ISO was made with
+  Manifest(InspectArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InspectArgs {
+  #[arg(value_name = "FILE")]
+  pub input_file: PathBuf,
+  /// Print JSON instead of text
+  #[arg(long)]
+  pub json: bool,
 }
 
 pub fn load_mod_data(mod_path: PathBuf) -> Result<ModData> {
   if !mod_path.exists() {
-    return Err(anyhow::anyhow!("Mod file not found: {:?}", mod_path));
+    return Err(PatchError::ModNotFound { path: mod_path }.into());
   }
 
   // if the mod .elf exists, load from the section ".patcher_config" inside the ELF
   info!("Loading patcher config from ELF section");
   let elf_bytes = fs::read(&mod_path)
-    .map_err(|e| anyhow::anyhow!("Failed to read mod ELF file: {}", e))?;
+    .map_err(|e| PatchError::InvalidModElf { path: mod_path.clone(), message: e.to_string() })?;
   let elf_file = object::File::parse(&*elf_bytes)
-    .map_err(|e| anyhow::anyhow!("Failed to parse mod ELF file: {}", e))?;
+    .map_err(|e| PatchError::InvalidModElf { path: mod_path.clone(), message: e.to_string() })?;
   if let Some(section) = elf_file.section_by_name(".patcher_config") {
     // this is a PT_NOTE section containing the TOML config
 
     let patcher_config_section = section.data()
-      .map_err(|e| anyhow::anyhow!("Failed to read .patcher_config section data: {}", e))?;
+      .map_err(|e| PatchError::InvalidModConfig { message: e.to_string() })?;
     info!("deb: {:?}", section.kind());
 
     let config_str = std::str::from_utf8(patcher_config_section)
-      .map_err(|e| anyhow::anyhow!("Failed to parse .patcher_config section as UTF-8: {}", e))?;
+      .map_err(|e| PatchError::InvalidModConfig { message: e.to_string() })?;
     let config: ModConfig = toml::from_str(config_str)
-      .map_err(|e| anyhow::anyhow!("Failed to parse patcher config from ELF section: {}", e))?;
+      .map_err(|e| PatchError::InvalidModConfig { message: e.to_string() })?;
 
     Ok(ModData {
       elf_bytes,
       config,
       overwrite_output: false,
       output_path_override: None,
+      symbol_map_files: Vec::new(),
+      gecko_code_files: Vec::new(),
+      ignore_memory_map: false,
+      additional_mods: Vec::new(),
+      repatch: false,
+      dry_run: false,
+      rebuild: false,
     })
   } else {
-    Err(anyhow::anyhow!(".patcher_config section not found in mod ELF"))
+    Err(PatchError::MissingModConfig.into())
   }
 }
 
@@ -103,11 +238,17 @@ pub fn find_app_dir() -> PathBuf {
   }
 }
 
-pub fn run_cli_mode(args: &Args, mut mod_data: ModData) -> Result<()> {
+pub fn run_cli_mode(args: &Args, mod_data: ModData) -> Result<()> {
   let input_path = args.input_file.as_ref().ok_or_else(|| {
     anyhow::anyhow!("CLI mode requires an input file")
   })?;
+  let mod_data = configure_cli_mod_data(args, mod_data)
+    .inspect_err(|e| print_error(args.message_format, None, e))?;
+  run_cli(input_path, &Some(mod_data), args.message_format)
+}
 
+/// Apply the CLI options to the loaded mod, and load the extra mods
+fn configure_cli_mod_data(args: &Args, mut mod_data: ModData) -> Result<ModData> {
   if args.ignore_hash {
     mod_data.config.expected_iso_hash = None;
     mod_data.config.expected_dol_hash = None;
@@ -116,41 +257,99 @@ pub fn run_cli_mode(args: &Args, mut mod_data: ModData) -> Result<()> {
     mod_data.output_path_override = Some(output_path.clone());
   }
   mod_data.overwrite_output = args.overwrite;
+  mod_data.symbol_map_files = args.symbol_map.clone();
+  mod_data.gecko_code_files = args.gecko.clone();
+  mod_data.ignore_memory_map = args.ignore_memory_map;
+  mod_data.repatch = args.repatch;
+  mod_data.rebuild = args.rebuild;
+  mod_data.dry_run = args.dry_run;
+  for path in &args.extra_mod {
+    let mut extra_mod_data = load_mod_data(std::env::current_dir()?.join(path))?;
+    if args.ignore_hash {
+      extra_mod_data.config.expected_iso_hash = None;
+      extra_mod_data.config.expected_dol_hash = None;
+    }
+    info!("Loaded extra mod {} {}", extra_mod_data.config.mod_name, extra_mod_data.config.version);
+    mod_data.additional_mods.push(extra_mod_data);
+  }
+  Ok(mod_data)
+}
 
-  run_cli(input_path, &Some(mod_data))
+/// Run a subcommand, these don't need a mod
+pub fn run_command(command: &Command, message_format: MessageFormat) -> Result<()> {
+  let progress_update = |progress: Progress| print_progress(message_format, &progress);
+  let result = match command {
+    Command::Revert { input_file, output_file, overwrite } => {
+      let out_path = output_file.clone().unwrap_or_else(|| default_revert_path(input_file));
+      info!("Reverting {:?} to {:?}", input_file, out_path);
+      revert_file(progress_update, input_file, &out_path, *overwrite)
+        .map(|_| ("revert", out_path))
+    }
+    Command::Extract { input_file, output_dir, path, overwrite } => {
+      let out_dir = output_dir.clone().unwrap_or_else(|| default_extract_path(input_file));
+      info!("Extracting {:?} to {:?}", input_file, out_dir);
+      extract_iso(progress_update, input_file, &out_dir, path.as_deref(), *overwrite)
+        .map(|_| ("extract", out_dir))
+    }
+    Command::Build { input_dir, output_file, overwrite } => {
+      let out_path = output_file.clone().unwrap_or_else(|| default_build_path(input_dir));
+      info!("Building {:?} from {:?}", out_path, input_dir);
+      build_iso(progress_update, input_dir, &out_path, *overwrite)
+        .map(|_| ("build", out_path))
+    }
+    Command::Inspect { target } => return match target {
+      InspectTarget::Dol(args) => inspect_dol(&args.input_file, args.json),
+      InspectTarget::Iso(args) => inspect_iso(&args.input_file, args.json),
+      InspectTarget::Mod(args) => inspect_mod(&args.input_file, args.json),
+      InspectTarget::Manifest(args) => inspect_manifest(&args.input_file, args.json),
+    },
+  };
+
+  match result {
+    Ok((kind, output_path)) => {
+      print_command_result(message_format, kind, &output_path);
+      Ok(())
+    }
+    Err(e) => {
+      print_error(message_format, None, &e);
+      Err(e)
+    }
+  }
 }
 
-pub fn run_cli(input_path: &PathBuf, patch_config: &Option<ModData>) -> Result<()> {
+pub fn run_cli(input_path: &PathBuf, patch_config: &Option<ModData>, message_format: MessageFormat) -> Result<()> {
   info!("Running in CLI mode. Input file: {:?}", input_path);
   let result = handle_patch_for_file(
     input_path,
     patch_config,
-    // dummy context for CLI mode
-    |progress| {
-      if let Some(description) = &progress.description {
-        println!("Progress: {:.2}% - {}", progress.ratio() * 100.0, description);
-      } else {
-        println!("Progress: {:.2}%", progress.ratio() * 100.0);
-      }
-    },
+    |progress| print_progress(message_format, &progress),
   );
 
   match result {
     Ok(result) => {
-      println!("Successfully patched file: {:?}", result);
+      print_result(message_format, input_path, &result);
       Ok(())
     }
     Err(e) => {
-      eprintln!("Error patching file {:?}: {}", input_path, e);
+      print_error(message_format, Some(input_path), &e);
       Err(e)
     }
   }
 }
 
+/// A patched file, or the plan for it on dry runs
+#[derive(Debug, Clone)]
+pub struct PatchOutput {
+  pub path: PathBuf,
+  pub manifest: PatchManifest,
+  /// Set for dry runs, nothing was written
+  pub plan: Option<PatchPlan>,
+}
+
 #[derive(Debug, Clone)]
 pub enum PatchResult {
-  Dol(PathBuf),
-  Iso(PathBuf),
+  Dol(PatchOutput),
+  Iso(PatchOutput),
   ModData(ModData),
 }
 
@@ -171,13 +370,13 @@ pub fn handle_patch_for_file<F>(
     };
     let out_path = mod_data.output_path_override.clone()
       .unwrap_or_else(|| path.with_file_name(&mod_data.config.output_name_dol));
-    patch_dol_file(
+    let output = patch_dol_file(
       progres_fn,
       path,
       &out_path,
       &mod_data,
     )?;
-    Ok(PatchResult::Dol(out_path))
+    Ok(PatchResult::Dol(output))
   } else if ext == Some("iso".to_string()) || ext == Some("gcm".to_string()) {
     let Some(mod_data) = mod_data else {
       return Err(anyhow::anyhow!("No mod data loaded to patch DOL"));
@@ -185,13 +384,13 @@ pub fn handle_patch_for_file<F>(
     info!("Patching ISO file: {:?}", path);
     let out_path = mod_data.output_path_override.clone()
       .unwrap_or_else(|| path.with_file_name(&mod_data.config.output_name_iso));
-    patch_iso_file(
+    let output = patch_iso_file(
       progres_fn,
       path,
       &out_path,
       mod_data,
     )?;
-    Ok(PatchResult::Iso(out_path))
+    Ok(PatchResult::Iso(output))
   } else {
     // check if it's an .elf
     const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
@@ -0,0 +1,68 @@
+//! A record of what the patcher applied. It is embedded in the patched output: after the DOL's
+//! sections, and as a file in the root of ISOs.
+use crate::patch_config::Placement;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchManifest {
+  /// Version of the patcher that made the output
+  pub patcher_version: String,
+  /// When the output was made, in UTC
+  pub timestamp: String,
+  /// md5 of the ISO before patching, if it was checked
+  pub input_iso_hash: Option<String>,
+  /// md5 of the DOL before patching
+  pub input_dol_hash: String,
+  /// md5 of the patched DOL, without the data embedded after its sections
+  pub output_dol_hash: String,
+  /// Bounds of the game's arena after patching
+  pub arena_lo: u32,
+  pub arena_hi: u32,
+  pub arena_patches: Vec<AppliedPatch>,
+  /// Mods in the order they were applied
+  pub mods: Vec<AppliedMod>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedMod {
+  pub game_name: String,
+  pub mod_name: String,
+  pub version: String,
+  pub placement: Placement,
+  /// Memory used by the mod, including generated code
+  pub start: u32,
+  pub end: u32,
+  pub entry: u32,
+  pub segments: Vec<AppliedSegment>,
+  pub patches: Vec<AppliedPatch>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedSegment {
+  pub address: u32,
+  pub size: u32,
+  pub executable: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedPatch {
+  pub address: u32,
+  pub size: u32,
+  pub description: String,
+}
+
+/// Name of the manifest file in the root of patched ISOs
+pub const MANIFEST_FILE_NAME: &str = "patch_manifest.toml";
+
+impl PatchManifest {
+  pub fn to_toml(&self) -> Result<String> {
+    toml::to_string(self)
+      .map_err(|e| anyhow::anyhow!("Failed to serialize patch manifest: {}", e))
+  }
+
+  pub fn from_toml(text: &str) -> Result<PatchManifest> {
+    toml::from_str(text)
+      .map_err(|e| anyhow::anyhow!("Failed to parse patch manifest: {}", e))
+  }
+}
@@ -0,0 +1,182 @@
+//! Checks the memory layout of a patched DOL for mod sections that would break the game
+use crate::dol::{DolHeader, SectionInfo};
+
+/// Start of cached MEM1
+pub const MEM1_START: u32 = 0x8000_0000;
+/// End of cached MEM1, on retail consoles
+pub const MEM1_END: u32 = 0x8180_0000;
+/// DOL sections are read from disc and loaded in 32-byte blocks
+const SECTION_ALIGN: u32 = 32;
+
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+  pub name: String,
+  pub start: u32,
+  pub end: u32,
+  /// Offset of the data in the DOL, for DOL sections
+  pub file_offset: Option<u32>,
+  /// Whether the region was added or changed by the patcher
+  pub from_mod: bool,
+}
+
+/// Build the memory map of a patched DOL. Sections that differ from the original DOL belong to
+/// the mod, and the arena from `arena_lo` to `arena_hi` belongs to the game's heap.
+pub fn build_memory_map(original: &DolHeader, patched: &DolHeader, arena_lo: u32, arena_hi: u32) -> Vec<MemoryRegion> {
+  let mut regions = Vec::new();
+  let tables = [
+    ("text", &original.text, &patched.text),
+    ("data", &original.data, &patched.data),
+  ];
+  for (kind, original_sections, patched_sections) in tables {
+    for (i, section) in patched_sections.iter().enumerate() {
+      if section.size == 0 {
+        continue;
+      }
+      regions.push(MemoryRegion {
+        name: format!("{} section {}", kind, i),
+        start: section.loading,
+        end: section.loading + section.size,
+        file_offset: Some(section.offset),
+        from_mod: !same_section(&original_sections[i], section),
+      });
+    }
+  }
+
+  let original_bss_end = original.bss_addr + original.bss_size;
+  regions.push(MemoryRegion {
+    name: "BSS".to_string(),
+    start: original.bss_addr,
+    end: original_bss_end,
+    file_offset: None,
+    from_mod: false,
+  });
+  let patched_bss_end = patched.bss_addr + patched.bss_size;
+  if patched_bss_end > original_bss_end {
+    regions.push(MemoryRegion {
+      name: "extended BSS".to_string(),
+      start: original_bss_end,
+      end: patched_bss_end,
+      file_offset: None,
+      from_mod: true,
+    });
+  }
+
+  regions.push(MemoryRegion {
+    name: "arena".to_string(),
+    start: arena_lo,
+    end: arena_hi,
+    file_offset: None,
+    from_mod: false,
+  });
+  regions
+}
+
+/// Find problems with the mod's regions: overlaps with other regions, addresses outside of MEM1
+/// and sections that are not 32-byte aligned. Overlaps between the game's own regions are normal,
+/// the BSS range usually covers small data sections.
+pub fn check_memory_map(regions: &[MemoryRegion]) -> Vec<String> {
+  let mut problems = Vec::new();
+  for (i, region) in regions.iter().enumerate() {
+    if !region.from_mod {
+      continue;
+    }
+    if region.start < MEM1_START || region.end > MEM1_END {
+      problems.push(format!(
+        "{} 0x{:08X} - 0x{:08X} is outside of MEM1 (0x{:08X} - 0x{:08X})",
+        region.name, region.start, region.end, MEM1_START, MEM1_END
+      ));
+    }
+    for (j, other) in regions.iter().enumerate() {
+      // report each overlap between two mod regions once
+      if i == j || (other.from_mod && j < i) {
+        continue;
+      }
+      if region.start < other.end && other.start < region.end {
+        problems.push(format!(
+          "{} 0x{:08X} - 0x{:08X} overlaps {} 0x{:08X} - 0x{:08X}",
+          region.name, region.start, region.end, other.name, other.start, other.end
+        ));
+      }
+    }
+    if let Some(offset) = region.file_offset
+      && (region.start % SECTION_ALIGN != 0 || offset % SECTION_ALIGN != 0)
+    {
+      problems.push(format!(
+        "{} (offset 0x{:08X}, address 0x{:08X}) is not {}-byte aligned",
+        region.name, offset, region.start, SECTION_ALIGN
+      ));
+    }
+  }
+  problems
+}
+
+fn same_section(a: &SectionInfo, b: &SectionInfo) -> bool {
+  a.offset == b.offset && a.loading == b.loading && a.size == b.size
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn region(name: &str, start: u32, end: u32, file_offset: Option<u32>, from_mod: bool) -> MemoryRegion {
+    MemoryRegion { name: name.to_string(), start, end, file_offset, from_mod }
+  }
+
+  fn game_regions() -> Vec<MemoryRegion> {
+    vec![
+      region("text section 0", 0x80003100, 0x80200000, Some(0x100), false),
+      region("data section 0", 0x80200000, 0x80300000, Some(0x1FD000), false),
+      region("BSS", 0x80300000, 0x80380000, None, false),
+      region("arena", 0x80400000, 0x81700000, None, false),
+    ]
+  }
+
+  #[test]
+  fn clean_layout_has_no_problems() {
+    let mut regions = game_regions();
+    regions.push(region("text section 1", 0x80380000, 0x80390000, Some(0x2FD000), true));
+    regions.push(region("extended BSS", 0x80390000, 0x803A0000, None, true));
+    // the game's own regions may overlap
+    regions.push(region("data section 1", 0x80370000, 0x80372000, Some(0x30D000), false));
+    assert!(check_memory_map(&regions).is_empty());
+  }
+
+  #[test]
+  fn overlaps_are_reported_once() {
+    let mut regions = game_regions();
+    regions.push(region("text section 1", 0x803FF000, 0x80401000, Some(0x2FD000), true));
+    regions.push(region("data section 1", 0x80400800, 0x80402000, Some(0x2FF000), true));
+    let problems = check_memory_map(&regions);
+    assert_eq!(problems, vec![
+      "text section 1 0x803FF000 - 0x80401000 overlaps arena 0x80400000 - 0x81700000",
+      "text section 1 0x803FF000 - 0x80401000 overlaps data section 1 0x80400800 - 0x80402000",
+      "data section 1 0x80400800 - 0x80402000 overlaps arena 0x80400000 - 0x81700000",
+    ]);
+  }
+
+  #[test]
+  fn regions_outside_of_mem1() {
+    let regions = [
+      region("text section 1", 0x817FF000, 0x81801000, Some(0x2FD000), true),
+      region("data section 1", 0x7FFFF000, 0x80000000, Some(0x2FF000), true),
+    ];
+    let problems = check_memory_map(&regions);
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].starts_with("text section 1 0x817FF000 - 0x81801000 is outside of MEM1"));
+    assert!(problems[1].starts_with("data section 1 0x7FFFF000 - 0x80000000 is outside of MEM1"));
+  }
+
+  #[test]
+  fn misaligned_sections() {
+    let regions = [
+      region("text section 1", 0x80380010, 0x80381000, Some(0x2FD000), true),
+      region("data section 1", 0x80390000, 0x80391000, Some(0x2FE004), true),
+      // regions without data in the DOL don't need to be aligned
+      region("extended BSS", 0x803A0004, 0x803A0008, None, true),
+    ];
+    assert_eq!(check_memory_map(&regions), vec![
+      "text section 1 (offset 0x002FD000, address 0x80380010) is not 32-byte aligned",
+      "data section 1 (offset 0x002FE004, address 0x80390000) is not 32-byte aligned",
+    ]);
+  }
+}
@@ -0,0 +1,188 @@
+//! Machine readable output for the CLI, one JSON object per line on stdout
+use crate::error::PatchError;
+use crate::plan::PatchPlan;
+use crate::progress::Progress;
+use crate::PatchResult;
+use clap::ValueEnum;
+use log::LevelFilter;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+  /// Progress lines and messages for people
+  #[default]
+  Human,
+  /// One JSON object per progress event, then a result object
+  Json,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message<'a> {
+  Progress {
+    current: u64,
+    total: u64,
+    ratio: f32,
+    description: Option<&'a str>,
+  },
+  Result(ResultMessage<'a>),
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ResultMessage<'a> {
+  success: bool,
+  /// `dol`, `iso` or `mod`, as in [`PatchResult`], or the command that was run:
+  /// `revert`, `extract` or `build`
+  kind: Option<&'static str>,
+  output_path: Option<&'a Path>,
+  dry_run: bool,
+  input_iso_hash: Option<&'a str>,
+  input_dol_hash: Option<&'a str>,
+  output_dol_hash: Option<&'a str>,
+  /// What would change, for dry runs
+  plan: Option<&'a PatchPlan>,
+  warnings: Vec<String>,
+  error: Option<ErrorMessage<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorMessage<'a> {
+  code: &'static str,
+  message: String,
+  /// Fields of the [`PatchError`], if it is one
+  details: Option<&'a PatchError>,
+}
+
+/// Warnings logged during the run, reported with the result
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Log output that keeps warnings for the result message. Chain it next to the formatted
+/// outputs, so the messages are stored without the log format.
+pub fn warning_log() -> fern::Dispatch {
+  fern::Dispatch::new()
+    .level(LevelFilter::Warn)
+    .chain(fern::Output::call(|record| {
+      if let Ok(mut warnings) = WARNINGS.lock() {
+        warnings.push(record.args().to_string());
+      }
+    }))
+}
+
+fn take_warnings() -> Vec<String> {
+  WARNINGS.lock()
+    .map(|mut warnings| std::mem::take(&mut *warnings))
+    .unwrap_or_default()
+}
+
+pub fn print_progress(format: MessageFormat, progress: &Progress) {
+  match format {
+    MessageFormat::Human => {
+      if let Some(description) = &progress.description {
+        println!("Progress: {:.2}% - {}", progress.ratio() * 100.0, description);
+      } else {
+        println!("Progress: {:.2}%", progress.ratio() * 100.0);
+      }
+    }
+    MessageFormat::Json => print_message(&Message::Progress {
+      current: progress.current,
+      total: progress.total,
+      ratio: progress.ratio(),
+      description: progress.description.as_deref(),
+    }),
+  }
+}
+
+pub fn print_result(format: MessageFormat, input_path: &Path, result: &PatchResult) {
+  match format {
+    MessageFormat::Human => match result {
+      PatchResult::Dol(output) | PatchResult::Iso(output) => match &output.plan {
+        Some(plan) => {
+          plan.print();
+          println!("Dry run finished for {:?}", input_path);
+        }
+        None => println!("Successfully patched file: {:?}", output.path),
+      },
+      PatchResult::ModData(_) => println!("Loaded mod from {:?}", input_path),
+    },
+    MessageFormat::Json => {
+      let message = match result {
+        PatchResult::Dol(output) | PatchResult::Iso(output) => ResultMessage {
+          success: true,
+          kind: Some(if matches!(result, PatchResult::Dol(_)) { "dol" } else { "iso" }),
+          output_path: Some(&output.path),
+          dry_run: output.plan.is_some(),
+          input_iso_hash: output.manifest.input_iso_hash.as_deref(),
+          input_dol_hash: Some(&output.manifest.input_dol_hash),
+          output_dol_hash: Some(&output.manifest.output_dol_hash),
+          plan: output.plan.as_ref(),
+          warnings: take_warnings(),
+          ..Default::default()
+        },
+        PatchResult::ModData(_) => ResultMessage {
+          success: true,
+          kind: Some("mod"),
+          warnings: take_warnings(),
+          ..Default::default()
+        },
+      };
+      print_message(&Message::Result(message));
+    }
+  }
+}
+
+/// Result of the `revert`, `extract` and `build` commands
+pub fn print_command_result(format: MessageFormat, kind: &'static str, output_path: &Path) {
+  match format {
+    MessageFormat::Human => match kind {
+      "revert" => println!("Successfully reverted file: {:?}", output_path),
+      "extract" => println!("Successfully extracted to {:?}", output_path),
+      _ => println!("Successfully built ISO: {:?}", output_path),
+    },
+    MessageFormat::Json => print_message(&Message::Result(ResultMessage {
+      success: true,
+      kind: Some(kind),
+      output_path: Some(output_path),
+      warnings: take_warnings(),
+      ..Default::default()
+    })),
+  }
+}
+
+pub fn print_error(format: MessageFormat, input_path: Option<&Path>, error: &anyhow::Error) {
+  match format {
+    MessageFormat::Human => match input_path {
+      Some(input_path) => eprintln!("Error patching file {:?}: {}", input_path, error),
+      None => eprintln!("Error: {}", error),
+    },
+    MessageFormat::Json => print_message(&Message::Result(ResultMessage {
+      success: false,
+      warnings: take_warnings(),
+      error: Some(ErrorMessage {
+        code: error_code(error),
+        message: error.to_string(),
+        details: error.downcast_ref::<PatchError>(),
+      }),
+      ..Default::default()
+    })),
+  }
+}
+
+/// Stable identifier of the kind of error, for scripts
+fn error_code(error: &anyhow::Error) -> &'static str {
+  if let Some(error) = error.downcast_ref::<PatchError>() {
+    error.code()
+  } else if error.downcast_ref::<std::io::Error>().is_some() {
+    "io_error"
+  } else {
+    "patch_failed"
+  }
+}
+
+fn print_message(message: &Message) {
+  match serde_json::to_string(message) {
+    Ok(line) => println!("{}", line),
+    Err(e) => eprintln!("Failed to serialize message: {}", e),
+  }
+}
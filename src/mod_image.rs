@@ -0,0 +1,547 @@
+use anyhow::Result;
+use log::info;
+use object::elf;
+use object::{Object, ObjectKind, ObjectSection, ObjectSegment, ObjectSymbol, RelocationFlags, RelocationTarget, SectionFlags, SectionIndex, SectionKind, SegmentFlags, SymbolIndex, SymbolSection};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The mod ELF laid out at its final addresses, ready to be copied into a DOL
+#[derive(Debug, Clone)]
+pub struct ModImage {
+  pub segments: Vec<ModSegment>,
+  pub symbols: HashMap<String, ModSymbol>,
+  pub entry: u32,
+  /// Whether the image was linked by the patcher from a relocatable object
+  pub relocated: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModSymbol {
+  pub address: u32,
+  pub size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModSegment {
+  pub address: u32,
+  /// Size in memory, may be larger than `data` for zero-filled sections
+  pub size: u32,
+  pub data: Vec<u8>,
+  /// Whether the segment holds code, and so goes in a DOL text section
+  pub executable: bool,
+}
+
+impl ModImage {
+  /// Build the image from the mod ELF.
+  ///
+  /// Fully linked mods (`ET_EXEC`) are copied as-is. Relocatable objects (`ET_REL`) are laid out
+  /// starting at `load_address` and their relocations are resolved against the mod's own symbols
+  /// and `game_symbols`.
+  pub fn from_elf(
+    mod_file: &object::File,
+    load_address: Option<u32>,
+    entry_symbol: Option<&str>,
+    game_symbols: &HashMap<String, u32>,
+  ) -> Result<ModImage> {
+    let mut image = if mod_file.kind() == ObjectKind::Relocatable {
+      let load_address = load_address
+        .ok_or_else(|| anyhow::anyhow!("Relocatable mods require a load address"))?;
+      link_relocatable(mod_file, load_address, game_symbols)?
+    } else {
+      from_executable(mod_file)?
+    };
+
+    image.entry = match entry_symbol {
+      Some(name) => image.symbol(name)
+        .ok_or_else(|| anyhow::anyhow!("Missing mod entry symbol {}", name))?,
+      None if image.relocated => {
+        return Err(anyhow::anyhow!("Relocatable mods require mod_entry_symbol to be set"));
+      }
+      None => mod_file.entry() as u32,
+    };
+    Ok(image)
+  }
+
+  pub fn symbol(&self, name: &str) -> Option<u32> {
+    self.symbols.get(name).map(|s| s.address)
+  }
+
+  /// The loaded contents of a symbol, as it will be in memory
+  pub fn symbol_data(&self, name: &str) -> Option<&[u8]> {
+    let symbol = self.symbols.get(name)?;
+    self.segments.iter()
+      .find(|s| s.address <= symbol.address && symbol.address + symbol.size <= s.address + s.data.len() as u32)
+      .map(|s| {
+        let start = (symbol.address - s.address) as usize;
+        &s.data[start..start + symbol.size as usize]
+      })
+  }
+}
+
+/// A loaded part of the mod ELF, as it is in the file
+#[derive(Debug, Clone, Serialize)]
+pub struct ElfRegion {
+  pub name: String,
+  /// Zero for sections of relocatable mods, they get an address when the patcher links them
+  pub address: u32,
+  pub size: u32,
+  /// Bytes stored in the file, the rest is zero-filled
+  pub file_size: u32,
+  pub executable: bool,
+}
+
+/// The loaded parts of the mod ELF: program segments of executables, allocated sections of
+/// relocatable objects
+pub fn elf_regions(mod_file: &object::File) -> Vec<ElfRegion> {
+  if mod_file.kind() == ObjectKind::Relocatable {
+    mod_file.sections()
+      .filter(|s| is_alloc(s) && s.size() > 0)
+      .map(|s| ElfRegion {
+        name: s.name().unwrap_or("<unnamed>").to_string(),
+        address: s.address() as u32,
+        size: s.size() as u32,
+        file_size: if s.kind() == SectionKind::UninitializedData { 0 } else { s.size() as u32 },
+        executable: is_exec(&s),
+      })
+      .collect()
+  } else {
+    mod_file.segments()
+      .enumerate()
+      .map(|(i, segment)| ElfRegion {
+        name: format!("segment {}", i),
+        address: segment.address() as u32,
+        size: segment.size() as u32,
+        file_size: segment.file_range().1 as u32,
+        executable: matches!(segment.flags(), SegmentFlags::Elf { p_flags } if p_flags & elf::PF_X != 0),
+      })
+      .collect()
+  }
+}
+
+fn from_executable(mod_file: &object::File) -> Result<ModImage> {
+  let mut segments = Vec::new();
+  for segment in mod_file.segments() {
+    // find the sections that are part of this segment
+    let segment_range = segment.address()..(segment.address() + segment.size());
+    info!("Segment:");
+    for section in mod_file.sections() {
+      if segment_range.contains(&section.address()) {
+        let section_name = section.name().unwrap_or("<unnamed>");
+        info!("  - {:} @ 0x{:08X} - 0x{:08X} ({:} bytes)",
+              section_name,
+              section.address(),
+              section.address() + section.size(),
+              section.size());
+      }
+    }
+    segments.push(ModSegment {
+      address: segment.address() as u32,
+      size: segment.size() as u32,
+      data: segment.data()?.to_vec(),
+      executable: matches!(segment.flags(), SegmentFlags::Elf { p_flags } if p_flags & elf::PF_X != 0),
+    });
+  }
+
+  let symbols = mod_file.symbols()
+    .filter_map(|sym| {
+      if let Ok(name) = sym.name() {
+        Some((name.to_string(), ModSymbol { address: sym.address() as u32, size: sym.size() as u32 }))
+      } else {
+        None
+      }
+    })
+    .collect();
+
+  Ok(ModImage {
+    segments,
+    symbols,
+    entry: 0,
+    relocated: false,
+  })
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+  if align <= 1 {
+    value
+  } else {
+    value.div_ceil(align) * align
+  }
+}
+
+fn is_alloc(section: &object::Section) -> bool {
+  match section.flags() {
+    SectionFlags::Elf { sh_flags } => sh_flags & elf::SHF_ALLOC as u64 != 0,
+    _ => false,
+  }
+}
+
+fn is_exec(section: &object::Section) -> bool {
+  match section.flags() {
+    SectionFlags::Elf { sh_flags } => sh_flags & elf::SHF_EXECINSTR as u64 != 0,
+    _ => false,
+  }
+}
+
+fn link_relocatable(
+  mod_file: &object::File,
+  load_address: u32,
+  game_symbols: &HashMap<String, u32>,
+) -> Result<ModImage> {
+  info!("Linking relocatable mod at 0x{:08X}", load_address);
+
+  // lay out executable sections first, then initialized data, then zero-filled data,
+  // so we end up with one text and one data segment
+  let alloc_sections: Vec<_> = mod_file.sections()
+    .filter(|s| is_alloc(s) && s.size() > 0)
+    .collect();
+  let text_sections: Vec<_> = alloc_sections.iter().filter(|s| is_exec(s)).collect();
+  let data_sections: Vec<_> = alloc_sections.iter()
+    .filter(|s| !is_exec(s) && s.kind() != SectionKind::UninitializedData)
+    .collect();
+  let bss_sections: Vec<_> = alloc_sections.iter()
+    .filter(|s| !is_exec(s) && s.kind() == SectionKind::UninitializedData)
+    .collect();
+
+  let mut section_addresses: HashMap<SectionIndex, u32> = HashMap::new();
+  let mut text = ModSegment { address: load_address, size: 0, data: Vec::new(), executable: true };
+  for section in text_sections {
+    let address = align_up(text.address + text.data.len() as u32, section.align() as u32);
+    text.data.resize((address - text.address) as usize, 0);
+    text.data.extend_from_slice(section.data()?);
+    section_addresses.insert(section.index(), address);
+  }
+  text.size = text.data.len() as u32;
+
+  let data_start = align_up(text.address + text.size, 32);
+  let mut data = ModSegment { address: data_start, size: 0, data: Vec::new(), executable: false };
+  for section in data_sections {
+    let address = align_up(data.address + data.data.len() as u32, section.align() as u32);
+    data.data.resize((address - data.address) as usize, 0);
+    data.data.extend_from_slice(section.data()?);
+    section_addresses.insert(section.index(), address);
+  }
+  let mut bss_end = data.address + data.data.len() as u32;
+  for section in bss_sections {
+    let address = align_up(bss_end, section.align() as u32);
+    bss_end = address + section.size() as u32;
+    section_addresses.insert(section.index(), address);
+  }
+
+  // common symbols are allocated at the end of the zero-filled data
+  let mut common_addresses: HashMap<SymbolIndex, u32> = HashMap::new();
+  for symbol in mod_file.symbols() {
+    if symbol.section() == SymbolSection::Common {
+      // for common symbols the value is the required alignment
+      let address = align_up(bss_end, symbol.address() as u32);
+      bss_end = address + symbol.size() as u32;
+      common_addresses.insert(symbol.index(), address);
+    }
+  }
+  data.size = bss_end - data.address;
+
+  for section in mod_file.sections() {
+    if let Some(address) = section_addresses.get(&section.index()) {
+      info!("  - {:} @ 0x{:08X} - 0x{:08X} ({:} bytes)",
+            section.name().unwrap_or("<unnamed>"),
+            address,
+            *address as u64 + section.size(),
+            section.size());
+    }
+  }
+
+  let link_end = align_up(bss_end, 32);
+  let mut internal_symbols = HashMap::new();
+  internal_symbols.insert("_LINK_START".to_string(), load_address);
+  internal_symbols.insert("_LINK_END".to_string(), link_end);
+  internal_symbols.insert("_LINK_SIZE".to_string(), link_end - load_address);
+
+  let resolve_symbol = |index: SymbolIndex| -> Result<u32> {
+    let symbol = mod_file.symbol_by_index(index)?;
+    let name = symbol.name().unwrap_or("<unnamed>");
+    match symbol.section() {
+      SymbolSection::Section(section_index) => {
+        let base = section_addresses.get(&section_index)
+          .ok_or_else(|| anyhow::anyhow!("Symbol {} is in a section that is not loaded", name))?;
+        Ok(base + symbol.address() as u32)
+      }
+      SymbolSection::Absolute => Ok(symbol.address() as u32),
+      SymbolSection::Common => Ok(common_addresses[&index]),
+      SymbolSection::Undefined => internal_symbols.get(name)
+        .or_else(|| game_symbols.get(name))
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("Undefined symbol {}", name)),
+      _ => Err(anyhow::anyhow!("Unsupported symbol {}", name)),
+    }
+  };
+
+  let mut symbols = HashMap::new();
+  for symbol in mod_file.symbols() {
+    let Ok(name) = symbol.name() else { continue };
+    if name.is_empty() || symbol.is_undefined() {
+      continue;
+    }
+    if let Ok(address) = resolve_symbol(symbol.index()) {
+      symbols.insert(name.to_string(), ModSymbol { address, size: symbol.size() as u32 });
+    }
+  }
+  for (name, address) in &internal_symbols {
+    symbols.entry(name.clone()).or_insert(ModSymbol { address: *address, size: 0 });
+  }
+
+  let mut segments = vec![text, data];
+  for section in mod_file.sections() {
+    let Some(&section_address) = section_addresses.get(&section.index()) else { continue };
+    let section_name = section.name().unwrap_or("<unnamed>");
+    let segment = segments.iter_mut()
+      .find(|s| s.address <= section_address && section_address < s.address + s.size)
+      .ok_or_else(|| anyhow::anyhow!("Section {} was not placed", section_name))?;
+    for (offset, relocation) in section.relocations() {
+      let RelocationFlags::Elf { r_type } = relocation.flags() else {
+        return Err(anyhow::anyhow!("Unsupported relocation in section {}", section_name));
+      };
+      let target = match relocation.target() {
+        RelocationTarget::Symbol(index) => resolve_symbol(index)?,
+        RelocationTarget::Section(index) => *section_addresses.get(&index)
+          .ok_or_else(|| anyhow::anyhow!("Relocation in {} targets a section that is not loaded", section_name))?,
+        RelocationTarget::Absolute => 0,
+        _ => return Err(anyhow::anyhow!("Unsupported relocation target in section {}", section_name)),
+      };
+      let value = (target as i64 + relocation.addend()) as u32;
+      let place = section_address + offset as u32;
+      let data_offset = (place - segment.address) as usize;
+      let width = match r_type {
+        elf::R_PPC_NONE => 0,
+        elf::R_PPC_ADDR16_LO | elf::R_PPC_ADDR16_HI | elf::R_PPC_ADDR16_HA => 2,
+        _ => 4,
+      };
+      if data_offset + width > segment.data.len() {
+        return Err(anyhow::anyhow!("Relocation at 0x{:08X} in {} is outside the section data", place, section_name));
+      }
+      apply_relocation(&mut segment.data[data_offset..], r_type, place, value)
+        .map_err(|e| anyhow::anyhow!("{} (in {} at 0x{:08X})", e, section_name, place))?;
+    }
+  }
+
+  Ok(ModImage {
+    segments,
+    symbols,
+    entry: 0,
+    relocated: true,
+  })
+}
+
+fn apply_relocation(data: &mut [u8], r_type: u32, place: u32, value: u32) -> Result<()> {
+  let read_u32 = |data: &[u8]| u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+  match r_type {
+    elf::R_PPC_NONE => {}
+    elf::R_PPC_ADDR32 => {
+      data[0..4].copy_from_slice(&value.to_be_bytes());
+    }
+    elf::R_PPC_REL32 => {
+      data[0..4].copy_from_slice(&value.wrapping_sub(place).to_be_bytes());
+    }
+    elf::R_PPC_ADDR16_LO => {
+      data[0..2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+    elf::R_PPC_ADDR16_HI => {
+      data[0..2].copy_from_slice(&((value >> 16) as u16).to_be_bytes());
+    }
+    elf::R_PPC_ADDR16_HA => {
+      // adjust for sign extension of the low half
+      data[0..2].copy_from_slice(&((value.wrapping_add(0x8000) >> 16) as u16).to_be_bytes());
+    }
+    elf::R_PPC_REL24 => {
+      let rel = value.wrapping_sub(place) as i32;
+      if !(-0x0200_0000..0x0200_0000).contains(&rel) || rel & 3 != 0 {
+        return Err(anyhow::anyhow!("R_PPC_REL24 target 0x{:08X} out of range", value));
+      }
+      let insn = (read_u32(data) & !0x03FF_FFFC) | (rel as u32 & 0x03FF_FFFC);
+      data[0..4].copy_from_slice(&insn.to_be_bytes());
+    }
+    elf::R_PPC_REL14 | elf::R_PPC_REL14_BRTAKEN | elf::R_PPC_REL14_BRNTAKEN => {
+      let rel = value.wrapping_sub(place) as i32;
+      if !(-0x8000..0x8000).contains(&rel) || rel & 3 != 0 {
+        return Err(anyhow::anyhow!("R_PPC_REL14 target 0x{:08X} out of range", value));
+      }
+      let insn = (read_u32(data) & !0x0000_FFFC) | (rel as u32 & 0x0000_FFFC);
+      data[0..4].copy_from_slice(&insn.to_be_bytes());
+    }
+    _ => return Err(anyhow::anyhow!("Unsupported relocation type {}", r_type)),
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  fn apply(r_type: u32, insn: u32, place: u32, value: u32) -> Result<u32> {
+    let mut data = insn.to_be_bytes();
+    apply_relocation(&mut data, r_type, place, value)?;
+    Ok(u32::from_be_bytes(data))
+  }
+
+  #[test]
+  fn addr16_ha_carries_into_the_high_half() {
+    // lis r3, 0
+    assert_eq!(apply(elf::R_PPC_ADDR16_HA, 0x3C60_0000, 0, 0x8041_8000).unwrap(), 0x8042_0000);
+    assert_eq!(apply(elf::R_PPC_ADDR16_HA, 0x3C60_0000, 0, 0x8041_7FFF).unwrap(), 0x8041_0000);
+    assert_eq!(apply(elf::R_PPC_ADDR16_HI, 0x3C60_0000, 0, 0x8041_8000).unwrap(), 0x8041_0000);
+    assert_eq!(apply(elf::R_PPC_ADDR16_LO, 0x3863_0000, 0, 0x8041_8000).unwrap(), 0x8000_0000);
+  }
+
+  #[test]
+  fn addr32_and_rel32() {
+    assert_eq!(apply(elf::R_PPC_ADDR32, 0, 0x8000_3000, 0x8000_3100).unwrap(), 0x8000_3100);
+    assert_eq!(apply(elf::R_PPC_REL32, 0, 0x8000_3100, 0x8000_3000).unwrap(), 0xFFFF_FF00);
+  }
+
+  #[test]
+  fn rel24_encodes_branches() {
+    // bl forward and backward, keeping the link bit
+    assert_eq!(apply(elf::R_PPC_REL24, 0x4800_0001, 0x8000_3000, 0x8000_3100).unwrap(), 0x4800_0101);
+    assert_eq!(apply(elf::R_PPC_REL24, 0x4800_0001, 0x8000_3000, 0x8000_2F00).unwrap(), 0x4BFF_FF01);
+    // furthest reachable targets
+    assert_eq!(apply(elf::R_PPC_REL24, 0x4800_0000, 0x8100_0000, 0x82FF_FFFC).unwrap(), 0x49FF_FFFC);
+    assert_eq!(apply(elf::R_PPC_REL24, 0x4800_0000, 0x8300_0000, 0x8100_0000).unwrap(), 0x4A00_0000);
+  }
+
+  #[test]
+  fn rel24_rejects_far_and_misaligned_targets() {
+    assert!(apply(elf::R_PPC_REL24, 0x4800_0001, 0x8100_0000, 0x8300_0000).is_err());
+    assert!(apply(elf::R_PPC_REL24, 0x4800_0001, 0x8300_0000, 0x80FF_FFFC).is_err());
+    assert!(apply(elf::R_PPC_REL24, 0x4800_0001, 0x8000_3000, 0x8000_3102).is_err());
+  }
+
+  #[test]
+  fn rel14_encodes_conditional_branches() {
+    // beq, keeping BO and BI
+    assert_eq!(apply(elf::R_PPC_REL14, 0x4182_0000, 0x8000_3000, 0x8000_3010).unwrap(), 0x4182_0010);
+    assert_eq!(apply(elf::R_PPC_REL14, 0x4182_0000, 0x8000_3000, 0x8000_2FF0).unwrap(), 0x4182_FFF0);
+    assert_eq!(apply(elf::R_PPC_REL14_BRTAKEN, 0x4182_0000, 0x8000_3000, 0x8000_AFFC).unwrap(), 0x4182_7FFC);
+  }
+
+  #[test]
+  fn rel14_rejects_far_and_misaligned_targets() {
+    assert!(apply(elf::R_PPC_REL14, 0x4182_0000, 0x8000_3000, 0x8000_B000).is_err());
+    assert!(apply(elf::R_PPC_REL14, 0x4182_0000, 0x8000_B004, 0x8000_3000).is_err());
+    assert!(apply(elf::R_PPC_REL14, 0x4182_0000, 0x8000_3000, 0x8000_3006).is_err());
+  }
+
+  #[test]
+  fn unsupported_relocations_are_errors() {
+    assert!(apply(elf::R_PPC_SDAREL16, 0, 0, 0).is_err());
+  }
+
+  /// A big-endian ELF32 relocatable object with the given sections.
+  /// Sections are `(name, sh_type, sh_flags, sh_link, sh_info, sh_entsize, data)`.
+  pub(crate) fn build_elf(sections: &[(&str, u32, u32, u32, u32, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut shstrtab = vec![0u8];
+    let mut name_offsets = Vec::new();
+    for (name, ..) in sections.iter().chain([&(".shstrtab", elf::SHT_STRTAB, 0, 0, 0, 0, Vec::new())]) {
+      name_offsets.push(shstrtab.len() as u32);
+      shstrtab.extend_from_slice(name.as_bytes());
+      shstrtab.push(0);
+    }
+
+    let mut bytes = vec![0u8; 52];
+    let mut headers = vec![0u8; 40];
+    let mut add_section = |bytes: &mut Vec<u8>, name: u32, sh_type: u32, flags: u32, link: u32, info: u32, entsize: u32, data: &[u8]| {
+      bytes.resize(bytes.len().next_multiple_of(4), 0);
+      let offset = bytes.len() as u32;
+      bytes.extend_from_slice(data);
+      for value in [name, sh_type, flags, 0, offset, data.len() as u32, link, info, 4, entsize] {
+        headers.extend_from_slice(&value.to_be_bytes());
+      }
+    };
+    for ((_, sh_type, flags, link, info, entsize, data), name) in sections.iter().zip(&name_offsets) {
+      add_section(&mut bytes, *name, *sh_type, *flags, *link, *info, *entsize, data);
+    }
+    add_section(&mut bytes, name_offsets[sections.len()], elf::SHT_STRTAB, 0, 0, 0, 0, &shstrtab);
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+    let shoff = bytes.len() as u32;
+    let shnum = sections.len() as u16 + 2;
+    bytes.extend_from_slice(&headers);
+
+    bytes[0..8].copy_from_slice(&[0x7F, b'E', b'L', b'F', elf::ELFCLASS32, elf::ELFDATA2MSB, elf::EV_CURRENT, 0]);
+    let mut header = Vec::new();
+    header.extend_from_slice(&elf::ET_REL.to_be_bytes());
+    header.extend_from_slice(&elf::EM_PPC.to_be_bytes());
+    header.extend_from_slice(&1u32.to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes());
+    header.extend_from_slice(&shoff.to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes());
+    for value in [52u16, 0, 0, 40, shnum, shnum - 1] {
+      header.extend_from_slice(&value.to_be_bytes());
+    }
+    bytes[16..52].copy_from_slice(&header);
+    bytes
+  }
+
+  pub(crate) fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_be_bytes()).collect()
+  }
+
+  fn rela(offset: u32, symbol: u32, r_type: u32) -> [u32; 3] {
+    [offset, (symbol << 8) | r_type, 0]
+  }
+
+  pub(crate) fn symbol(name: u32, value: u32, size: u32, info: u8, shndx: u16) -> Vec<u8> {
+    let mut bytes = words(&[name, value, size]);
+    bytes.extend_from_slice(&[info, 0]);
+    bytes.extend_from_slice(&shndx.to_be_bytes());
+    bytes
+  }
+
+  #[test]
+  fn links_relocatable_object() {
+    let text = words(&[
+      0x3C60_0000, // lis r3, data_var@ha
+      0x3863_0000, // addi r3, r3, data_var@l
+      0x4800_0001, // bl game_func
+      0x4E80_0020, // blr
+    ]);
+    let data = words(&[0x1234_5678, 0]);
+    let rela_text = words(&[
+      rela(2, 1, elf::R_PPC_ADDR16_HA),
+      rela(6, 1, elf::R_PPC_ADDR16_LO),
+      rela(8, 3, elf::R_PPC_REL24),
+    ].concat());
+    let rela_data = words(&rela(4, 3, elf::R_PPC_ADDR32));
+    let strtab = b"\0data_var\0mod_entry\0game_func\0".to_vec();
+    let symtab = [
+      symbol(0, 0, 0, 0, 0),
+      symbol(1, 0, 4, (elf::STB_LOCAL << 4) | elf::STT_OBJECT, 2),
+      symbol(10, 0, 16, (elf::STB_GLOBAL << 4) | elf::STT_FUNC, 1),
+      symbol(20, 0, 0, elf::STB_GLOBAL << 4, 0),
+    ].concat();
+    let elf_bytes = build_elf(&[
+      (".text", elf::SHT_PROGBITS, elf::SHF_ALLOC | elf::SHF_EXECINSTR, 0, 0, 0, text),
+      (".data", elf::SHT_PROGBITS, elf::SHF_ALLOC | elf::SHF_WRITE, 0, 0, 0, data),
+      (".rela.text", elf::SHT_RELA, 0, 5, 1, 12, rela_text),
+      (".rela.data", elf::SHT_RELA, 0, 5, 2, 12, rela_data),
+      (".symtab", elf::SHT_SYMTAB, 0, 6, 2, 16, symtab),
+      (".strtab", elf::SHT_STRTAB, 0, 0, 0, 0, strtab),
+    ]);
+    let mod_file = object::File::parse(&*elf_bytes).unwrap();
+    let game_symbols = HashMap::from([("game_func".to_string(), 0x8000_3100)]);
+
+    // the data section starts at 0x80408000, so @ha has to carry
+    let image = ModImage::from_elf(&mod_file, Some(0x8040_7FE0), Some("mod_entry"), &game_symbols).unwrap();
+    assert!(image.relocated);
+    assert_eq!(image.entry, 0x8040_7FE0);
+    assert_eq!(image.symbol("data_var"), Some(0x8040_8000));
+    assert_eq!(image.symbol("_LINK_END"), Some(0x8040_8020));
+    assert_eq!(image.segments[0].address, 0x8040_7FE0);
+    assert_eq!(image.segments[0].data, words(&[0x3C60_8041, 0x3863_8000, 0x4BBF_B119, 0x4E80_0020]));
+    assert_eq!(image.segments[1].address, 0x8040_8000);
+    assert_eq!(image.segments[1].data, words(&[0x1234_5678, 0x8000_3100]));
+
+    let missing = ModImage::from_elf(&mod_file, Some(0x8040_7FE0), Some("mod_entry"), &HashMap::new());
+    assert!(missing.unwrap_err().to_string().contains("Undefined symbol game_func"));
+    // bl can't reach more than 32 MiB
+    let far = ModImage::from_elf(&mod_file, Some(0x8300_0000), Some("mod_entry"), &game_symbols);
+    assert!(far.unwrap_err().to_string().contains("R_PPC_REL24"));
+  }
+}
@@ -1,4 +1,8 @@
+use log::info;
+use object::{Object, ObjectSection};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
@@ -10,12 +14,53 @@ pub struct ModData {
   /// This will override the output path for both ISO and DOL outputs
   /// Specified via CLI only
   pub output_path_override: Option<PathBuf>,
+  /// Additional symbol maps to load, regardless of game revision
+  /// Specified via CLI only
+  pub symbol_map_files: Vec<PathBuf>,
+  /// Additional Gecko code lists to bake into the DOL
+  /// Specified via CLI only
+  pub gecko_code_files: Vec<PathBuf>,
+  /// Patch even if mod sections overlap the game or are outside of MEM1
+  /// Specified via CLI only
+  pub ignore_memory_map: bool,
+  /// Mods applied after this one, into the same output.
+  /// The output names and ISO settings of this mod are used.
+  /// Specified via CLI only
+  pub additional_mods: Vec<ModData>,
+  /// Patch the original stored in already patched inputs, instead of refusing them
+  pub repatch: bool,
+  /// Check everything and report what would change, without writing any files
+  /// Specified via CLI only
+  pub dry_run: bool,
+  /// Lay out the whole ISO again instead of fitting the new data into unused space.
+  /// Rebuilt ISOs can't be reverted.
+  pub rebuild: bool,
 }
 
 impl ModData {
   pub fn parse_elf(&self) -> Result<object::File<'_>, object::Error> {
     object::File::parse(&self.elf_bytes)
   }
+
+  /// Read data referenced by the config, either from a file relative to the working directory
+  /// or from a section of the mod ELF
+  pub fn read_file_or_section(&self, file: &Option<String>, section: &Option<String>) -> anyhow::Result<Vec<u8>> {
+    match (file, section) {
+      (Some(file), None) => {
+        let path = std::env::current_dir()?.join(file);
+        info!("Loading {:?}", path);
+        fs::read(&path).map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))
+      }
+      (None, Some(section_name)) => {
+        info!("Loading ELF section {}", section_name);
+        let mod_file = self.parse_elf()?;
+        let section = mod_file.section_by_name(section_name)
+          .ok_or_else(|| anyhow::anyhow!("Section {} not found in mod ELF", section_name))?;
+        Ok(section.data()?.to_vec())
+      }
+      _ => Err(anyhow::anyhow!("Exactly one of file or section must be set")),
+    }
+  }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,12 +77,120 @@ pub struct ModConfig {
 
   /// Symbol name of the place to jump *from* to entry point of the mod
   pub entry_point_symbol: String,
+  /// Symbol name of the mod's entry point. Defaults to the ELF entry point.
+  /// Required for relocatable mods.
+  pub mod_entry_symbol: Option<String>,
+  /// Address to link relocatable mods at.
+  /// Defaults to the game's original arena lo, read from the arena lo patch site.
+  pub load_address: Option<u32>,
+  /// Route the entry hook and branch patches through a `lis/ori/mtctr/bctr` veneer placed after
+  /// the mod when their target is out of range of a relative branch.
+  /// Veneers clobber r12 and ctr, so hooks and Gecko codes never use them.
+  #[serde(default)]
+  pub long_branch_veneers: bool,
+  /// Cover the mod's zero-filled data with the DOL's BSS range instead of storing zeros in the DOL.
+  /// Only possible when it starts right after the game's BSS, otherwise it is zero-padded.
+  /// Games only clear the BSS in their own linker tables at startup, not the DOL header's range,
+  /// so the extended BSS is only zero if the loader zeroes it. Mods shouldn't rely on that.
+  #[serde(default)]
+  pub extend_dol_bss: bool,
+  /// Addresses of game symbols, used for symbols the mod ELF does not define.
+  /// Takes priority over symbols loaded from symbol maps.
+  #[serde(default)]
+  pub symbols: HashMap<String, u32>,
+  /// Symbol maps of the game, used for symbols the mod ELF does not define
+  #[serde(default)]
+  pub symbol_maps: Vec<SymbolMapConfig>,
+  /// Where to put relocatable mods, below the game's arena or at the top of it
+  #[serde(default)]
+  pub placement: Placement,
+  /// Highest address the mod may use with top placement.
+  /// Defaults to the game's original arena hi, read from the arena hi patch site.
+  pub memory_ceiling: Option<u32>,
+  /// Fail if the game's arena would be smaller than this after making room for the mod
+  pub min_heap_size: Option<u32>,
+  /// `lis`/`addi` pairs in the game that set up the arena, patched to leave room for the mod.
+  /// Defaults to setting arena lo with r3 at `_PATCH_ARENA_LO_1` and `_PATCH_ARENA_LO_2`.
+  #[serde(default)]
+  pub arena_patches: Vec<ArenaPatchConfig>,
   /// List of additional branch patches to apply
   #[serde(default)]
   pub branch_patches: Vec<PatchBranchConfig>,
+  /// List of data patches to apply, after the branch patches
+  #[serde(default)]
+  pub patches: Vec<PatchConfig>,
+  /// Gecko code lists to bake into the DOL, applied after all other patches
+  #[serde(default)]
+  pub gecko_codes: Vec<GeckoCodesConfig>,
   /// List of FST files to truncate
   #[serde(default)]
   pub truncate_files: Vec<String>,
+  /// Files to replace, add or delete in the ISO's FST, in order, after truncating
+  #[serde(default)]
+  pub files: Vec<FileConfig>,
+}
+
+impl ModConfig {
+  /// The configured arena patch sites, or the legacy ones
+  pub fn arena_patches(&self) -> Vec<ArenaPatchConfig> {
+    if !self.arena_patches.is_empty() {
+      return self.arena_patches.clone();
+    }
+    ["_PATCH_ARENA_LO_1", "_PATCH_ARENA_LO_2"].iter()
+      .map(|symbol| ArenaPatchConfig {
+        symbol: Some(symbol.to_string()),
+        address: None,
+        register: default_arena_register(),
+        bound: ArenaBound::Lo,
+        align: default_arena_align(),
+      })
+      .collect()
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArenaPatchConfig {
+  /// Symbol of the `lis`, from the mod ELF or the game's symbols
+  pub symbol: Option<String>,
+  /// Address of the `lis`, instead of a symbol
+  pub address: Option<u32>,
+  /// Register the pair loads the arena bound into
+  #[serde(default = "default_arena_register")]
+  pub register: u32,
+  /// Which end of the arena the pair sets
+  #[serde(default)]
+  pub bound: ArenaBound,
+  /// Arena lo is rounded up to a multiple of this, arena hi is rounded down
+  #[serde(default = "default_arena_align")]
+  pub align: u32,
+}
+
+fn default_arena_register() -> u32 {
+  3
+}
+
+fn default_arena_align() -> u32 {
+  32
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArenaBound {
+  /// Start of the arena, moved up past the end of the mod with bottom placement
+  #[default]
+  Lo,
+  /// End of the arena, moved down below the start of the mod with top placement
+  Hi,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+  /// Load the mod at the start of the arena and move arena lo up
+  #[default]
+  Bottom,
+  /// Load the mod at the end of the arena, or below `memory_ceiling`, and move arena hi down
+  Top,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,4 +199,223 @@ pub struct PatchBranchConfig {
   pub to_symbol: String,
   #[serde(default)]
   pub link: bool,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchConfig {
+  /// Symbol to patch, from the mod ELF or the game's symbols
+  pub symbol: Option<String>,
+  /// Address to patch, instead of a symbol
+  pub address: Option<u32>,
+  /// Added to the symbol or address
+  #[serde(default)]
+  pub offset: u32,
+  #[serde(flatten)]
+  pub data: PatchData,
+  /// Value that must be at the address before patching.
+  /// Either an integer, or a hex string of bytes.
+  pub expect: Option<PatchExpect>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PatchData {
+  U8 { value: u8 },
+  U16 { value: u16 },
+  U32 { value: u32 },
+  /// Hex string, whitespace is ignored
+  Bytes { bytes: String },
+  /// Copy the contents of a symbol in the mod ELF
+  SymbolData { source: String },
+  /// PowerPC assembly, e.g. `"li r3, 1"` or `["bl MySymbol", "nop"]`.
+  /// Symbols are resolved through the mod ELF and the game's symbols.
+  Asm { asm: AsmSource },
+  /// Replace the instruction with a branch to a generated trampoline that calls `function`,
+  /// runs the replaced instruction and branches back
+  Hook { function: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AsmSource {
+  Line(String),
+  Lines(Vec<String>),
+}
+
+impl AsmSource {
+  pub fn to_source(&self) -> String {
+    match self {
+      AsmSource::Line(line) => line.clone(),
+      AsmSource::Lines(lines) => lines.join("\n"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PatchExpect {
+  Value(u32),
+  Bytes(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeckoCodesConfig {
+  /// Path to a Gecko code list (text or .gct), relative to the working directory
+  pub file: Option<String>,
+  /// Name of a section in the mod ELF containing a Gecko code list
+  pub section: Option<String>,
+  /// Only use the codes with these `$Name` headers. All codes are used when empty.
+  #[serde(default)]
+  pub names: Vec<String>,
+  /// Only use these codes when the input DOL has this hash
+  pub dol_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SymbolMapConfig {
+  /// Path to the symbol map, relative to the working directory
+  pub file: Option<String>,
+  /// Name of a section in the mod ELF containing the symbol map
+  pub section: Option<String>,
+  /// Only use this map when the input DOL has this hash
+  pub dol_hash: Option<String>,
+  #[serde(default)]
+  pub format: SymbolMapFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolMapFormat {
+  #[default]
+  Auto,
+  /// Symbol map saved by Dolphin
+  Dolphin,
+  /// CodeWarrior linker map
+  CodeWarrior,
+  /// decomp-toolkit `symbols.txt`
+  SymbolsTxt,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileConfig {
+  /// Path of the file in the FST, e.g. `Audio/frontend_1.sp.dsp`
+  pub path: String,
+  #[serde(default)]
+  pub action: FileAction,
+  /// New contents of the file, relative to the working directory
+  pub file: Option<String>,
+  /// Name of a section in the mod ELF with the new contents of the file
+  pub section: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+  /// Replace the contents of a file
+  #[default]
+  Replace,
+  /// Add a new file, creating the directories it is in
+  Add,
+  /// Remove the file or directory from the FST. Its data stays in the ISO, unreferenced.
+  Delete,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Deserialize)]
+  struct Patches {
+    patches: Vec<PatchConfig>,
+  }
+
+  fn parse(toml: &str) -> Vec<PatchConfig> {
+    toml::from_str::<Patches>(toml).unwrap().patches
+  }
+
+  #[test]
+  fn patches_of_every_kind() {
+    let patches = parse(r#"
+      [[patches]]
+      address = 0x80003100
+      kind = "u8"
+      value = 0xFF
+      [[patches]]
+      symbol = "Game_Flags"
+      offset = 2
+      kind = "u16"
+      value = 0x1234
+      [[patches]]
+      address = 0x80003104
+      kind = "u32"
+      value = 0x60000000
+      [[patches]]
+      symbol = "Game_Name"
+      kind = "bytes"
+      bytes = "DE AD BE EF"
+      [[patches]]
+      symbol = "Game_Table"
+      kind = "symbol_data"
+      source = "mod_table"
+      [[patches]]
+      address = 0x80003108
+      kind = "asm"
+      asm = "li r3, 1"
+      [[patches]]
+      address = 0x8000310C
+      kind = "asm"
+      asm = ["bl mod_function", "nop"]
+      [[patches]]
+      symbol = "Game_Update"
+      offset = 0x10
+      kind = "hook"
+      function = "mod_update"
+    "#);
+    assert_eq!(patches.len(), 8);
+    assert!(matches!(patches[0].data, PatchData::U8 { value: 0xFF }));
+    assert!(matches!(patches[1].data, PatchData::U16 { value: 0x1234 }));
+    assert!(matches!(patches[2].data, PatchData::U32 { value: 0x6000_0000 }));
+    assert!(matches!(&patches[3].data, PatchData::Bytes { bytes } if bytes == "DE AD BE EF"));
+    assert!(matches!(&patches[4].data, PatchData::SymbolData { source } if source == "mod_table"));
+    assert!(matches!(&patches[5].data, PatchData::Asm { asm } if asm.to_source() == "li r3, 1"));
+    assert!(matches!(&patches[6].data, PatchData::Asm { asm } if asm.to_source() == "bl mod_function\nnop"));
+    assert!(matches!(&patches[7].data, PatchData::Hook { function } if function == "mod_update"));
+
+    assert_eq!(patches[0].address, Some(0x8000_3100));
+    assert_eq!(patches[0].offset, 0);
+    assert_eq!(patches[1].symbol.as_deref(), Some("Game_Flags"));
+    assert_eq!(patches[1].offset, 2);
+    assert_eq!(patches[7].offset, 0x10);
+    assert!(patches.iter().all(|patch| patch.expect.is_none()));
+  }
+
+  #[test]
+  fn expected_values_are_integers_or_hex_strings() {
+    let patches = parse(r#"
+      [[patches]]
+      address = 0x80003100
+      kind = "u32"
+      value = 0x60000000
+      expect = 0x4E800020
+      [[patches]]
+      address = 0x80003104
+      kind = "bytes"
+      bytes = "00 01"
+      expect = "4E80 0020"
+    "#);
+    assert!(matches!(patches[0].expect, Some(PatchExpect::Value(0x4E80_0020))));
+    assert!(matches!(&patches[1].expect, Some(PatchExpect::Bytes(bytes)) if bytes == "4E80 0020"));
+  }
+
+  #[test]
+  fn invalid_patches_are_errors() {
+    for toml in [
+      "[[patches]]\naddress = 0\nkind = \"u8\"\nvalue = 0x100",
+      "[[patches]]\naddress = 0\nkind = \"u64\"\nvalue = 0",
+      "[[patches]]\naddress = 0\nkind = \"hook\"",
+      "[[patches]]\naddress = 0\nvalue = 0",
+    ] {
+      assert!(toml::from_str::<Patches>(toml).is_err(), "{}", toml);
+    }
+  }
+}
@@ -1,11 +1,24 @@
+use crate::PatchOutput;
 use crate::binstream::{BinStreamRead, BinStreamReadable, BinStreamWritable, BinStreamWrite};
-use crate::dol::DolHeader;
-use crate::patch_config::ModData;
+use crate::dol::{DolHeader, SectionInfo};
+use crate::error::{HashedFile, PatchError};
+use crate::embedded::{read_embedded, EmbeddedData, PatchSignature, TAG_MANIFEST, TAG_REVERT_DOL, TAG_SIGNATURE};
+use crate::gecko::{load_gecko_codes, GeckoPatch};
+use crate::manifest::{AppliedMod, AppliedPatch, AppliedSegment, PatchManifest};
+use crate::memory_map::{build_memory_map, check_memory_map, MEM1_END};
+use crate::mod_image::{ModImage, ModSegment};
+use crate::patch_config::{ArenaBound, ArenaPatchConfig, ModData, PatchData, PatchExpect, Placement};
+use crate::ppc_asm::{assemble, branch_in_range, build_addi, build_b_rel24, build_lis};
+use crate::trampoline::{build_trampoline, build_veneer};
+use crate::plan::{plan_dol_changes, PatchPlan};
 use crate::progress::Progress;
+use crate::revert::{already_patched_error, describe_patched, revert_dol_bytes, RevertRecord};
+use crate::symbol_map::load_symbol_maps;
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
 use md5::Digest;
-use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol};
+use object::{Object, ObjectKind};
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::PathBuf;
@@ -15,20 +28,46 @@ pub fn patch_dol_file<F>(
   in_path: &PathBuf,
   out_path: &PathBuf,
   mod_data: &ModData,
-) -> Result<()> where F: Fn(Progress) {
-  if !mod_data.overwrite_output && out_path.exists() {
-    return Err(anyhow::anyhow!("Output file already exists: {:?}", out_path));
+) -> Result<PatchOutput> where F: Fn(Progress) {
+  if !mod_data.overwrite_output && !mod_data.dry_run && out_path.exists() {
+    return Err(PatchError::OutputExists { path: out_path.clone() }.into());
   }
 
   progress_update(Progress::new(0, 4, "Reading DOL".to_string()));
   info!("Preparing to patch DOL file...");
   info!("Reading DOL file from {:?}", in_path);
-  let dol_bytes = fs::read(in_path)?;
+  let mut dol_bytes = fs::read(in_path)?;
   info!("Read DOL file: {} bytes", dol_bytes.len());
+  if let Some(embedded) = read_embedded(&dol_bytes)? {
+    if !mod_data.repatch {
+      return Err(already_patched_error(in_path, Some(&embedded), TAG_REVERT_DOL));
+    }
+    info!("{:?} was already patched with {}, patching the original stored in it", in_path, describe_patched(&embedded));
+    revert_dol_bytes(&mut dol_bytes)?;
+  }
 
   progress_update(Progress::new(1, 4, "Patching DOL".to_string()));
   // path is relative to the executable
-  let out_bytes = patch_dol(&mod_data, &dol_bytes)?;
+  let (mut out_bytes, manifest) = patch_dol(mod_data, &dol_bytes)?;
+  let revert_record = RevertRecord::diff(&dol_bytes, &out_bytes, Some(manifest.input_dol_hash.clone()));
+  let mut embedded = EmbeddedData::default();
+  embedded.push(TAG_SIGNATURE, PatchSignature::from_manifest(&manifest).to_bytes()?);
+  embedded.push(TAG_MANIFEST, manifest.to_toml()?.into_bytes());
+  embedded.push(TAG_REVERT_DOL, revert_record.to_bytes()?);
+  embedded.append_to(&mut out_bytes)?;
+
+  if mod_data.dry_run {
+    // compared to the original when re-patching
+    let plan = PatchPlan {
+      output_path: out_path.clone(),
+      output_size: out_bytes.len() as u64,
+      changes: plan_dol_changes(&dol_bytes, &out_bytes, &manifest)?,
+      dol_changes: Vec::new(),
+      manifest,
+    };
+    progress_update(Progress::new(4, 4, "Done planning DOL patch".to_string()));
+    return Ok(PatchOutput { path: out_path.clone(), manifest: plan.manifest.clone(), plan: Some(plan) });
+  }
 
   progress_update(Progress::new(3, 4, "Writing DOL".to_string()));
   info!("Writing patched DOL file to {:?}", out_path);
@@ -37,169 +76,709 @@ pub fn patch_dol_file<F>(
   info!("Mod size (in dol): {} bytes", out_bytes.len() - dol_bytes.len());
   progress_update(Progress::new(4, 4, "Done patching dol".to_string()));
 
-  Ok(())
+  Ok(PatchOutput { path: out_path.clone(), manifest, plan: None })
 }
 
 pub fn patch_dol(
   mod_data: &ModData,
   dol_bytes: &[u8],
-) -> Result<Vec<u8>> {
-  if let Some(expected_dol_hash) = mod_data.config.expected_dol_hash.clone() {
-    info!("Verifying input DOL hash...");
-    let mut hasher = md5::Md5::new();
-    hasher.update(dol_bytes);
-    let result_hash = format!("{:x}", hasher.finalize());
-    if result_hash != expected_dol_hash {
-      return Err(anyhow::anyhow!(
-                "Input DOL hash does not match expected hash. Expected: {}, Got: {}. Check \"Ignore Hash\" option to bypass this check.",
-                expected_dol_hash,
-                result_hash
-            ));
+) -> Result<(Vec<u8>, PatchManifest)> {
+  let dol_hash = md5_hex(dol_bytes);
+  let mods: Vec<&ModData> = std::iter::once(mod_data).chain(&mod_data.additional_mods).collect();
+  for mod_data in &mods {
+    if let Some(expected_dol_hash) = mod_data.config.expected_dol_hash.clone() {
+      info!("Verifying input DOL hash for {}...", mod_data.config.mod_name);
+      if dol_hash != expected_dol_hash {
+        return Err(PatchError::HashMismatch {
+          file: HashedFile::Dol,
+          expected: expected_dol_hash,
+          actual: dol_hash,
+        }.into());
+      }
     }
   }
 
   let mut dol_header = DolHeader::read_from_stream(&mut io::Cursor::new(dol_bytes))?;
+  let original_dol_header = dol_header.clone();
   info!("DOL Header: {:?}", dol_header);
 
+  // mods placed at the bottom go one after the other upwards from the arena's start,
+  // mods placed at the top go downwards from its end
+  let mut next_bottom = None;
+  let mut next_top = None;
+  let mut layouts = Vec::new();
+  for mod_data in &mods {
+    info!("Laying out mod {} {}", mod_data.config.mod_name, mod_data.config.version);
+    let layout = place_mod(mod_data, &dol_header, dol_bytes, &dol_hash, next_bottom, next_top)?;
+    match layout.placement {
+      Placement::Bottom => next_bottom = Some(layout.end.div_ceil(32) * 32),
+      Placement::Top => next_top = Some(layout.start),
+    }
+    layouts.push(layout);
+  }
+  let writes = combine_writes(&mods, &layouts)?;
+  let segments: Vec<ModSegment> = layouts.iter().flat_map(|l| l.segments.iter().cloned()).collect();
+
+  check_mod_bss(&dol_header, &segments)?;
+
+  // move the arena bounds next to the mods out of their way, and find where the heap ends up
+  let bottom_end = layouts.iter().filter(|l| l.placement == Placement::Bottom).map(|l| l.end).max();
+  let top_start = layouts.iter().filter(|l| l.placement == Placement::Top).map(|l| l.start).min();
+  let mut arena_sites: Vec<(&ArenaPatchConfig, u32)> = Vec::new();
+  for (site, site_addr) in layouts.iter().flat_map(|l| l.arena_sites.iter()) {
+    match arena_sites.iter().find(|(_, addr)| addr == site_addr) {
+      Some((existing, _)) if existing.bound != site.bound || existing.register != site.register => {
+        return Err(PatchError::ArenaPatchConflict { address: *site_addr }.into());
+      }
+      Some(_) => {}
+      None => arena_sites.push((site, *site_addr)),
+    }
+  }
+  let mut arena_lo = None;
+  let mut arena_hi = None;
+  let mut arena_writes = Vec::new();
+  for (site, site_addr) in arena_sites {
+    if site.align == 0 {
+      return Err(PatchError::ZeroArenaAlign { address: site_addr }.into());
+    }
+    let (value, patched) = match (site.bound, bottom_end, top_start) {
+      (ArenaBound::Lo, Some(end), _) => (end.div_ceil(site.align) * site.align, true),
+      (ArenaBound::Hi, _, Some(start)) => (start / site.align * site.align, true),
+      _ => (read_lis_addi(&dol_header, dol_bytes, site_addr)?, false),
+    };
+    match site.bound {
+      ArenaBound::Lo => arena_lo = Some(arena_lo.map_or(value, |lo: u32| lo.max(value))),
+      ArenaBound::Hi => arena_hi = Some(arena_hi.map_or(value, |hi: u32| hi.min(value))),
+    }
+    if patched {
+      arena_writes.push((site_addr, site.register, site.bound, value));
+    }
+  }
+  for (bound, used) in [(ArenaBound::Lo, bottom_end.is_some()), (ArenaBound::Hi, top_start.is_some())] {
+    if used && !arena_writes.iter().any(|(_, _, b, _)| *b == bound) {
+      return Err(PatchError::MissingArenaSite { bound }.into());
+    }
+  }
+  // without an arena lo site, the arena starts after the game's BSS
+  let arena_lo = arena_lo.unwrap_or(dol_header.bss_addr + dol_header.bss_size);
+  let arena_hi = arena_hi.unwrap_or(MEM1_END);
+  for mod_data in &mods {
+    let Some(min_heap_size) = mod_data.config.min_heap_size else {
+      continue;
+    };
+    let heap_size = arena_hi.saturating_sub(arena_lo);
+    if heap_size < min_heap_size {
+      return Err(PatchError::HeapTooSmall {
+        mod_name: mod_data.config.mod_name.clone(),
+        arena_lo,
+        arena_hi,
+        required: min_heap_size,
+        available: heap_size,
+      }.into());
+    }
+  }
+
+  let mut output_bytes = dol_bytes.to_vec();
+
+  // each mod decides whether its zero-filled memory may extend the DOL's BSS
+  let mut filled_segments = Vec::new();
+  for (mod_data, layout) in mods.iter().zip(&layouts) {
+    for segment in &layout.segments {
+      filled_segments.push(fill_segment_bss(&mut dol_header, segment, mod_data.config.extend_dol_bss));
+    }
+  }
+  place_segments(&mut dol_header, &mut output_bytes, filled_segments)?;
+
+  let regions = build_memory_map(&original_dol_header, &dol_header, arena_lo, arena_hi);
+  for region in &regions {
+    info!("Memory region {}: 0x{:08X} - 0x{:08X}{}", region.name, region.start, region.end, if region.from_mod { " (mod)" } else { "" });
+  }
+  let problems = check_memory_map(&regions);
+  if !problems.is_empty() {
+    if !mod_data.ignore_memory_map {
+      return Err(PatchError::InvalidMemoryMap { problems }.into());
+    }
+    for problem in &problems {
+      warn!("Ignoring memory map problem: {}", problem);
+    }
+  }
+
+  info!("Updating DOL header");
+  dol_header.write_to_stream(&mut io::Cursor::new(&mut output_bytes[..]))?;
+
+  info!("Reloading DOL for testing patches...");
+  let new_dol_header = DolHeader::read_from_stream(&mut io::Cursor::new(&output_bytes[..]))?;
+  info!("New DOL Header: {:?}", new_dol_header);
+
+  let mut applied_arena_patches = Vec::new();
+  for (site_addr, register, bound, value) in arena_writes {
+    info!("Patching arena {:?} at 0x{:08X} to 0x{:08X} (r{})", bound, site_addr, value, register);
+    patch_lis_addi(&dol_header, &mut output_bytes, site_addr, register, value)?;
+    applied_arena_patches.push(AppliedPatch {
+      address: site_addr,
+      size: 8,
+      description: format!("arena {:?}", bound).to_lowercase(),
+    });
+  }
+  for (_, write) in &writes {
+    if let Some(expected) = &write.expect {
+      let current = read_dol_addr_bytes(&dol_header, &output_bytes, write.address, expected.len())?;
+      if &current != expected {
+        return Err(PatchError::UnexpectedData {
+          address: write.address,
+          expected: expected.clone(),
+          actual: current,
+        }.into());
+      }
+    }
+    info!("Applying {} at 0x{:08X}: {}", write.description, write.address, format_hex_bytes(&write.data));
+    patch_dol_addr_bytes(&dol_header, &mut output_bytes, write.address, &write.data)?;
+  }
+
+  let manifest = PatchManifest {
+    patcher_version: env!("CARGO_PKG_VERSION").to_string(),
+    timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    input_iso_hash: None,
+    input_dol_hash: dol_hash,
+    output_dol_hash: md5_hex(&output_bytes),
+    arena_lo,
+    arena_hi,
+    arena_patches: applied_arena_patches,
+    mods: mods.iter().zip(&layouts).enumerate()
+      .map(|(i, (mod_data, layout))| AppliedMod {
+        game_name: mod_data.config.game_name.clone(),
+        mod_name: mod_data.config.mod_name.clone(),
+        version: mod_data.config.version.clone(),
+        placement: layout.placement,
+        start: layout.start,
+        end: layout.end,
+        entry: layout.entry,
+        segments: layout.segments.iter()
+          .map(|s| AppliedSegment { address: s.address, size: s.size, executable: s.executable })
+          .collect(),
+        patches: writes.iter()
+          .filter(|(mod_index, _)| *mod_index == i)
+          .map(|(_, w)| AppliedPatch { address: w.address, size: w.data.len() as u32, description: w.description.to_string() })
+          .collect(),
+      })
+      .collect(),
+  };
+
+  Ok((output_bytes, manifest))
+}
+
+fn md5_hex(bytes: &[u8]) -> String {
+  let mut hasher = md5::Md5::new();
+  hasher.update(bytes);
+  format!("{:x}", hasher.finalize())
+}
+
+/// Link a mod below the arena or at its top. `next_bottom` and `next_top` are where the previous
+/// mods left room, instead of the game's original arena bounds.
+fn place_mod(
+  mod_data: &ModData,
+  dol_header: &DolHeader,
+  dol_bytes: &[u8],
+  dol_hash: &str,
+  next_bottom: Option<u32>,
+  next_top: Option<u32>,
+) -> Result<ModLayout> {
   let mod_file = mod_data.parse_elf()?;
+  let mut game_symbols = load_symbol_maps(mod_data, dol_hash)?;
+  game_symbols.extend(mod_data.config.symbols.clone());
 
-  let symbol_map = mod_file.symbols()
-    .filter_map(|sym| {
-      if let Ok(name) = sym.name() {
-        Some((name.to_string(), sym))
+  let placement = mod_data.config.placement;
+  let arena_patches = mod_data.config.arena_patches();
+  let relocatable = mod_file.kind() == ObjectKind::Relocatable;
+  match placement {
+    Placement::Bottom => {
+      let load_address = match (mod_data.config.load_address, next_bottom) {
+        (Some(load_address), _) => Some(load_address),
+        (None, _) if !relocatable => None,
+        (None, Some(next_bottom)) => Some(next_bottom),
+        (None, None) => {
+          // default to where the game's arena used to start
+          let arena_lo = read_original_arena(dol_header, dol_bytes, &arena_patches, ArenaBound::Lo, &game_symbols)?;
+          info!("Original arena lo: 0x{:08X}", arena_lo);
+          Some(arena_lo.div_ceil(32) * 32)
+        }
+      };
+      layout_mod(mod_data, &mod_file, dol_header, dol_bytes, dol_hash, &game_symbols, load_address)
+    }
+    Placement::Top => {
+      let ceiling = match (mod_data.config.memory_ceiling, next_top) {
+        (Some(ceiling), _) => ceiling,
+        (None, Some(next_top)) => next_top,
+        (None, None) => {
+          let arena_hi = read_original_arena(dol_header, dol_bytes, &arena_patches, ArenaBound::Hi, &game_symbols)?;
+          info!("Original arena hi: 0x{:08X}", arena_hi);
+          arena_hi
+        }
+      };
+      let layout = if relocatable {
+        // link once to find the size of the mod, then again right below the ceiling
+        let mut load_address = ceiling / 32 * 32;
+        let mut layout = layout_mod(mod_data, &mod_file, dol_header, dol_bytes, dol_hash, &game_symbols, Some(load_address))?;
+        while layout.end > ceiling {
+          load_address = load_address.checked_sub((layout.end - ceiling).div_ceil(32) * 32)
+            .ok_or_else(|| PatchError::AboveMemoryCeiling {
+              mod_name: mod_data.config.mod_name.clone(),
+              end: layout.end,
+              ceiling,
+            })?;
+          info!("Linking mod at 0x{:08X} to fit below 0x{:08X}", load_address, ceiling);
+          layout = layout_mod(mod_data, &mod_file, dol_header, dol_bytes, dol_hash, &game_symbols, Some(load_address))?;
+        }
+        layout
       } else {
-        None
+        layout_mod(mod_data, &mod_file, dol_header, dol_bytes, dol_hash, &game_symbols, None)?
+      };
+      if layout.end > ceiling {
+        return Err(PatchError::AboveMemoryCeiling {
+          mod_name: mod_data.config.mod_name.clone(),
+          end: layout.end,
+          ceiling,
+        }.into());
+      }
+      Ok(layout)
+    }
+  }
+}
+
+/// Put the writes of all mods in one list, tagged with the index of their mod.
+/// Writes of different mods may only overlap if they are identical.
+fn combine_writes(mods: &[&ModData], layouts: &[ModLayout]) -> Result<Vec<(usize, PlannedWrite)>> {
+  let mut combined: Vec<(usize, PlannedWrite)> = Vec::new();
+  for (i, layout) in layouts.iter().enumerate() {
+    let mut writes = Vec::new();
+    'writes: for write in &layout.writes {
+      for (other_index, other) in &combined {
+        if !write.overlaps(other) {
+          continue;
+        }
+        if write.address == other.address && write.data == other.data {
+          info!("{} and {} both apply the same {} at 0x{:08X}",
+                mods[*other_index].config.mod_name, mods[i].config.mod_name, write.description, write.address);
+          continue 'writes;
+        }
+        return Err(PatchError::WriteConflict {
+          mod_name: mods[i].config.mod_name.clone(),
+          description: write.description.to_string(),
+          address: write.address,
+          other_mod_name: mods[*other_index].config.mod_name.clone(),
+          other_description: other.description.to_string(),
+          other_address: other.address,
+        }.into());
       }
+      writes.push((i, write.clone()));
+    }
+    combined.extend(writes);
+  }
+  Ok(combined)
+}
+
+/// The mod linked at its final address, with the code the patcher generates for it
+struct ModLayout {
+  placement: Placement,
+  entry: u32,
+  segments: Vec<ModSegment>,
+  writes: Vec<PlannedWrite>,
+  arena_sites: Vec<(ArenaPatchConfig, u32)>,
+  /// Lowest address used by the mod
+  start: u32,
+  /// End of the mod, including generated code
+  end: u32,
+}
+
+/// Link the mod at `load_address` and plan its patches
+fn layout_mod(
+  mod_data: &ModData,
+  mod_file: &object::File,
+  dol_header: &DolHeader,
+  dol_bytes: &[u8],
+  dol_hash: &str,
+  game_symbols: &HashMap<String, u32>,
+  load_address: Option<u32>,
+) -> Result<ModLayout> {
+  let image = ModImage::from_elf(
+    mod_file,
+    load_address,
+    mod_data.config.mod_entry_symbol.as_deref(),
+    game_symbols,
+  )?;
+  let resolve_symbol = |name: &str| -> Result<u32> {
+    image.symbol(name)
+      .or_else(|| game_symbols.get(name).copied())
+      .ok_or_else(|| PatchError::MissingSymbol { name: name.to_string() }.into())
+  };
+
+  let entry_addr = image.entry;
+
+  // let link_start = resolve_symbol("_LINK_START")?;
+  let link_end = resolve_symbol("_LINK_END")?;
+  // let link_size = resolve_symbol("_LINK_SIZE")?;
+  let arena_sites = mod_data.config.arena_patches().into_iter()
+    .map(|site| {
+      let address = resolve_location(&site.symbol, site.address, resolve_symbol)?;
+      Ok((site, address))
     })
-    .collect::<std::collections::HashMap<_, _>>();
-
-  let entry_addr = mod_file.entry();
-
-  // let link_start = symbol_map.get("_LINK_START")
-  //   .ok_or_else(|| anyhow::anyhow!("Missing symbol _LINK_START"))?
-  //   .address();
-  let link_end = symbol_map.get("_LINK_END")
-    .ok_or_else(|| anyhow::anyhow!("Missing symbol _LINK_END"))?
-    .address();
-  // let link_size = symbol_map.get("_LINK_SIZE")
-  //   .ok_or_else(|| anyhow::anyhow!("Missing symbol _LINK_SIZE"))?
-  //   .address();
-  let patch_arena_lo_1 = symbol_map.get("_PATCH_ARENA_LO_1")
-    .ok_or_else(|| anyhow::anyhow!("Missing symbol _PATCH_ARENA_LO_1"))?
-    .address();
-  let patch_arena_lo_2 = symbol_map.get("_PATCH_ARENA_LO_2")
-    .ok_or_else(|| anyhow::anyhow!("Missing symbol _PATCH_ARENA_LO_2"))?
-    .address();
-  let entry_hook_addr = symbol_map.get(&mod_data.config.entry_point_symbol)
-    .ok_or_else(|| anyhow::anyhow!("Missing symbol {}", mod_data.config.entry_point_symbol))?
-    .address();
+    .collect::<Result<Vec<_>>>()?;
+  let entry_hook_addr = resolve_symbol(&mod_data.config.entry_point_symbol)?;
 
-  let mut output_bytes = dol_bytes.to_vec();
+  // code generated by the patcher goes after the mod, in its own segment
+  let mut generated = GeneratedCode {
+    address: link_end.div_ceil(32) * 32,
+    data: Vec::new(),
+  };
+  let mut writes = Vec::new();
+  let veneers = mod_data.config.long_branch_veneers;
 
-  for segment in mod_file.segments() {
-    // find the sections that are part of this segment
-    let segment_range = segment.address()..(segment.address() + segment.size());
-    info!("Segment:");
-    for section in mod_file.sections() {
-      if segment_range.contains(&section.address()) {
-        let section_name = section.name().unwrap_or("<unnamed>");
-        info!("  - {:} @ 0x{:08X} - 0x{:08X} ({:} bytes)",
-                      section_name,
-                      section.address(),
-                      section.address() + section.size(),
-                      section.size());
+  info!("Patching entry hook at 0x{:08X} to jump to 0x{:08X}", entry_hook_addr, entry_addr);
+  writes.push(PlannedWrite {
+    address: entry_hook_addr,
+    data: generated.branch(entry_hook_addr, entry_addr, false, veneers)?.to_be_bytes().to_vec(),
+    expect: None,
+    description: "entry hook",
+  });
+
+  for branch_patch in &mod_data.config.branch_patches {
+    let patch_from = resolve_symbol(&branch_patch.branch_from_symbol)?;
+    let patch_to = resolve_symbol(&branch_patch.to_symbol)?;
+    info!("Applying custom patch at 0x{:08X} to jump to 0x{:08X}", patch_from, patch_to);
+    writes.push(PlannedWrite {
+      address: patch_from,
+      data: generated.branch(patch_from, patch_to, branch_patch.link, veneers)?.to_be_bytes().to_vec(),
+      expect: None,
+      description: "branch patch",
+    });
+  }
+
+  for patch in &mod_data.config.patches {
+    let addr = resolve_location(&patch.symbol, patch.address, resolve_symbol)? + patch.offset;
+    let data = match &patch.data {
+      PatchData::U8 { value } => vec![*value],
+      PatchData::U16 { value } => value.to_be_bytes().to_vec(),
+      PatchData::U32 { value } => value.to_be_bytes().to_vec(),
+      PatchData::Bytes { bytes } => parse_hex_bytes(bytes)?,
+      PatchData::SymbolData { source } => image.symbol_data(source)
+        .ok_or_else(|| anyhow::anyhow!("Missing data for symbol {}", source))?
+        .to_vec(),
+      PatchData::Asm { asm } => assemble(&asm.to_source(), addr, |name| resolve_symbol(name).ok())?,
+      PatchData::Hook { function } => {
+        let function_addr = resolve_symbol(function)?;
+        let original = read_dol_addr_32(dol_header, dol_bytes, addr)?;
+        let trampoline_addr = generated.next_address();
+        let trampoline = build_trampoline(addr, trampoline_addr, original, function_addr)?;
+        generated.data.extend_from_slice(&trampoline);
+        info!("Placed hook trampoline for 0x{:08X} calling {} at 0x{:08X}", addr, function, trampoline_addr);
+        build_b_rel24(addr, trampoline_addr, false)?.to_be_bytes().to_vec()
+      }
+    };
+    let expect = match &patch.expect {
+      None => None,
+      Some(PatchExpect::Value(value)) => match data.len() {
+        1 => Some(vec![*value as u8]),
+        2 => Some((*value as u16).to_be_bytes().to_vec()),
+        4 => Some(value.to_be_bytes().to_vec()),
+        _ => return Err(anyhow::anyhow!("Patch at 0x{:08X} is {} bytes, expect must be a hex string", addr, data.len())),
+      },
+      Some(PatchExpect::Bytes(bytes)) => Some(parse_hex_bytes(bytes)?),
+    };
+    let description = match &patch.data {
+      PatchData::Asm { .. } => "asm patch",
+      PatchData::Hook { .. } => "hook",
+      _ => "data patch",
+    };
+    writes.push(PlannedWrite { address: addr, data, expect, description });
+  }
+
+  for patch in load_gecko_codes(mod_data, dol_hash)? {
+    match patch {
+      GeckoPatch::Write { address, data } => {
+        writes.push(PlannedWrite { address, data, expect: None, description: "Gecko write" });
+      }
+      GeckoPatch::InsertAsm { address, mut code } => {
+        let block_addr = generated.next_address();
+        let branch_back_addr = block_addr + code.len() as u32 - 4;
+        let branch_back = build_b_rel24(branch_back_addr, address + 4, false)?;
+        let len = code.len();
+        code[len - 4..].copy_from_slice(&branch_back.to_be_bytes());
+        generated.data.extend_from_slice(&code);
+        info!("Placed Gecko insert asm for 0x{:08X} at 0x{:08X}", address, block_addr);
+        writes.push(PlannedWrite {
+          address,
+          data: build_b_rel24(address, block_addr, false)?.to_be_bytes().to_vec(),
+          expect: None,
+          description: "Gecko insert asm",
+        });
       }
     }
-    let data = segment.data()?;
-    info!("  Data size: {} bytes", data.len());
-    if data.is_empty() {
-      info!("  Skipping empty segment");
-      continue;
-    }
+  }
+
+  let mut segments = image.segments.clone();
+  let mut mod_end = link_end;
+  if !generated.data.is_empty() {
+    info!("Generated code: 0x{:08X} - 0x{:08X}", generated.address, generated.next_address());
+    mod_end = generated.next_address();
+    segments.push(ModSegment {
+      address: generated.address,
+      size: generated.data.len() as u32,
+      data: generated.data,
+      executable: true,
+    });
+  }
 
-    let segment_output_offset = output_bytes.len();
-    output_bytes.extend_from_slice(&data);
-    info!("  Wrote segment data at output offset 0x{:08X}", segment_output_offset);
 
-    // find a target section in the .dol with an offset of 0
-    let mut found = false;
-    for dol_segment in dol_header.text.iter_mut().chain(dol_header.data.iter_mut()) {
-      if dol_segment.offset != 0 {
-        continue;
+  let start = segments.iter().map(|s| s.address).min().unwrap_or(mod_end);
+  Ok(ModLayout {
+    placement: mod_data.config.placement,
+    entry: entry_addr,
+    segments,
+    writes,
+    arena_sites,
+    start,
+    end: mod_end,
+  })
+}
+
+/// The value one of the game's arena patch sites sets the bound to
+fn read_original_arena(
+  dol_header: &DolHeader,
+  dol_bytes: &[u8],
+  arena_patches: &[ArenaPatchConfig],
+  bound: ArenaBound,
+  game_symbols: &HashMap<String, u32>,
+) -> Result<u32> {
+  let site = arena_patches.iter()
+    .find(|site| site.bound == bound)
+    .ok_or(PatchError::MissingArenaSite { bound })?;
+  let site = resolve_location(&site.symbol, site.address, |name| {
+    game_symbols.get(name).copied().ok_or_else(|| anyhow::anyhow!("Missing game symbol {}", name))
+  })
+    .map_err(|e| anyhow::anyhow!("Failed to find the original arena {:?}: {}", bound, e))?;
+  read_lis_addi(dol_header, dol_bytes, site)
+}
+
+/// Largest gap between two pieces of memory that still counts as contiguous,
+/// enough for the padding between 32-byte aligned sections
+const CONTIGUOUS_GAP: u32 = 32;
+
+/// Zero-filled memory at the end of a segment is stored as zeros, or added to the DOL's BSS range
+/// when `extend_bss` is set and it follows the BSS
+fn fill_segment_bss(dol_header: &mut DolHeader, segment: &ModSegment, extend_bss: bool) -> ModSegment {
+  info!("Segment 0x{:08X} - 0x{:08X} ({})", segment.address, segment.address + segment.size, segment_kind(segment));
+  info!("  Data size: {} bytes", segment.data.len());
+  let mut segment = segment.clone();
+  let bss_start = segment.address + segment.data.len() as u32;
+  let bss_end = segment.address + segment.size;
+  if bss_start < bss_end {
+    if extend_bss && is_contiguous(dol_header.bss_addr + dol_header.bss_size, bss_start) {
+      dol_header.bss_size = bss_end - dol_header.bss_addr;
+      segment.size = segment.data.len() as u32;
+      info!("  Extended DOL BSS to 0x{:08X} - 0x{:08X}", dol_header.bss_addr, bss_end);
+    } else {
+      segment.data.resize(segment.size as usize, 0);
+      if extend_bss {
+        warn!(
+          "  extend_dol_bss is set, but 0x{:08X} does not follow the DOL BSS ending at 0x{:08X}. Zero-padded {} bytes of BSS instead.",
+          bss_start,
+          dol_header.bss_addr + dol_header.bss_size,
+          bss_end - bss_start
+        );
+      } else {
+        info!("  Zero-padded {} bytes of BSS", bss_end - bss_start);
       }
-      found = true;
-      dol_segment.offset = segment_output_offset as u32;
-      dol_segment.loading = segment.address() as u32;
-      dol_segment.size = segment.size() as u32;
+    }
+  }
+  segment
+}
+
+/// Copy the mod segments into the DOL, executable segments into text sections and the rest into
+/// data sections. When a kind runs out of free sections, contiguous segments are merged, and
+/// segments that still don't fit extend the DOL section that ends right before them.
+fn place_segments(dol_header: &mut DolHeader, output_bytes: &mut Vec<u8>, segments: Vec<ModSegment>) -> Result<()> {
+  let placed_segments: Vec<ModSegment> = segments.into_iter()
+    .filter(|segment| {
+      if segment.data.is_empty() {
+        info!("  Skipping empty segment 0x{:08X}", segment.address);
+      }
+      !segment.data.is_empty()
+    })
+    .collect();
+
+  let free_text = dol_header.text.iter().filter(|s| s.offset == 0).count();
+  let free_data = dol_header.data.iter().filter(|s| s.offset == 0).count();
+  let placed_segments = merge_contiguous_segments(placed_segments, free_text, free_data);
+
+  for segment in &placed_segments {
+    let sections = if segment.executable { &mut dol_header.text } else { &mut dol_header.data };
+    info!("Placing segment 0x{:08X} - 0x{:08X} in a {} section", segment.address, segment.address + segment.size, segment_kind(segment));
+    if let Some(dol_segment) = sections.iter_mut().find(|s| s.offset == 0) {
+      let segment_output_offset = align_output(output_bytes);
+      output_bytes.extend_from_slice(&segment.data);
+      dol_segment.offset = segment_output_offset;
+      dol_segment.loading = segment.address;
+      dol_segment.size = segment.size;
       info!("  Patching DOL segment offset 0x{:08X} loading 0x{:08X} size 0x{:08X} end 0x{:08X}",
             dol_segment.offset,
             dol_segment.loading,
             dol_segment.size,
             dol_segment.loading + dol_segment.size);
-      break;
+      continue;
     }
-    if !found {
-      return Err(anyhow::anyhow!("No available DOL segment found for mod segment"));
+
+    let dol_segment = sections.iter_mut()
+      .find(|s| s.size > 0 && is_contiguous(s.loading + s.size, segment.address))
+      .ok_or_else(|| PatchError::NoSectionForSegment {
+        kind: segment_kind(segment),
+        start: segment.address,
+        end: segment.address + segment.size,
+      })?;
+    extend_dol_segment(dol_segment, output_bytes, segment);
+  }
+  Ok(())
+}
+
+/// Make sure the zero-filled parts of the mod don't clear memory the game loads or uses for its BSS
+fn check_mod_bss(dol_header: &DolHeader, segments: &[ModSegment]) -> Result<()> {
+  let mut game_ranges: Vec<(String, u32, u32)> = Vec::new();
+  for (kind, sections) in [("text", &dol_header.text), ("data", &dol_header.data)] {
+    for (i, section) in sections.iter().enumerate() {
+      if section.size > 0 {
+        game_ranges.push((format!("{} section {}", kind, i), section.loading, section.loading + section.size));
+      }
     }
   }
+  game_ranges.push(("BSS".to_string(), dol_header.bss_addr, dol_header.bss_addr + dol_header.bss_size));
 
-  info!("Updating DOL header");
-  dol_header.write_to_stream(&mut io::Cursor::new(&mut output_bytes[..]))?;
+  for segment in segments {
+    let bss_start = segment.address + segment.data.len() as u32;
+    let bss_end = segment.address + segment.size;
+    if bss_start == bss_end {
+      continue;
+    }
+    for (name, start, end) in &game_ranges {
+      if bss_start < *end && *start < bss_end {
+        return Err(PatchError::ModBssOverlap {
+          start: bss_start,
+          end: bss_end,
+          region: name.clone(),
+          region_start: *start,
+          region_end: *end,
+        }.into());
+      }
+    }
+  }
+  Ok(())
+}
 
-  info!("Reloading DOL for testing patches...");
-  let new_dol_header = DolHeader::read_from_stream(&mut io::Cursor::new(&output_bytes[..]))?;
-  info!("New DOL Header: {:?}", new_dol_header);
+fn segment_kind(segment: &ModSegment) -> &'static str {
+  if segment.executable { "text" } else { "data" }
+}
 
-  let mut arenalo_upper = ((link_end >> 16) & 0xFFFF) as u16;
-  let arenalo_lower = (link_end & 0xFFFF) as u16;
+/// Whether memory starting at `next_address` follows memory ending at `end`
+fn is_contiguous(end: u32, next_address: u32) -> bool {
+  end <= next_address && next_address - end < CONTIGUOUS_GAP
+}
 
-  // adjust for sign extension
-  if arenalo_lower & 0x8000 != 0 {
-    arenalo_upper = arenalo_upper.wrapping_add(1);
-  }
-
-  info!("Patching areana lo to 0x{:08X}",  link_end);
-  patch_dol_addr_32(&dol_header, &mut output_bytes, patch_arena_lo_1 as u32, |_| {
-    build_lis(3, arenalo_upper)
-  })?;
-  patch_dol_addr_32(&dol_header, &mut output_bytes, patch_arena_lo_1 as u32 + 4, |_| {
-    build_addi(3, 3, arenalo_lower)
-  })?;
-  patch_dol_addr_32(&dol_header, &mut output_bytes, patch_arena_lo_2 as u32, |_| {
-    build_lis(3, arenalo_upper)
-  })?;
-  patch_dol_addr_32(&dol_header, &mut output_bytes, patch_arena_lo_2 as u32 + 4, |_| {
-    build_addi(3, 3, arenalo_lower)
-  })?;
-  info!("Patching entry hook at 0x{:08X} to jump to 0x{:08X}", entry_hook_addr, entry_addr);
-  patch_dol_addr_32(&dol_header, &mut output_bytes, entry_hook_addr as u32, |_| {
-    build_b_rel24(entry_hook_addr as u32, entry_addr as u32, false)
-  })?;
+/// Merge segments that are next to each other in memory until they fit in the free text and data
+/// sections. A merged segment that contains code goes in a text section.
+fn merge_contiguous_segments(mut segments: Vec<ModSegment>, free_text: usize, free_data: usize) -> Vec<ModSegment> {
+  segments.sort_by_key(|s| s.address);
+  loop {
+    let text_count = segments.iter().filter(|s| s.executable).count();
+    let too_much_text = text_count > free_text;
+    let too_much_data = segments.len() - text_count > free_data;
+    if !too_much_text && !too_much_data {
+      break;
+    }
+    let mergeable = (1..segments.len()).find(|&i| {
+      let (first, second) = (&segments[i - 1], &segments[i]);
+      is_contiguous(first.address + first.size, second.address)
+        && ((too_much_text && (first.executable || second.executable))
+          || (too_much_data && (!first.executable || !second.executable)))
+    });
+    let Some(i) = mergeable else {
+      break;
+    };
+    let next = segments.remove(i);
+    let segment = &mut segments[i - 1];
+    info!("  Merging segment 0x{:08X} into 0x{:08X}", next.address, segment.address);
+    // zero-filled memory at the end of the first segment becomes part of the data
+    segment.data.resize((next.address - segment.address) as usize, 0);
+    segment.data.extend_from_slice(&next.data);
+    segment.size = next.address + next.size - segment.address;
+    segment.executable |= next.executable;
+  }
+  segments
+}
 
-  for branch_patch in &mod_data.config.branch_patches {
-    let patch_from = symbol_map.get(&branch_patch.branch_from_symbol)
-      .ok_or_else(|| anyhow::anyhow!("Missing symbol {}", &branch_patch.branch_from_symbol))?
-      .address();
-    let patch_to = symbol_map.get(&branch_patch.to_symbol)
-      .ok_or_else(|| anyhow::anyhow!("Missing symbol {}", &branch_patch.to_symbol))?
-      .address();
-    info!("Applying custom patch at 0x{:08X} to jump to 0x{:08X}", patch_from, patch_to);
-    patch_dol_addr_32(&dol_header, &mut output_bytes, patch_from as u32, |_| {
-      build_b_rel24(patch_from as u32, patch_to as u32, branch_patch.link)
-    })?;
+/// Pad the DOL so the next section starts on a 32-byte boundary, returns the new length
+fn align_output(output_bytes: &mut Vec<u8>) -> u32 {
+  output_bytes.resize(output_bytes.len().div_ceil(32) * 32, 0);
+  output_bytes.len() as u32
+}
+
+/// Grow an existing DOL section to also cover `segment`, which starts right after it.
+/// The section's data is moved to the end of the file, unless it is already there.
+fn extend_dol_segment(dol_segment: &mut SectionInfo, output_bytes: &mut Vec<u8>, segment: &ModSegment) {
+  let start = dol_segment.offset as usize;
+  let end = (start + dol_segment.size as usize).min(output_bytes.len());
+  if end != output_bytes.len() {
+    let existing = output_bytes[start..end].to_vec();
+    dol_segment.offset = align_output(output_bytes);
+    output_bytes.extend_from_slice(&existing);
   }
+  let padded_size = (segment.address - dol_segment.loading) as usize;
+  output_bytes.resize(dol_segment.offset as usize + padded_size, 0);
+  output_bytes.extend_from_slice(&segment.data);
+  dol_segment.size = segment.address + segment.size - dol_segment.loading;
+  info!("  Extended DOL segment offset 0x{:08X} loading 0x{:08X} size 0x{:08X} end 0x{:08X}",
+        dol_segment.offset,
+        dol_segment.loading,
+        dol_segment.size,
+        dol_segment.loading + dol_segment.size);
+}
 
-  Ok(output_bytes)
+#[derive(Debug, Clone)]
+struct PlannedWrite {
+  address: u32,
+  data: Vec<u8>,
+  /// Data that must be at the address before writing
+  expect: Option<Vec<u8>>,
+  description: &'static str,
 }
 
-fn build_lis(register: i32, immediate: u16) -> u32 {
-  let op = 0x3C00_0000; // lis opcode
-  op | ((register as u32) << 21) | ((immediate as u32) & 0xFFFF)
+impl PlannedWrite {
+  fn overlaps(&self, other: &PlannedWrite) -> bool {
+    self.address < other.address + other.data.len() as u32 && other.address < self.address + self.data.len() as u32
+  }
 }
 
-fn build_addi(register_dst: i32, register_src: i32, immediate: u16) -> u32 {
-  let op = 0x3800_0000; // addi opcode
-  op | ((register_src as u32) << 21) | ((register_dst as u32) << 16) | ((immediate as u32) & 0xFFFF)
+struct GeneratedCode {
+  address: u32,
+  data: Vec<u8>,
+}
+
+impl GeneratedCode {
+  fn next_address(&self) -> u32 {
+    self.address + self.data.len() as u32
+  }
+
+  /// Build a branch from `from` to `to`, going through a veneer when it is out of range
+  /// and `veneers` is set
+  fn branch(&mut self, from: u32, to: u32, link: bool, veneers: bool) -> Result<u32> {
+    if branch_in_range(from, to) {
+      return build_b_rel24(from, to, link);
+    }
+    if !veneers {
+      return Err(PatchError::BranchOutOfRange { from, to }.into());
+    }
+    let veneer_addr = self.next_address();
+    self.data.extend_from_slice(&build_veneer(veneer_addr, to)?);
+    info!("Placed veneer to 0x{:08X} at 0x{:08X}", to, veneer_addr);
+    build_b_rel24(from, veneer_addr, link)
+  }
 }
 
 fn patch_dol_addr_32<F>(
@@ -243,8 +822,399 @@ where
   }
 }
 
-fn build_b_rel24(addr: u32, target: u32, link: bool) -> u32 {
-  let rel = (target.wrapping_sub(addr)) & 0xFFFF_FFFC;
-  let op = if link { 0x4800_0001 } else { 0x4800_0000 };
-  op | rel
-}
\ No newline at end of file
+/// Write a `lis`/`addi` pair that loads `value` into `register`
+fn patch_lis_addi(dol_header: &DolHeader, dol_bytes: &mut Vec<u8>, addr: u32, register: u32, value: u32) -> Result<()> {
+  let mut upper = ((value >> 16) & 0xFFFF) as u16;
+  let lower = (value & 0xFFFF) as u16;
+
+  // adjust for sign extension
+  if lower & 0x8000 != 0 {
+    upper = upper.wrapping_add(1);
+  }
+
+  patch_dol_addr_32(dol_header, dol_bytes, addr, |_| build_lis(register, upper))?;
+  patch_dol_addr_32(dol_header, dol_bytes, addr + 4, |_| build_addi(register, register, lower))
+}
+
+/// An address given as exactly one of a symbol or an address
+fn resolve_location<F>(symbol: &Option<String>, address: Option<u32>, resolve_symbol: F) -> Result<u32>
+where
+  F: Fn(&str) -> Result<u32>,
+{
+  match (symbol, address) {
+    (Some(symbol), None) => resolve_symbol(symbol),
+    (None, Some(address)) => Ok(address),
+    _ => Err(anyhow::anyhow!("Patches need exactly one of symbol or address")),
+  }
+}
+
+/// Read the value loaded by a `lis rX, hi` / `addi rX, rX, lo` pair
+fn read_lis_addi(dol_header: &DolHeader, dol_bytes: &[u8], addr: u32) -> Result<u32> {
+  let lis = read_dol_addr_32(dol_header, dol_bytes, addr)?;
+  let addi = read_dol_addr_32(dol_header, dol_bytes, addr + 4)?;
+  let register = (lis >> 21) & 0x1F;
+  let is_lis = lis & 0xFC1F_0000 == 0x3C00_0000;
+  let is_addi = addi & 0xFC00_0000 == 0x3800_0000
+    && (addi >> 21) & 0x1F == register
+    && (addi >> 16) & 0x1F == register;
+  if !is_lis || !is_addi {
+    return Err(anyhow::anyhow!("Expected lis/addi pair at 0x{:08X}, found 0x{:08X} 0x{:08X}", addr, lis, addi));
+  }
+  let upper = (lis & 0xFFFF) << 16;
+  let lower = (addi & 0xFFFF) as u16 as i16 as i32;
+  Ok(upper.wrapping_add(lower as u32))
+}
+
+fn dol_addr_range_to_offsets(dol_header: &DolHeader, addr: u32, len: usize) -> Result<Vec<usize>> {
+  let end = addr as u64 + len as u64;
+  let mut offsets = Vec::new();
+  for dol_segment in dol_header.text.iter().chain(dol_header.data.iter()) {
+    let segment_end = dol_segment.loading as u64 + dol_segment.size as u64;
+    if dol_segment.loading <= addr && end <= segment_end {
+      offsets.push((dol_segment.offset + (addr - dol_segment.loading)) as usize);
+    } else if (addr as u64) < segment_end && (dol_segment.loading as u64) < end {
+      return Err(anyhow::anyhow!("Range 0x{:08X} - 0x{:08X} crosses a DOL segment boundary", addr, end));
+    }
+  }
+  if offsets.is_empty() {
+    return Err(anyhow::anyhow!("Address 0x{:08X} not found in DOL segments", addr));
+  }
+  Ok(offsets)
+}
+
+fn read_dol_addr_bytes(dol_header: &DolHeader, dol_bytes: &[u8], addr: u32, len: usize) -> Result<Vec<u8>> {
+  let offset = dol_addr_range_to_offsets(dol_header, addr, len)?[0];
+  Ok(dol_bytes[offset..offset + len].to_vec())
+}
+
+fn patch_dol_addr_bytes(dol_header: &DolHeader, dol_bytes: &mut [u8], addr: u32, data: &[u8]) -> Result<()> {
+  // this may happen in multiple segments
+  for offset in dol_addr_range_to_offsets(dol_header, addr, data.len())? {
+    dol_bytes[offset..offset + data.len()].copy_from_slice(data);
+  }
+  Ok(())
+}
+
+fn parse_hex_bytes(hex: &str) -> Result<Vec<u8>> {
+  let digits: String = hex.trim_start_matches("0x").chars().filter(|c| !c.is_whitespace()).collect();
+  if !digits.len().is_multiple_of(2) {
+    return Err(anyhow::anyhow!("Hex string has an odd number of digits: {}", hex));
+  }
+  (0..digits.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16)
+      .map_err(|e| anyhow::anyhow!("Invalid hex string {}: {}", hex, e)))
+    .collect()
+}
+
+pub fn format_hex_bytes(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn read_dol_addr_32(dol_header: &DolHeader, dol_bytes: &[u8], addr: u32) -> Result<u32> {
+  for dol_segment in dol_header.text.iter().chain(dol_header.data.iter()) {
+    if dol_segment.loading <= addr && addr < (dol_segment.loading + dol_segment.size) {
+      let offset = dol_segment.offset + (addr - dol_segment.loading);
+      let mut cursor = io::Cursor::new(dol_bytes);
+      cursor.set_position(offset as u64);
+      return Ok(cursor.read_u32()?);
+    }
+  }
+  Err(anyhow::anyhow!("Address 0x{:08X} not found in DOL segments", addr))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  /// A DOL header with the given `(offset, loading, size)` sections, the rest are free
+  fn dol_header(text: &[(u32, u32, u32)], data: &[(u32, u32, u32)]) -> DolHeader {
+    let sections = |used: &[(u32, u32, u32)], count: usize| (0..count)
+      .map(|i| match used.get(i) {
+        Some(&(offset, loading, size)) => SectionInfo { offset, loading, size },
+        None => SectionInfo { offset: 0, loading: 0, size: 0 },
+      })
+      .collect();
+    DolHeader {
+      text: sections(text, 7),
+      data: sections(data, 11),
+      bss_addr: 0x80300000,
+      bss_size: 0x1000,
+      entry_point: 0x80003100,
+    }
+  }
+
+  fn segment(address: u32, size: u32, data: Vec<u8>, executable: bool) -> ModSegment {
+    ModSegment { address, size, data, executable }
+  }
+
+  /// Mod data with the required config fields filled in, followed by `config`
+  pub(crate) fn mod_data(mod_name: &str, elf_bytes: Vec<u8>, config: &str) -> ModData {
+    let config = format!(
+      "game_name = \"Test Game\"\nmod_name = \"{}\"\nversion = \"1.0\"\n\
+       output_name_iso = \"out.iso\"\noutput_name_dol = \"out.dol\"\nentry_point_symbol = \"game_entry\"\n{}",
+      mod_name,
+      config
+    );
+    ModData {
+      elf_bytes,
+      config: toml::from_str(&config).unwrap(),
+      overwrite_output: false,
+      output_path_override: None,
+      symbol_map_files: Vec::new(),
+      gecko_code_files: Vec::new(),
+      ignore_memory_map: false,
+      additional_mods: Vec::new(),
+      repatch: false,
+      dry_run: false,
+      rebuild: false,
+    }
+  }
+
+  /// A DOL with one text section at `address`: a `nop` to hook, and the game's arena hi
+  /// `lis r3, 0x8170` / `addi r3, r3, 0` 0x10 bytes after it
+  fn test_dol(address: u32) -> Vec<u8> {
+    let header = dol_header(&[(0x100, address, 0x100)], &[]);
+    let mut dol = vec![0; 0x200];
+    header.write_to_stream(&mut io::Cursor::new(&mut dol[..])).unwrap();
+    dol[0x100..0x104].copy_from_slice(&0x6000_0000u32.to_be_bytes());
+    dol[0x110..0x114].copy_from_slice(&build_lis(3, 0x8170).to_be_bytes());
+    dol[0x114..0x118].copy_from_slice(&build_addi(3, 3, 0).to_be_bytes());
+    dol
+  }
+
+  /// A relocatable mod with `mod_entry` in 8 bytes of text, and 4 bytes of data
+  fn test_mod_elf() -> Vec<u8> {
+    use crate::mod_image::tests::{build_elf, symbol, words};
+    use object::elf;
+    build_elf(&[
+      (".text", elf::SHT_PROGBITS, elf::SHF_ALLOC | elf::SHF_EXECINSTR, 0, 0, 0, words(&[0x3860_0001, 0x4E80_0020])),
+      (".data", elf::SHT_PROGBITS, elf::SHF_ALLOC | elf::SHF_WRITE, 0, 0, 0, words(&[0x1234_5678])),
+      (".symtab", elf::SHT_SYMTAB, 0, 4, 1, 16, [
+        symbol(0, 0, 0, 0, 0),
+        symbol(1, 0, 8, (elf::STB_GLOBAL << 4) | elf::STT_FUNC, 1),
+      ].concat()),
+      (".strtab", elf::SHT_STRTAB, 0, 0, 0, 0, b"\0mod_entry\0".to_vec()),
+    ])
+  }
+
+  /// Top placement of [`test_mod_elf`] into [`test_dol`] at `address`
+  fn top_placement_config(address: u32) -> String {
+    format!(
+      "placement = \"top\"\nmod_entry_symbol = \"mod_entry\"\n\
+       symbols = {{ game_entry = {}, arena_hi_site = {} }}\n\
+       arena_patches = [{{ symbol = \"arena_hi_site\", bound = \"hi\" }}]\n",
+      address,
+      address + 0x10
+    )
+  }
+
+  fn layout_with_writes(writes: &[(u32, &[u8])]) -> ModLayout {
+    ModLayout {
+      placement: Placement::Bottom,
+      entry: 0,
+      segments: Vec::new(),
+      writes: writes.iter()
+        .map(|(address, data)| PlannedWrite { address: *address, data: data.to_vec(), expect: None, description: "data patch" })
+        .collect(),
+      arena_sites: Vec::new(),
+      start: 0,
+      end: 0,
+    }
+  }
+
+  #[test]
+  fn merges_contiguous_segments_when_sections_run_out() {
+    let segments = vec![
+      segment(0x80400200, 0x20, vec![3; 0x20], false),
+      // ends with zero-filled memory
+      segment(0x80400000, 0x100, vec![1; 0x80], true),
+      segment(0x80400100, 0x100, vec![2; 0x100], false),
+    ];
+    let merged = merge_contiguous_segments(segments, 1, 1);
+    assert_eq!(merged.len(), 2);
+    assert_eq!((merged[0].address, merged[0].size, merged[0].executable), (0x80400000, 0x200, true));
+    assert_eq!(merged[0].data[..0x80], [1; 0x80]);
+    assert_eq!(merged[0].data[0x80..0x100], [0; 0x80]);
+    assert_eq!(merged[0].data[0x100..], [2; 0x100]);
+    assert_eq!((merged[1].address, merged[1].size, merged[1].executable), (0x80400200, 0x20, false));
+  }
+
+  #[test]
+  fn leaves_segments_alone_when_they_fit_or_are_apart() {
+    let segments = vec![
+      segment(0x80400000, 0x100, vec![1; 0x100], true),
+      segment(0x80400100, 0x100, vec![2; 0x100], false),
+    ];
+    assert_eq!(merge_contiguous_segments(segments.clone(), 1, 1).len(), 2);
+
+    let apart = vec![
+      segment(0x80400000, 0x100, vec![1; 0x100], true),
+      segment(0x80500000, 0x100, vec![2; 0x100], true),
+    ];
+    assert_eq!(merge_contiguous_segments(apart, 1, 1).len(), 2);
+  }
+
+  #[test]
+  fn extends_the_section_before_a_segment() {
+    let full_text: Vec<_> = (0..7).map(|i| (0x100 + i * 0x100, 0x80003100 + i * 0x100, 0x100)).collect();
+    let mut header = dol_header(&full_text, &[]);
+    header.text[0] = SectionInfo { offset: 0x100, loading: 0x803FFF00, size: 0x100 };
+    let mut output = vec![0xAA; 0x800];
+    output[0x100..0x200].fill(0x55);
+    place_segments(&mut header, &mut output, vec![segment(0x80400000, 0x40, vec![0x77; 0x40], true)]).unwrap();
+
+    // the section's data moves to the end of the DOL, followed by the segment
+    assert_eq!((header.text[0].offset, header.text[0].loading, header.text[0].size), (0x800, 0x803FFF00, 0x140));
+    assert_eq!(output.len(), 0x940);
+    assert_eq!(output[0x800..0x900], [0x55; 0x100]);
+    assert_eq!(output[0x900..], [0x77; 0x40]);
+    // the rest of the DOL is untouched
+    assert!(output[..0x800].iter().enumerate().all(|(i, b)| *b == if (0x100..0x200).contains(&i) { 0x55 } else { 0xAA }));
+  }
+
+  #[test]
+  fn extends_the_section_at_the_end_in_place() {
+    let mut header = dol_header(&[], &[(0x100, 0x80300000, 0x100)]);
+    header.data.iter_mut().skip(1).for_each(|s| *s = SectionInfo { offset: 0x200, loading: 0x80200000, size: 0x20 });
+    let mut output = vec![0x55; 0x200];
+    place_segments(&mut header, &mut output, vec![segment(0x80300110, 0x10, vec![0x77; 0x10], false)]).unwrap();
+    assert_eq!((header.data[0].offset, header.data[0].size), (0x100, 0x120));
+    assert_eq!(output.len(), 0x220);
+    assert_eq!(output[0x200..0x210], [0; 0x10]);
+    assert_eq!(output[0x210..], [0x77; 0x10]);
+  }
+
+  #[test]
+  fn segments_without_a_section_are_errors() {
+    let full_text: Vec<_> = (0..7).map(|i| (0x100 + i * 0x100, 0x80003100 + i * 0x100, 0x100)).collect();
+    let mut header = dol_header(&full_text, &[]);
+    let mut output = vec![0; 0x800];
+    let error = place_segments(&mut header, &mut output, vec![segment(0x80400000, 0x40, vec![1; 0x40], true)])
+      .unwrap_err();
+    assert!(matches!(
+      error.downcast_ref::<PatchError>(),
+      Some(PatchError::NoSectionForSegment { kind: "text", start: 0x80400000, end: 0x80400040 })
+    ));
+  }
+
+  #[test]
+  fn overlapping_writes_of_two_mods_conflict() {
+    let (first, second) = (mod_data("First", Vec::new(), ""), mod_data("Second", Vec::new(), ""));
+    let layouts = [
+      layout_with_writes(&[(0x80003100, &[1, 2, 3, 4])]),
+      layout_with_writes(&[(0x80003102, &[5, 6])]),
+    ];
+    let error = combine_writes(&[&first, &second], &layouts).unwrap_err();
+    let Some(PatchError::WriteConflict { mod_name, address, other_mod_name, other_address, .. }) =
+      error.downcast_ref::<PatchError>() else {
+      panic!("unexpected error: {}", error);
+    };
+    assert_eq!((mod_name.as_str(), *address), ("Second", 0x80003102));
+    assert_eq!((other_mod_name.as_str(), *other_address), ("First", 0x80003100));
+
+    // the same data at a different address still conflicts
+    let layouts = [
+      layout_with_writes(&[(0x80003100, &[1, 2, 3, 4])]),
+      layout_with_writes(&[(0x80003102, &[3, 4])]),
+    ];
+    assert!(combine_writes(&[&first, &second], &layouts).is_err());
+  }
+
+  #[test]
+  fn identical_writes_are_applied_once() {
+    let (first, second) = (mod_data("First", Vec::new(), ""), mod_data("Second", Vec::new(), ""));
+    let layouts = [
+      layout_with_writes(&[(0x80003100, &[1, 2, 3, 4])]),
+      layout_with_writes(&[(0x80003100, &[1, 2, 3, 4]), (0x80003200, &[7])]),
+    ];
+    let combined = combine_writes(&[&first, &second], &layouts).unwrap();
+    let combined: Vec<_> = combined.iter().map(|(i, write)| (*i, write.address)).collect();
+    assert_eq!(combined, [(0, 0x80003100), (1, 0x80003200)]);
+  }
+
+  #[test]
+  fn disjoint_writes_are_combined() {
+    let (first, second) = (mod_data("First", Vec::new(), ""), mod_data("Second", Vec::new(), ""));
+    let layouts = [
+      layout_with_writes(&[(0x80003100, &[1, 2, 3, 4]), (0x80003108, &[1])]),
+      layout_with_writes(&[(0x80003104, &[5, 6, 7, 8])]),
+    ];
+    let combined = combine_writes(&[&first, &second], &layouts).unwrap();
+    let combined: Vec<_> = combined.iter().map(|(i, write)| (*i, write.address)).collect();
+    assert_eq!(combined, [(0, 0x80003100), (0, 0x80003108), (1, 0x80003104)]);
+  }
+
+  #[test]
+  fn zero_filled_memory_extends_the_bss_only_when_enabled() {
+    let mut header = dol_header(&[], &[]);
+    // follows the game's BSS, 0x80300000 - 0x80301000
+    let bss_segment = segment(0x80301000, 0x100, Vec::new(), false);
+    let filled = fill_segment_bss(&mut header, &bss_segment, false);
+    assert_eq!((filled.size, filled.data), (0x100, vec![0; 0x100]));
+    assert_eq!(header.bss_size, 0x1000);
+
+    let filled = fill_segment_bss(&mut header, &bss_segment, true);
+    assert_eq!((filled.size, filled.data.len()), (0, 0));
+    assert_eq!(header.bss_size, 0x1100);
+  }
+
+  #[test]
+  fn top_placement_links_below_the_ceiling_and_lowers_arena_hi() {
+    let dol = test_dol(0x80003100);
+    let mod_data = mod_data("Top", test_mod_elf(), &top_placement_config(0x80003100));
+    let (output, manifest) = patch_dol(&mod_data, &dol).unwrap();
+    let applied = &manifest.mods[0];
+    assert_eq!(applied.placement, Placement::Top);
+    assert!(applied.end <= 0x81700000 && applied.start >= 0x81700000 - 0x40, "{:08X} - {:08X}", applied.start, applied.end);
+    assert_eq!(applied.entry, applied.start);
+    assert_eq!(manifest.arena_hi, applied.start);
+
+    let header = DolHeader::read_from_stream(&mut io::Cursor::new(&output[..])).unwrap();
+    assert_eq!(read_lis_addi(&header, &output, 0x80003110).unwrap(), applied.start);
+    assert_eq!(read_dol_addr_32(&header, &output, 0x80003100).unwrap(), build_b_rel24(0x80003100, applied.entry, false).unwrap());
+    assert_eq!(read_dol_addr_32(&header, &output, applied.entry).unwrap(), 0x3860_0001);
+  }
+
+  #[test]
+  fn mods_larger_than_the_memory_ceiling_are_errors() {
+    // low enough for the entry hook to reach the mod
+    let dol = test_dol(0x1000);
+    let config = format!("{}memory_ceiling = 0x20\n", top_placement_config(0x1000));
+    let mod_data = mod_data("Top", test_mod_elf(), &config);
+    let error = patch_dol(&mod_data, &dol).unwrap_err();
+    assert!(matches!(
+      error.downcast_ref::<PatchError>(),
+      Some(PatchError::AboveMemoryCeiling { ceiling: 0x20, .. })
+    ), "unexpected error: {}", error);
+  }
+
+  #[test]
+  fn far_branches_go_through_veneers() {
+    let mut generated = GeneratedCode { address: 0x81700000, data: Vec::new() };
+    assert_eq!(generated.branch(0x80003100, 0x80003200, true, false).unwrap(), 0x4800_0101);
+    assert!(generated.data.is_empty());
+
+    let error = generated.branch(0x80003100, 0x90000000, false, false).unwrap_err();
+    assert!(matches!(
+      error.downcast_ref::<PatchError>(),
+      Some(PatchError::BranchOutOfRange { from: 0x80003100, to: 0x90000000 })
+    ));
+    assert!(generated.data.is_empty());
+
+    let branch = generated.branch(0x80003100, 0x90000000, true, true).unwrap();
+    assert_eq!(branch, build_b_rel24(0x80003100, 0x81700000, true).unwrap());
+    assert_eq!(generated.data, build_veneer(0x81700000, 0x90000000).unwrap());
+  }
+
+  #[test]
+  fn mod_bss_may_not_clear_the_game() {
+    let header = dol_header(&[(0x100, 0x80003100, 0x100)], &[]);
+    check_mod_bss(&header, &[segment(0x80301000, 0x100, vec![1; 0x20], false)]).unwrap();
+    let error = check_mod_bss(&header, &[segment(0x802FFF00, 0x200, vec![1; 0x20], false)]).unwrap_err();
+    assert!(matches!(
+      error.downcast_ref::<PatchError>(),
+      Some(PatchError::ModBssOverlap { start: 0x802FFF20, end: 0x80300100, region, .. }) if region == "BSS"
+    ));
+  }
+}
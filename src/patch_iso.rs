@@ -1,31 +1,72 @@
+use crate::PatchOutput;
+use crate::error::{HashedFile, PatchError};
 use crate::binstream::{BinStreamReadable, BinStreamWritable, BinStreamWrite};
 use crate::dol::DolHeader;
-use crate::gcdisc::{FSTEntry, GCDiscHeader, FST};
-use crate::patch_config::ModData;
+use crate::gcdisc::{apploader_end, FSTEntry, GCDiscHeader, DISC_HEADER_SIZE, FST};
+use crate::patch_config::{FileAction, ModData};
+use crate::manifest::MANIFEST_FILE_NAME;
+use crate::embedded::{read_embedded, EmbeddedData, PatchSignature, TAG_MANIFEST, TAG_REVERT_DOL, TAG_REVERT_ISO, TAG_SIGNATURE};
 use crate::patch_dol::patch_dol;
+use crate::plan::{plan_dol_changes, PatchPlan, PlannedChange};
 use crate::progress::Progress;
+use crate::revert::{already_patched_error, describe_patched, find_patched_dol, revert_iso_file, RevertChunk, RevertRecord};
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
 use md5::Digest;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{Cursor, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Alignment of the manifest file in the ISO
+const MANIFEST_ALIGN: u32 = 32;
+/// Alignment of files the mod adds or replaces
+const FILE_ALIGN: u32 = 32;
+/// Size of a GameCube disc, larger ISOs only work in emulators
+const GCN_DISC_SIZE: u32 = 1_459_978_240;
+/// Alignment of the DOL and the FST in rebuilt and built ISOs
+const SYSTEM_ALIGN: u32 = 0x100;
+/// Alignment of files in rebuilt and built ISOs. Streamed audio needs 32 KiB alignment.
+const REBUILD_FILE_ALIGN: u32 = 0x8000;
 
 pub fn patch_iso_file<F>(
   progress_update: F,
   in_path: &PathBuf,
   out_path: &PathBuf,
   mod_data: &ModData,
-) -> Result<()> where
+) -> Result<PatchOutput> where
   F: Fn(Progress),
 {
-  if !mod_data.overwrite_output && out_path.exists() {
-    return Err(anyhow::anyhow!("Output file already exists: {:?}", out_path));
+  if !mod_data.overwrite_output && !mod_data.dry_run && out_path.exists() {
+    return Err(PatchError::OutputExists { path: out_path.clone() }.into());
   }
 
   info!("Preparing to patch ISO file...");
-  let input_file = fs::File::open(in_path)?;
-  let input_file_mmap = unsafe { memmap2::MmapOptions::new().map(&input_file)? };
+  let mut input_file = fs::File::open(in_path)?;
+  let mut input_file_mmap = unsafe { memmap2::MmapOptions::new().map(&input_file)? };
+
+  // an already patched ISO is reverted to a temporary file, which is then patched instead
+  let mut _reverted_input = None;
+  let patched_dol_embedded = find_patched_dol(&input_file_mmap)?
+    .map(read_embedded)
+    .transpose()?;
+  if let Some(embedded) = patched_dol_embedded {
+    match &embedded {
+      Some(embedded) if mod_data.repatch => {
+        info!("{:?} was already patched with {}, patching the original stored in it", in_path, describe_patched(embedded));
+      }
+      _ => return Err(already_patched_error(in_path, embedded.as_ref(), TAG_REVERT_ISO)),
+    }
+    if mod_data.dry_run {
+      return Err(anyhow::anyhow!("Dry runs can't re-patch ISOs, revert {:?} first", in_path));
+    }
+    let reverted = TempFile(temp_path(out_path, "original"));
+    revert_iso_file(&progress_update, in_path, &reverted.0)?;
+    input_file = fs::File::open(&reverted.0)?;
+    input_file_mmap = unsafe { memmap2::MmapOptions::new().map(&input_file)? };
+    _reverted_input = Some(reverted);
+  }
 
   if let Some(expected_iso_hash) = mod_data.config.expected_iso_hash.clone() {
     info!("Verifying input ISO hash...");
@@ -50,11 +91,11 @@ pub fn patch_iso_file<F>(
     progress_update(Progress::new(length as u64, length as u64, "Hashing ISO".to_string()));
     let result_hash = format!("{:x}", hasher.finalize());
     if result_hash != expected_iso_hash {
-      return Err(anyhow::anyhow!(
-                "Input ISO hash does not match expected hash. Expected: {}, Got: {}. Check \"Ignore Hash\" option to bypass this check.",
-                expected_iso_hash,
-                result_hash
-            ));
+      return Err(PatchError::HashMismatch {
+        file: HashedFile::Iso,
+        expected: expected_iso_hash,
+        actual: result_hash,
+      }.into());
     }
     info!("Input ISO hash verified.");
   } else {
@@ -62,7 +103,7 @@ pub fn patch_iso_file<F>(
   }
 
   let mut input_reader = Cursor::new(&input_file_mmap[..]);
-  let mut disc_header = GCDiscHeader::read_from_stream(&mut input_reader)?;
+  let disc_header = GCDiscHeader::read_from_stream(&mut input_reader)?;
   info!("Disk name: {}", disc_header.name_string());
 
   input_reader.seek(SeekFrom::Start(disc_header.fst_offset as u64))?;
@@ -74,14 +115,14 @@ pub fn patch_iso_file<F>(
   // attract.length = 0
   for file in &mod_data.config.truncate_files {
     info!("Truncating {} to make room for mod", file);
-    let mut split = file.split('/').collect::<Vec<&str>>();
-    split.insert(0, "<root>");
-    if let Some(FSTEntry::File { length, .. }) = fst.root.find_mut(&split) {
+    if let Some(FSTEntry::File { length, .. }) = fst.root.find_path_mut(file) {
       *length = 0;
     } else {
       info!("Warning: Could not find {} in FST", file);
     }
   }
+  let gaps = find_gaps(&disc_header, &fst);
+  let new_files = apply_file_configs(&mut fst, mod_data)?;
 
   info!("Extracting dol...");
   let dol_header_bytes = &input_file_mmap[disc_header.dol_offset as usize..(disc_header.dol_offset + 0x100) as usize];
@@ -90,55 +131,92 @@ pub fn patch_iso_file<F>(
   let unpatched_dol_bytes = &input_file_mmap[disc_header.dol_offset as usize..(disc_header.dol_offset + dol_length) as usize];
 
   info!("Patching dol...");
-  let patched_dol_bytes = patch_dol(&mod_data, unpatched_dol_bytes)?;
+  let (patched_dol_bytes, mut manifest) = patch_dol(mod_data, unpatched_dol_bytes)?;
+  // only known when it was verified, hashing the whole ISO is slow
+  manifest.input_iso_hash = mod_data.config.expected_iso_hash.clone();
+  let manifest_bytes = manifest.to_toml()?.into_bytes();
 
-  info!("Finding a suitable gap...");
-  let mut file_ranges = fst.root.get_ranges();
-  // add the first gap (before user area)
-  file_ranges.insert(0, (0, disc_header.user_pos));
+  // TODO: load this from the elf
+  let bnr_bytes = match &mod_data.config.bnr_file {
+    Some(bnr_name) => Some(fs::read(std::env::current_dir()?.join(bnr_name))?),
+    None => None,
+  };
 
-  let gaps = convert_ranges_to_gaps(&file_ranges);
-  let search_size = patched_dol_bytes.len() as u32 + 8192; // extra padding
-  let mut chosen_gap: Option<(u32, u32)> = None;
-  for gap in gaps {
-    let gap_size = gap.1 - gap.0;
-    if gap_size >= search_size {
-      chosen_gap = Some(gap);
-      break;
-    }
-  }
-  if chosen_gap.is_none() {
-    return Err(anyhow::anyhow!("Could not find a suitable gap in the ISO to fit the patched DOL"));
-  }
-  let chosen_gap = chosen_gap.unwrap();
-  info!("Chosen gap: {:?}", chosen_gap);
+  // everything but the ISO revert record, which depends on the layout
+  let dol_revert_record = RevertRecord::diff(
+    unpatched_dol_bytes,
+    &patched_dol_bytes,
+    Some(manifest.input_dol_hash.clone()),
+  );
+  let mut dol_embedded = EmbeddedData::default();
+  dol_embedded.push(TAG_SIGNATURE, PatchSignature::from_manifest(&manifest).to_bytes()?);
+  dol_embedded.push(TAG_MANIFEST, manifest_bytes.clone());
+  dol_embedded.push(TAG_REVERT_DOL, dol_revert_record.to_bytes()?);
 
-  let mod_dol_offset = chosen_gap.1 - patched_dol_bytes.len() as u32;
-  let mod_dol_offset = mod_dol_offset - (mod_dol_offset % 8192);
-  info!("Mod DOL offset in ISO: {}", mod_dol_offset);
+  let contents = IsoContents {
+    fst,
+    gaps,
+    new_files,
+    dol_bytes: patched_dol_bytes,
+    dol_embedded,
+    manifest_bytes,
+    bnr_bytes,
+  };
+  let IsoLayout { mut disc_header, mut writes, size, copy_input, dol_bytes } = if mod_data.rebuild {
+    info!("Rebuilding ISO...");
+    rebuild_layout(&input_file_mmap, &disc_header, contents)?
+  } else {
+    fit_layout(&input_file_mmap, &disc_header, contents, mod_data.config.expected_iso_hash.clone())?
+  };
 
-  info!("Patching FST...");
-  fst.root.add_child(FSTEntry::File {
-    name: "default_mod.dol".to_string(),
-    offset: mod_dol_offset,
-    length: patched_dol_bytes.len() as u32,
-  })?;
+  info!("Building patched header...");
+  // write new string to the start of the game name
+  Cursor::new(&mut disc_header.game_name[..])
+    .write_string(&mod_data.config.game_name)?;
+  let mut disc_header_bytes = Vec::new();
+  disc_header.write_to_stream(&mut Cursor::new(&mut disc_header_bytes))?;
+  writes.push(IsoWrite {
+    offset: 0,
+    data: Cow::Owned(disc_header_bytes),
+    description: "disc header".to_string(),
+  });
+
+  if mod_data.dry_run {
+    let mut changes: Vec<PlannedChange> = writes.iter()
+      .map(|write| PlannedChange {
+        offset: write.offset as u64,
+        size: write.data.len() as u64,
+        address: None,
+        description: write.description.clone(),
+      })
+      .collect();
+    changes.sort_by_key(|change| change.offset);
+    let plan = PatchPlan {
+      output_path: out_path.clone(),
+      output_size: size as u64,
+      changes,
+      dol_changes: plan_dol_changes(unpatched_dol_bytes, &dol_bytes, &manifest)?,
+      manifest,
+    };
+    progress_update(Progress::new(0, 0, "Done planning ISO patch".to_string()));
+    return Ok(PatchOutput { path: out_path.clone(), manifest: plan.manifest.clone(), plan: Some(plan) });
+  }
 
-  info!("Copying ISO...");
   let output_file = fs::File::options()
-    .create(true).write(true).read(true)
+    .create(true).write(true).read(true).truncate(true)
     .open(out_path)?;
-  output_file.set_len(input_file_mmap.len() as u64)?;
+  output_file.set_len(size as u64)?;
   let mut output_file_mmap = unsafe { memmap2::MmapOptions::new().map_mut(&output_file)? };
   // do it in chunks so we can update progress \
-  {
+  if copy_input {
+    info!("Copying ISO...");
     const CHUNK_SIZE: usize = 8 * 1024 * 1024;
     let mut processed_bytes = 0;
     let mut last_update = 0;
     let length = input_file_mmap.len();
 
     progress_update(Progress::new(0, length as u64, "Copying ISO".to_string()));
-    for (in_chunk, out_chunk) in input_file_mmap.chunks(CHUNK_SIZE).zip(output_file_mmap.chunks_mut(CHUNK_SIZE)) {
+    for (in_chunk, out_chunk) in input_file_mmap.chunks(CHUNK_SIZE).zip(output_file_mmap[..length].chunks_mut(CHUNK_SIZE)) {
       out_chunk.copy_from_slice(in_chunk);
       processed_bytes += in_chunk.len();
       // only update ever 1MB to avoid spamming the UI
@@ -150,57 +228,729 @@ pub fn patch_iso_file<F>(
     progress_update(Progress::new(length as u64, length as u64, "Copying ISO".to_string()));
   }
 
-  info!("Writing patched fst...");
+  {
+    let length: usize = writes.iter().map(|write| write.data.len()).sum();
+    let mut processed_bytes = 0;
+    let mut last_update = 0;
+    progress_update(Progress::new(0, length as u64, "Writing ISO".to_string()));
+    for write in &writes {
+      info!("Writing {}...", write.description);
+      let offset = write.offset as usize;
+      output_file_mmap[offset..offset + write.data.len()].copy_from_slice(&write.data);
+      processed_bytes += write.data.len();
+      if processed_bytes - last_update >= 1024 * 1024 {
+        last_update = processed_bytes;
+        progress_update(Progress::new(processed_bytes as u64, length as u64, "Writing ISO".to_string()));
+      }
+    }
+  }
+
+  info!("Closing files...");
+  output_file_mmap.flush()?;
+
+  progress_update(Progress::new(0, 0, "Done patching ISO".to_string()));
+  Ok(PatchOutput { path: out_path.clone(), manifest, plan: None })
+}
+
+/// What goes into the patched ISO, before it is laid out
+struct IsoContents {
+  /// With the mod's file changes applied, without the patched DOL and the manifest
+  fst: FST,
+  /// Unused space in the input. Replaced and deleted files keep their data, so it isn't reused.
+  gaps: Vec<(u32, u32)>,
+  new_files: Vec<NewFile>,
+  dol_bytes: Vec<u8>,
+  /// Appended to the DOL, along with the ISO revert record if there is one
+  dol_embedded: EmbeddedData,
+  manifest_bytes: Vec<u8>,
+  bnr_bytes: Option<Vec<u8>>,
+}
+
+/// Where everything goes in the patched ISO
+struct IsoLayout<'a> {
+  /// Without the new game name
+  disc_header: GCDiscHeader,
+  /// Everything written to the output, over a copy of the input if `copy_input`
+  writes: Vec<IsoWrite<'a>>,
+  size: u32,
+  copy_input: bool,
+  /// The patched DOL, with its embedded data
+  dol_bytes: Vec<u8>,
+}
+
+struct IsoWrite<'a> {
+  offset: u32,
+  /// Data from the input is borrowed, so rebuilding doesn't need a copy of it in memory
+  data: Cow<'a, [u8]>,
+  description: String,
+}
+
+/// Keep the input as it is and fit the patched DOL and new files into unused space,
+/// or append them to the end
+fn fit_layout<'a>(
+  input: &'a [u8],
+  disc_header: &GCDiscHeader,
+  contents: IsoContents,
+  original_hash: Option<String>,
+) -> Result<IsoLayout<'a>> {
+  let IsoContents { mut fst, gaps, mut new_files, mut dol_bytes, dol_embedded, manifest_bytes, bnr_bytes } = contents;
+  let mut disc_header = disc_header.clone();
+  let mut space = IsoSpace { gaps, end: input.len() as u32 };
+  // original data the patcher writes over, copied after the end of the original
+  let mut saved = Vec::new();
+
+  // the FST only grows by the new entries, their offsets don't matter for the size
+  let new_fst_size = {
+    let mut sized_fst = fst.clone();
+    for name in ["default_mod.dol", MANIFEST_FILE_NAME] {
+      sized_fst.root.add_child(FSTEntry::File {
+        name: name.to_string(),
+        offset: 0,
+        length: 0,
+      })?;
+    }
+    let mut fst_bytes_vec = Vec::new();
+    sized_fst.write_to_stream(&mut Cursor::new(&mut fst_bytes_vec))?;
+    fst_bytes_vec.len() as u32
+  };
+
+  // everything the patcher overwrites in the ISO. The original DOL stays where it was.
+  // Data appended after the end of the original is dropped when reverting.
+  let mut iso_revert_record = RevertRecord {
+    original_size: input.len() as u32,
+    original_hash,
+    chunks: vec![RevertChunk::capture(input, 0, DISC_HEADER_SIZE)],
+  };
+  if let Some(bnr_bytes) = &bnr_bytes {
+    iso_revert_record.chunks.push(RevertChunk::capture(input, disc_header.user_pos, bnr_bytes.len() as u32));
+  }
+
+  // the FST is written back in place when it fits the space the original had. Otherwise it
+  // moves, so it doesn't overwrite what follows it, and the original stays where it was.
+  let fst_allocation = disc_header.fst_max_size.max(disc_header.fst_size);
+  let fst_offset = if new_fst_size <= fst_allocation {
+    iso_revert_record.chunks.push(RevertChunk::capture(input, disc_header.fst_offset, disc_header.fst_size.max(new_fst_size)));
+    disc_header.fst_offset
+  } else {
+    let gap_offset = space.take_gap(new_fst_size);
+    let offset = gap_offset.unwrap_or_else(|| space.append(new_fst_size));
+    info!(
+      "The FST grows to {} bytes, more than the {} bytes it had, moving it to 0x{:08X}",
+      new_fst_size,
+      fst_allocation,
+      offset
+    );
+    if gap_offset.is_some() {
+      iso_revert_record.chunks.push(capture_unused(input, offset, new_fst_size, "the FST", &mut space, &mut saved));
+    }
+    offset
+  };
+
+  // placed before the DOL, the revert record needs to know where they go to know its size
+  for new_file in &mut new_files {
+    let length = new_file.data.len() as u32;
+    new_file.offset = match space.take_gap(length) {
+      Some(offset) => {
+        iso_revert_record.chunks.push(capture_unused(input, offset, length, &new_file.path, &mut space, &mut saved));
+        offset
+      }
+      None => space.append(length),
+    };
+    info!("Placing {} at 0x{:08X} ({} bytes)", new_file.path, new_file.offset, length);
+    if let Some(FSTEntry::File { offset, .. }) = fst.root.find_path_mut(&new_file.path) {
+      *offset = new_file.offset;
+    }
+  }
+  let embedded_for = |iso_revert_record: &RevertRecord| -> Result<EmbeddedData> {
+    let mut embedded = dol_embedded.clone();
+    embedded.push(TAG_REVERT_ISO, iso_revert_record.to_bytes()?);
+    Ok(embedded)
+  };
+  // the space the DOL and the manifest go into is recorded as a moved chunk, the largest kind
+  // that doesn't store data, so the record's size doesn't depend on where they end up.
+  // A fill takes less space and leaves unused bytes after the DOL.
+  iso_revert_record.chunks.push(RevertChunk::Moved { offset: 0, length: 0, from: 0 });
+  let patched_dol_length = embedded_for(&iso_revert_record)?.appended_len(dol_bytes.len())? as u32;
+
+  info!("Finding a suitable gap...");
+  let gaps = &space.gaps;
+  // the manifest goes right before the DOL
+  let search_size = patched_dol_length + manifest_bytes.len() as u32 + MANIFEST_ALIGN + 8192; // extra padding
+  let mut chosen_gap: Option<(u32, u32)> = None;
+  for gap in gaps {
+    let gap_size = gap.1 - gap.0;
+    if gap_size >= search_size {
+      chosen_gap = Some(*gap);
+      break;
+    }
+  }
+  let Some(chosen_gap) = chosen_gap else {
+    return Err(PatchError::NoIsoGap {
+      required: search_size,
+      available: gaps.iter().map(|gap| gap.1 - gap.0).max().unwrap_or(0),
+    }.into());
+  };
+  info!("Chosen gap: {:?}", chosen_gap);
+
+  let mod_dol_offset = chosen_gap.1 - patched_dol_length;
+  let mod_dol_offset = mod_dol_offset - (mod_dol_offset % 8192);
+  info!("Mod DOL offset in ISO: {}", mod_dol_offset);
+  let manifest_offset = mod_dol_offset - manifest_bytes.len() as u32;
+  let manifest_offset = manifest_offset - (manifest_offset % MANIFEST_ALIGN);
+  info!("Manifest offset in ISO: {}", manifest_offset);
+
+  let used_length = mod_dol_offset + patched_dol_length - manifest_offset;
+  let gap_chunk = capture_unused(input, manifest_offset, used_length, "the patched DOL", &mut space, &mut saved);
+  *iso_revert_record.chunks.last_mut().unwrap() = gap_chunk;
+  embedded_for(&iso_revert_record)?.append_to(&mut dol_bytes)?;
+
+  info!("Patching FST...");
+  fst.root.add_child(FSTEntry::File {
+    name: "default_mod.dol".to_string(),
+    offset: mod_dol_offset,
+    length: dol_bytes.len() as u32,
+  })?;
+  fst.root.add_child(FSTEntry::File {
+    name: MANIFEST_FILE_NAME.to_string(),
+    offset: manifest_offset,
+    length: manifest_bytes.len() as u32,
+  })?;
+
+  info!("Building patched fst...");
   let fst_bytes = {
     let mut fst_bytes_vec = Vec::new();
     fst.write_to_stream(&mut Cursor::new(&mut fst_bytes_vec))?;
     fst_bytes_vec
   };
-  let fst_offset = disc_header.fst_offset as usize;
-  let fst_size = fst_bytes.len();
-  output_file_mmap[fst_offset..fst_offset + fst_size].copy_from_slice(&fst_bytes);
+  let fst_size = fst_bytes.len() as u32;
 
-  info!("Patching header...");
-  // write new string to the start of the game name
-  Cursor::new(&mut disc_header.game_name[..])
-    .write_string(&mod_data.config.game_name)?;
   disc_header.dol_offset = mod_dol_offset;
-  disc_header.fst_offset = fst_offset as u32; // didn't actually move, but to be safe
-  disc_header.fst_size = fst_size as u32;
-  disc_header.fst_max_size = fst_size as u32;
-  disc_header.write_to_stream(&mut Cursor::new(&mut output_file_mmap[..]))?;
+  disc_header.fst_offset = fst_offset;
+  disc_header.fst_size = fst_size;
+  disc_header.fst_max_size = fst_size;
 
-  info!("Writing patched dol...");
-  let dol_offset = mod_dol_offset as usize;
-  output_file_mmap[dol_offset..dol_offset + patched_dol_bytes.len()]
-    .copy_from_slice(&patched_dol_bytes);
+  // everything written over the copy of the input
+  let mut writes = vec![
+    IsoWrite { offset: fst_offset, data: Cow::Owned(fst_bytes), description: "FST".to_string() },
+    IsoWrite { offset: mod_dol_offset, data: Cow::Owned(dol_bytes.clone()), description: "patched DOL".to_string() },
+    IsoWrite { offset: manifest_offset, data: Cow::Owned(manifest_bytes), description: "patch manifest".to_string() },
+  ];
+  if let Some(bnr_bytes) = bnr_bytes {
+    writes.push(IsoWrite { offset: disc_header.user_pos, data: Cow::Owned(bnr_bytes), description: "banner".to_string() });
+  }
+  for new_file in new_files {
+    writes.push(IsoWrite { offset: new_file.offset, data: Cow::Owned(new_file.data), description: new_file.path });
+  }
+  writes.extend(saved);
+  if space.end as usize > input.len() {
+    info!("ISO grows from {} to {} bytes", input.len(), space.end);
+    if space.end > GCN_DISC_SIZE {
+      warn!("The patched ISO is larger than a GameCube disc, it may only work in emulators");
+    }
+  }
 
-  // TODO: load this from the elf
-  if let Some(bnr_name) = &mod_data.config.bnr_file {
-    info!("Patching bnr...");
-    let bnr_path = std::env::current_dir()?
-      .join(bnr_name);
-    let bnr_bytes = fs::read(bnr_path)?;
-    let bnr_offset = disc_header.user_pos as usize;
-    output_file_mmap[bnr_offset..bnr_offset + bnr_bytes.len()]
-      .copy_from_slice(&bnr_bytes);
+  Ok(IsoLayout { disc_header, writes, size: space.end, copy_input: true, dol_bytes })
+}
+
+/// Lay out the whole ISO again: the system area as it was, the DOL, the FST, then every file.
+/// Unused space and the data of replaced or deleted files are dropped, so it can't be reverted.
+fn rebuild_layout<'a>(
+  input: &'a [u8],
+  disc_header: &GCDiscHeader,
+  contents: IsoContents,
+) -> Result<IsoLayout<'a>> {
+  let IsoContents { mut fst, new_files, mut dol_bytes, dol_embedded, manifest_bytes, bnr_bytes, .. } = contents;
+  let mut disc_header = disc_header.clone();
+  // the disc header, bi2 and apploader are kept as they are
+  let system_area_end = apploader_end(input)?;
+
+  dol_embedded.append_to(&mut dol_bytes)?;
+  fst.root.add_child(FSTEntry::File {
+    name: "default_mod.dol".to_string(),
+    offset: 0,
+    length: dol_bytes.len() as u32,
+  })?;
+  fst.root.add_child(FSTEntry::File {
+    name: MANIFEST_FILE_NAME.to_string(),
+    offset: 0,
+    length: manifest_bytes.len() as u32,
+  })?;
+  // offsets don't change the size of the FST
+  let fst_size = {
+    let mut fst_bytes_vec = Vec::new();
+    fst.write_to_stream(&mut Cursor::new(&mut fst_bytes_vec))?;
+    fst_bytes_vec.len() as u32
+  };
+
+  let mut layout = RebuildLayout::new(system_area_end, dol_bytes.len() as u32, fst_size);
+  let dol_offset = layout.dol_offset;
+  let mut writes = vec![
+    IsoWrite { offset: 0, data: Cow::Borrowed(&input[..system_area_end as usize]), description: "system area".to_string() },
+    IsoWrite { offset: dol_offset, data: Cow::Owned(dol_bytes.clone()), description: "patched DOL".to_string() },
+  ];
+
+  // original files keep their order, so the banner stays at the start of the user area.
+  // The manifest and the mod's files go after them.
+  let mut new_files: HashMap<String, Vec<u8>> = new_files.into_iter()
+    .map(|new_file| (new_file.path.to_ascii_lowercase(), new_file.data))
+    .collect();
+  let mut files = fst.root.files();
+  files.sort_by_key(|(path, offset, _)| {
+    (path == MANIFEST_FILE_NAME || new_files.contains_key(&path.to_ascii_lowercase()), *offset)
+  });
+  for (path, original_offset, length) in files {
+    let data = if path == "default_mod.dol" {
+      set_file_offset(&mut fst, &path, dol_offset);
+      continue;
+    } else if path == MANIFEST_FILE_NAME {
+      Cow::Owned(manifest_bytes.clone())
+    } else if let Some(data) = new_files.remove(&path.to_ascii_lowercase()) {
+      Cow::Owned(data)
+    } else {
+      let data = &input[original_offset as usize..(original_offset + length) as usize];
+      match &bnr_bytes {
+        // the banner replaces the start of the file at the start of the user area
+        Some(bnr_bytes) if original_offset == disc_header.user_pos && length > 0 => {
+          let mut data = data.to_vec();
+          let bnr_length = bnr_bytes.len().min(data.len());
+          data[..bnr_length].copy_from_slice(&bnr_bytes[..bnr_length]);
+          Cow::Owned(data)
+        }
+        _ => Cow::Borrowed(data),
+      }
+    };
+    let offset = layout.place_file(data.len() as u32);
+    set_file_offset(&mut fst, &path, offset);
+    if !data.is_empty() {
+      writes.push(IsoWrite { offset, data, description: path });
+    }
   }
+  let size = layout.finish(&mut disc_header)?;
+  info!("Rebuilt ISO is {} bytes, {} bytes of files", size, size - layout.user_pos);
 
-  info!("Closing files...");
-  output_file_mmap.flush()?;
+  let mut fst_bytes = Vec::new();
+  fst.write_to_stream(&mut Cursor::new(&mut fst_bytes))?;
+  writes.push(IsoWrite { offset: layout.fst_offset, data: Cow::Owned(fst_bytes), description: "FST".to_string() });
 
-  progress_update(Progress::new(0, 0, "Done patching ISO".to_string()));
-  Ok(())
+  Ok(IsoLayout { disc_header, writes, size, copy_input: false, dol_bytes })
+}
+
+fn set_file_offset(fst: &mut FST, path: &str, new_offset: u32) {
+  if let Some(FSTEntry::File { offset, .. }) = fst.root.find_path_mut(path) {
+    *offset = new_offset;
+  }
 }
 
+/// Where the DOL, FST and files go in a rebuilt or built ISO, packed after the system area
+pub(crate) struct RebuildLayout {
+  pub dol_offset: u32,
+  pub fst_offset: u32,
+  fst_size: u32,
+  pub user_pos: u32,
+  /// End of the files placed so far, may be past the end of a disc
+  end: u64,
+}
+
+impl RebuildLayout {
+  pub fn new(system_area_end: u32, dol_length: u32, fst_size: u32) -> Self {
+    let dol_offset = system_area_end.next_multiple_of(SYSTEM_ALIGN);
+    let fst_offset = (dol_offset + dol_length).next_multiple_of(SYSTEM_ALIGN);
+    let user_pos = (fst_offset + fst_size).next_multiple_of(REBUILD_FILE_ALIGN);
+    RebuildLayout { dol_offset, fst_offset, fst_size, user_pos, end: user_pos as u64 }
+  }
+
+  /// Offset for the next file in the user area
+  pub fn place_file(&mut self, length: u32) -> u32 {
+    let offset = self.end.next_multiple_of(REBUILD_FILE_ALIGN as u64);
+    self.end = offset + length as u64;
+    offset.min(u32::MAX as u64) as u32
+  }
+
+  /// Point `disc_header` at the layout, and return the size of the ISO
+  pub fn finish(&self, disc_header: &mut GCDiscHeader) -> Result<u32> {
+    let size = self.end.next_multiple_of(REBUILD_FILE_ALIGN as u64);
+    if size > GCN_DISC_SIZE as u64 {
+      return Err(PatchError::DiscTooLarge { required: size.min(u32::MAX as u64) as u32, available: GCN_DISC_SIZE }.into());
+    }
+    let size = size as u32;
+    disc_header.dol_offset = self.dol_offset;
+    disc_header.fst_offset = self.fst_offset;
+    disc_header.fst_size = self.fst_size;
+    disc_header.fst_max_size = self.fst_size;
+    disc_header.user_pos = self.user_pos;
+    disc_header.user_len = size - self.user_pos;
+    Ok(size)
+  }
+}
+
+/// A file the mod adds or replaces, and where its data goes
+#[derive(Debug)]
+struct NewFile {
+  path: String,
+  data: Vec<u8>,
+  offset: u32,
+}
+
+/// Apply the mod's `files` to the FST. Returns the files that need their data written,
+/// their FST entries get the offset once it is known.
+fn apply_file_configs(fst: &mut FST, mod_data: &ModData) -> Result<Vec<NewFile>> {
+  let mut new_files: Vec<NewFile> = Vec::new();
+  for file in &mod_data.config.files {
+    let path = file.path.trim_matches('/');
+    if file.action == FileAction::Delete {
+      info!("Deleting {} from FST", path);
+      fst.root.remove_path(path)
+        .ok_or_else(|| PatchError::FileNotInFst { path: path.to_string(), action: "delete" })?;
+      new_files.retain(|new_file| !new_file.path.eq_ignore_ascii_case(path));
+      continue;
+    }
+    let data = mod_data.read_file_or_section(&file.file, &file.section)
+      .map_err(|e| anyhow::anyhow!("Failed to load new contents of {}: {}", path, e))?;
+    match file.action {
+      FileAction::Replace => {
+        info!("Replacing {} in FST ({} bytes)", path, data.len());
+        let Some(FSTEntry::File { length, .. }) = fst.root.find_path_mut(path) else {
+          return Err(PatchError::FileNotInFst { path: path.to_string(), action: "replace" }.into());
+        };
+        *length = data.len() as u32;
+      }
+      FileAction::Add => {
+        info!("Adding {} to FST ({} bytes)", path, data.len());
+        fst.root.add_file(path, 0, data.len() as u32)?;
+      }
+      FileAction::Delete => unreachable!(),
+    }
+    new_files.retain(|new_file| !new_file.path.eq_ignore_ascii_case(path));
+    new_files.push(NewFile { path: path.to_string(), data, offset: 0 });
+  }
+  Ok(new_files)
+}
+
+/// Space for new data: the unused space in the ISO, then the end of it
+struct IsoSpace {
+  gaps: Vec<(u32, u32)>,
+  end: u32,
+}
+
+impl IsoSpace {
+  /// Use the smallest gap `length` fits in, leaving large gaps for the DOL
+  fn take_gap(&mut self, length: u32) -> Option<u32> {
+    let (index, offset) = self.gaps.iter()
+      .enumerate()
+      .map(|(index, gap)| (index, gap.0.next_multiple_of(FILE_ALIGN)))
+      .filter(|(index, offset)| *offset + length <= self.gaps[*index].1)
+      .min_by_key(|(index, _)| self.gaps[*index].1 - self.gaps[*index].0)?;
+    self.gaps[index].0 = offset + length;
+    Some(offset)
+  }
+
+  fn append(&mut self, length: u32) -> u32 {
+    let offset = self.end.next_multiple_of(FILE_ALIGN);
+    self.end = offset + length;
+    offset
+  }
+}
+
+/// Revert chunk for unused space the patcher writes into. Space that was all the same byte is
+/// recorded as a fill. Anything else, like the data of truncated files, is copied after the end
+/// of the ISO. The record is in the DOL, so it can't hold the data of the space the DOL goes into.
+fn capture_unused<'a>(
+  original: &'a [u8],
+  offset: u32,
+  length: u32,
+  description: &str,
+  space: &mut IsoSpace,
+  saved: &mut Vec<IsoWrite<'a>>,
+) -> RevertChunk {
+  match RevertChunk::capture(original, offset, length) {
+    RevertChunk::Bytes { .. } => {
+      let from = space.append(length);
+      info!("The space used for {} is not empty, keeping its data at 0x{:08X} to revert it", description, from);
+      saved.push(IsoWrite {
+        offset: from,
+        data: Cow::Borrowed(&original[offset as usize..(offset + length) as usize]),
+        description: format!("original data under {}", description),
+      });
+      RevertChunk::Moved { offset, length, from }
+    }
+    fill => fill,
+  }
+}
+
+/// Removed when dropped
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    if let Err(e) = fs::remove_file(&self.0) {
+      warn!("Failed to remove temporary file {:?}: {}", self.0, e);
+    }
+  }
+}
+
+/// `game_mod.iso` -> `game_mod.iso.<suffix>.tmp`
+fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+  let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+  file_name.push(format!(".{}.tmp", suffix));
+  path.with_file_name(file_name)
+}
+
+/// Space in the user area that no file, and not the FST, uses
+pub fn find_gaps(disc_header: &GCDiscHeader, fst: &FST) -> Vec<(u32, u32)> {
+  let mut file_ranges = fst.root.get_ranges();
+  // add the first gap (before user area)
+  file_ranges.insert(0, (0, disc_header.user_pos));
+  let fst_end = disc_header.fst_offset + disc_header.fst_max_size.max(disc_header.fst_size);
+  file_ranges.push((disc_header.fst_offset, fst_end));
+  file_ranges.sort_by_key(|range| range.0);
+  convert_ranges_to_gaps(&file_ranges)
+}
+
+/// `ranges` must be sorted by start, they may overlap
 fn convert_ranges_to_gaps(ranges: &Vec<(u32, u32)>) -> Vec<(u32, u32)> {
   let mut gaps = Vec::new();
-  for i in 0..ranges.len() - 1 {
-    let end_of_current = ranges[i].1;
-    let start_of_next = ranges[i + 1].0;
+  let mut end_of_current = ranges[0].1;
+  for range in &ranges[1..] {
+    let start_of_next = range.0;
     if start_of_next > end_of_current {
       gaps.push((end_of_current, start_of_next));
     }
+    end_of_current = end_of_current.max(range.1);
   }
   gaps
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::revert::{check_reverted_hash, read_iso_revert_record};
+
+  const DOL_OFFSET: u32 = 0x2600;
+  const FST_OFFSET: u32 = 0x3000;
+  const USER_POS: u32 = 0x8000;
+  const MOVIE: (u32, u32) = (0x8200, 0x20000);
+
+  /// Deterministic bytes that are never all the same, so every gap has to be kept
+  fn noise(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+      .map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (state >> 16) as u8
+      })
+      .collect()
+  }
+
+  /// A disc with one text section in its DOL, the banner, a movie to truncate and a last file.
+  /// The FST has no room to grow.
+  fn test_iso() -> Vec<u8> {
+    let mut iso = noise(0x40000, 1);
+    let mut dol = vec![0u8; 0x100];
+    dol[0x00..0x04].copy_from_slice(&0x100u32.to_be_bytes());
+    dol[0x48..0x4C].copy_from_slice(&0x8000_3100u32.to_be_bytes());
+    dol[0x90..0x94].copy_from_slice(&0x100u32.to_be_bytes());
+    dol[0xE0..0xE4].copy_from_slice(&0x8000_3100u32.to_be_bytes());
+    dol.extend_from_slice(&noise(0x100, 2));
+
+    let mut fst = FST { root: FSTEntry::Directory { name: String::new(), children: Vec::new() } };
+    fst.root.add_file("opening.bnr", USER_POS, 0x100).unwrap();
+    fst.root.add_file("movie.thp", MOVIE.0, MOVIE.1).unwrap();
+    fst.root.add_file("last.bin", MOVIE.0 + MOVIE.1, 0x40000 - MOVIE.0 - MOVIE.1).unwrap();
+    let mut fst_bytes = Vec::new();
+    fst.write_to_stream(&mut Cursor::new(&mut fst_bytes)).unwrap();
+
+    let mut disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&iso[..])).unwrap();
+    disc_header.dol_offset = DOL_OFFSET;
+    disc_header.fst_offset = FST_OFFSET;
+    disc_header.fst_size = fst_bytes.len() as u32;
+    disc_header.fst_max_size = fst_bytes.len() as u32;
+    disc_header.user_pos = USER_POS;
+    disc_header.user_len = 0x40000 - USER_POS;
+    let mut header_bytes = Vec::new();
+    disc_header.write_to_stream(&mut Cursor::new(&mut header_bytes)).unwrap();
+
+    for (offset, data) in [(0, &header_bytes), (DOL_OFFSET, &dol), (FST_OFFSET, &fst_bytes)] {
+      iso[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+    }
+    iso
+  }
+
+  /// A mod that truncates the movie and changes `files`, `(path, action, length)`.
+  /// The contents of each file are in their own ELF section.
+  fn files_mod(files: &[(&str, &str, usize)]) -> ModData {
+    use crate::mod_image::tests::build_elf;
+    let names: Vec<String> = (0..files.len()).map(|i| format!(".file{}", i)).collect();
+    let sections: Vec<_> = files.iter().zip(&names).enumerate()
+      .map(|(i, (&(_, _, length), name))| (name.as_str(), object::elf::SHT_PROGBITS, 0, 0, 0, 0, vec![0x50 + i as u8; length]))
+      .collect();
+    let files_config: Vec<String> = files.iter().zip(&names)
+      .map(|(&(path, action, _), name)| format!("{{ path = \"{}\", action = \"{}\", section = \"{}\" }}", path, action, name))
+      .collect();
+    let config = format!("truncate_files = [\"movie.thp\"]\nfiles = [{}]\n", files_config.join(", "));
+    crate::patch_dol::tests::mod_data("Files", build_elf(&sections), &config)
+  }
+
+  fn read_fst(iso: &[u8]) -> FST {
+    let disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(iso)).unwrap();
+    let mut reader = Cursor::new(iso);
+    reader.seek(SeekFrom::Start(disc_header.fst_offset as u64)).unwrap();
+    FST::read_from_stream(&mut reader).unwrap()
+  }
+
+  /// Patch like `patch_iso_file`, with a DOL patched to return right away
+  fn patch(input: &[u8], mod_data: &ModData) -> Vec<u8> {
+    let disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(input)).unwrap();
+    let mut fst = read_fst(input);
+    for file in &mod_data.config.truncate_files {
+      if let Some(FSTEntry::File { length, .. }) = fst.root.find_path_mut(file) {
+        *length = 0;
+      }
+    }
+    let gaps = find_gaps(&disc_header, &fst);
+    let new_files = apply_file_configs(&mut fst, mod_data).unwrap();
+
+    let dol_offset = disc_header.dol_offset as usize;
+    let mut dol_bytes = input[dol_offset..dol_offset + 0x200].to_vec();
+    dol_bytes[0x100..0x104].copy_from_slice(&0x4E80_0020u32.to_be_bytes());
+    let contents = IsoContents {
+      fst,
+      gaps,
+      new_files,
+      dol_bytes,
+      dol_embedded: EmbeddedData::default(),
+      manifest_bytes: b"mod = \"test\"\n".to_vec(),
+      bnr_bytes: None,
+    };
+    let original_hash = format!("{:x}", md5::Md5::digest(input));
+    let layout = fit_layout(input, &disc_header, contents, Some(original_hash)).unwrap();
+
+    let mut output = input.to_vec();
+    output.resize(layout.size as usize, 0);
+    let mut header_bytes = Vec::new();
+    layout.disc_header.write_to_stream(&mut Cursor::new(&mut header_bytes)).unwrap();
+    output[..header_bytes.len()].copy_from_slice(&header_bytes);
+    for write in &layout.writes {
+      let offset = write.offset as usize;
+      output[offset..offset + write.data.len()].copy_from_slice(&write.data);
+    }
+    output
+  }
+
+  fn revert(patched: &[u8]) -> Vec<u8> {
+    let record = read_iso_revert_record(patched).unwrap();
+    let mut reverted = patched[..record.original_size as usize].to_vec();
+    record.apply_chunks(patched, &mut reverted);
+    check_reverted_hash(&record, &reverted).unwrap();
+    reverted
+  }
+
+  #[test]
+  fn patched_iso_reverts_to_the_original() {
+    let original = test_iso();
+    let patched = patch(&original, &files_mod(&[("new.bin", "add", 0x300)]));
+    // the DOL went into the truncated movie, whose data was kept after the end
+    let dol_offset = GCDiscHeader::read_from_stream(&mut Cursor::new(&patched[..])).unwrap().dol_offset;
+    assert!((MOVIE.0..MOVIE.0 + MOVIE.1).contains(&dol_offset));
+    assert!(patched.len() > original.len());
+    assert_eq!(revert(&patched), original);
+  }
+
+  #[test]
+  fn repatched_iso_is_patched_from_the_exact_original() {
+    let original = test_iso();
+    let mod_data = files_mod(&[("new.bin", "add", 0x300)]);
+    let patched = patch(&original, &mod_data);
+    let repatched = patch(&revert(&patched), &mod_data);
+    assert_eq!(repatched, patched);
+    assert_eq!(revert(&repatched), original);
+  }
+
+  #[test]
+  fn file_configs_edit_the_fst() {
+    let mut fst = read_fst(&test_iso());
+    let mod_data = files_mod(&[("MOVIE.THP", "replace", 0x30000), ("Mods/new.bin", "add", 0x10), ("last.bin", "delete", 0)]);
+    let new_files = apply_file_configs(&mut fst, &mod_data).unwrap();
+    assert_eq!(fst.root.files(), [
+      ("opening.bnr".to_string(), USER_POS, 0x100),
+      ("movie.thp".to_string(), MOVIE.0, 0x30000),
+      ("Mods/new.bin".to_string(), 0, 0x10),
+    ]);
+    let new_files: Vec<_> = new_files.iter().map(|file| (file.path.as_str(), file.data.len())).collect();
+    assert_eq!(new_files, [("MOVIE.THP", 0x30000), ("Mods/new.bin", 0x10)]);
+
+    for action in ["replace", "delete"] {
+      let mut fst = read_fst(&test_iso());
+      let error = apply_file_configs(&mut fst, &files_mod(&[("missing.bin", action, 0x10)])).unwrap_err();
+      assert!(matches!(
+        error.downcast_ref::<PatchError>(),
+        Some(PatchError::FileNotInFst { path, action: error_action }) if path == "missing.bin" && *error_action == action
+      ));
+    }
+  }
+
+  #[test]
+  fn replaced_files_that_grow_move_and_revert() {
+    let original = test_iso();
+    let last_offset = MOVIE.0 + MOVIE.1;
+    let last_length = 0x40000 - last_offset;
+    let patched = patch(&original, &files_mod(&[("last.bin", "replace", last_length as usize + 0x100)]));
+    let Some(FSTEntry::File { offset, length, .. }) = read_fst(&patched).root.find_path_mut("last.bin").cloned() else {
+      panic!("last.bin is missing");
+    };
+    assert_eq!(length, last_length + 0x100);
+    assert_ne!(offset, last_offset);
+    assert!(patched[offset as usize..(offset + length) as usize].iter().all(|b| *b == 0x50));
+    // the original data stays where it was
+    assert_eq!(patched[last_offset as usize..0x40000], original[last_offset as usize..]);
+    assert_eq!(revert(&patched), original);
+  }
+
+  #[test]
+  fn fst_that_outgrows_its_space_moves_and_reverts() {
+    let original = test_iso();
+    let original_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&original[..])).unwrap();
+    let files: Vec<(String, &str, usize)> = (0..8).map(|i| (format!("Mods/file{}.bin", i), "add", 0x20)).collect();
+    let files: Vec<(&str, &str, usize)> = files.iter().map(|(path, action, length)| (path.as_str(), *action, *length)).collect();
+    let patched = patch(&original, &files_mod(&files));
+
+    let disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&patched[..])).unwrap();
+    assert_ne!(disc_header.fst_offset, FST_OFFSET);
+    assert_eq!(read_fst(&patched).root.files().len(), 3 + 8 + 2);
+    // the original FST and everything after it are untouched
+    assert_eq!(patched[FST_OFFSET as usize..USER_POS as usize], original[FST_OFFSET as usize..USER_POS as usize]);
+    assert!(disc_header.fst_size > original_header.fst_max_size);
+    assert_eq!(revert(&patched), original);
+  }
+
+  #[test]
+  fn rebuild_layout_aligns_everything_and_updates_the_header() {
+    let mut disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&test_iso()[..])).unwrap();
+    let mut layout = RebuildLayout::new(0x3694, 0x2001, 0x30);
+    assert_eq!((layout.dol_offset, layout.fst_offset, layout.user_pos), (0x3700, 0x5800, 0x8000));
+    assert_eq!(layout.place_file(0x100), 0x8000);
+    assert_eq!(layout.place_file(0), 0x10000);
+    assert_eq!(layout.place_file(0x10), 0x10000);
+    assert_eq!(layout.finish(&mut disc_header).unwrap(), 0x18000);
+    assert_eq!((disc_header.dol_offset, disc_header.fst_offset), (0x3700, 0x5800));
+    assert_eq!((disc_header.fst_size, disc_header.fst_max_size), (0x30, 0x30));
+    assert_eq!((disc_header.user_pos, disc_header.user_len), (0x8000, 0x10000));
+  }
+
+  #[test]
+  fn rebuild_layout_must_fit_on_a_disc() {
+    let mut disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&test_iso()[..])).unwrap();
+    let mut layout = RebuildLayout::new(0x2460, 0x100, 0x30);
+    layout.place_file(GCN_DISC_SIZE - 0x8000);
+    assert_eq!(layout.finish(&mut disc_header).unwrap(), GCN_DISC_SIZE);
+
+    layout.place_file(1);
+    let error = layout.finish(&mut disc_header).unwrap_err();
+    assert!(matches!(
+      error.downcast_ref::<PatchError>(),
+      Some(PatchError::DiscTooLarge { required, available: GCN_DISC_SIZE }) if *required == GCN_DISC_SIZE + 0x8000
+    ));
+    // past the end of the address space
+    layout.place_file(u32::MAX);
+    layout.place_file(u32::MAX);
+    assert!(layout.finish(&mut disc_header).is_err());
+  }
+}
@@ -0,0 +1,170 @@
+//! What patching would change, reported by dry runs instead of writing the output
+use crate::binstream::BinStreamReadable;
+use crate::dol::{DolHeader, SectionInfo};
+use crate::manifest::PatchManifest;
+use crate::revert::changed_ranges;
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::PathBuf;
+
+const DOL_HEADER_SIZE: usize = 0x100;
+const WORD_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PatchPlan {
+  pub output_path: PathBuf,
+  pub output_size: u64,
+  /// Byte ranges of the output that would differ from the input
+  pub changes: Vec<PlannedChange>,
+  /// For ISOs, how the patched DOL differs from the original DOL
+  pub dol_changes: Vec<PlannedChange>,
+  pub manifest: PatchManifest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedChange {
+  pub offset: u64,
+  pub size: u64,
+  /// Where the changed bytes are loaded, for DOL section data
+  pub address: Option<u32>,
+  pub description: String,
+}
+
+impl PatchPlan {
+  pub fn print(&self) {
+    println!("Dry run, nothing was written.");
+    println!("Patching would write {:?} ({} bytes), changing:", self.output_path, self.output_size);
+    print_changes(&self.changes);
+    if !self.dol_changes.is_empty() {
+      println!("Changes to the DOL:");
+      print_changes(&self.dol_changes);
+    }
+    println!("Arena: 0x{:08X} - 0x{:08X}", self.manifest.arena_lo, self.manifest.arena_hi);
+    for applied_mod in &self.manifest.mods {
+      println!(
+        "{} v{}: 0x{:08X} - 0x{:08X}, entry 0x{:08X}",
+        applied_mod.mod_name, applied_mod.version, applied_mod.start, applied_mod.end, applied_mod.entry
+      );
+    }
+  }
+}
+
+fn print_changes(changes: &[PlannedChange]) {
+  for change in changes {
+    let address = change.address
+      .map(|address| format!(" @ 0x{:08X}", address))
+      .unwrap_or_default();
+    println!(
+      "  0x{:08X} - 0x{:08X} ({} bytes){}: {}",
+      change.offset, change.offset + change.size, change.size, address, change.description
+    );
+  }
+}
+
+/// Describe every byte range of `patched` that differs from `original`, using the manifest to
+/// name the patches
+pub fn plan_dol_changes(original: &[u8], patched: &[u8], manifest: &PatchManifest) -> Result<Vec<PlannedChange>> {
+  let original_header = DolHeader::read_from_stream(&mut Cursor::new(original))?;
+  let patched_header = DolHeader::read_from_stream(&mut Cursor::new(patched))?;
+  let mut changes = Vec::new();
+
+  // instructions and most patched values are words, so describe whole words
+  let mut header_range: Option<Range<usize>> = None;
+  let mut data_ranges: Vec<Range<usize>> = Vec::new();
+  for range in changed_ranges(original, patched, WORD_SIZE) {
+    if range.start < DOL_HEADER_SIZE {
+      let end = range.end.min(DOL_HEADER_SIZE);
+      header_range = Some(match header_range {
+        Some(header_range) => header_range.start..end,
+        None => range.start..end,
+      });
+    }
+    let start = range.start.max(DOL_HEADER_SIZE) / WORD_SIZE * WORD_SIZE;
+    let end = range.end.next_multiple_of(WORD_SIZE).min(original.len());
+    if start >= end {
+      continue;
+    }
+    match data_ranges.last_mut() {
+      Some(last) if last.end >= start => last.end = end,
+      _ => data_ranges.push(start..end),
+    }
+  }
+  if let Some(range) = header_range {
+    changes.push(PlannedChange {
+      offset: range.start as u64,
+      size: range.len() as u64,
+      address: None,
+      description: "DOL header".to_string(),
+    });
+  }
+  for range in data_ranges {
+    let address = offset_to_address(&original_header, range.start as u32);
+    changes.push(PlannedChange {
+      offset: range.start as u64,
+      size: range.len() as u64,
+      address,
+      description: address
+        .and_then(|address| describe_patch(manifest, address, range.len() as u32))
+        .unwrap_or_else(|| "modified data".to_string()),
+    });
+  }
+
+  // sections placed or grown past the end of the original
+  let original_len = original.len() as u32;
+  let tables = [("text", &patched_header.text), ("data", &patched_header.data)];
+  for (kind, sections) in tables {
+    for (i, section) in sections.iter().enumerate() {
+      let end = section.offset + section.size;
+      if section.size == 0 || end <= original_len {
+        continue;
+      }
+      let start = section.offset.max(original_len);
+      let description = if section.offset >= original_len {
+        format!("{} section {}", kind, i)
+      } else {
+        format!("{} section {} (extended)", kind, i)
+      };
+      changes.push(PlannedChange {
+        offset: start as u64,
+        size: (end - start) as u64,
+        address: Some(section.loading + (start - section.offset)),
+        description,
+      });
+    }
+  }
+  let sections_end = (patched_header.total_length() as usize).max(original.len());
+  if patched.len() > sections_end {
+    changes.push(PlannedChange {
+      offset: sections_end as u64,
+      size: (patched.len() - sections_end) as u64,
+      address: None,
+      description: "patcher data (manifest and revert record)".to_string(),
+    });
+  }
+  changes.sort_by_key(|change| change.offset);
+  Ok(changes)
+}
+
+fn offset_to_address(dol_header: &DolHeader, offset: u32) -> Option<u32> {
+  dol_header.text.iter()
+    .chain(dol_header.data.iter())
+    .find(|section: &&SectionInfo| section.size > 0 && section.offset <= offset && offset < section.offset + section.size)
+    .map(|section| section.loading + (offset - section.offset))
+}
+
+/// Names of the patches in the manifest that touch `address..address + size`
+fn describe_patch(manifest: &PatchManifest, address: u32, size: u32) -> Option<String> {
+  let overlaps = |patch_address: u32, patch_size: u32| patch_address < address + size && address < patch_address + patch_size;
+  let mut descriptions: Vec<String> = manifest.arena_patches.iter()
+    .filter(|patch| overlaps(patch.address, patch.size))
+    .map(|patch| patch.description.clone())
+    .collect();
+  for applied_mod in &manifest.mods {
+    descriptions.extend(applied_mod.patches.iter()
+      .filter(|patch| overlaps(patch.address, patch.size))
+      .map(|patch| format!("{} {}", applied_mod.mod_name, patch.description)));
+  }
+  (!descriptions.is_empty()).then(|| descriptions.join(", "))
+}
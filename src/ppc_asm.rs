@@ -0,0 +1,550 @@
+//! A small assembler for the Gekko's integer, branch and basic floating point instructions.
+//! Enough for one-off instruction patches, not a replacement for a real toolchain.
+use anyhow::Result;
+
+/// Assemble instructions separated by newlines or `;`, the first one placed at `address`.
+/// Symbols in operands are looked up with `resolve`.
+pub fn assemble<F>(source: &str, address: u32, resolve: F) -> Result<Vec<u8>>
+where
+  F: Fn(&str) -> Option<u32>,
+{
+  let mut bytes = Vec::new();
+  for line in source.split(['\n', ';']) {
+    // strip comments
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() {
+      continue;
+    }
+    let pc = address + bytes.len() as u32;
+    let instruction = assemble_instruction(line, pc, &resolve)
+      .map_err(|e| anyhow::anyhow!("Failed to assemble \"{}\": {}", line, e))?;
+    bytes.extend_from_slice(&instruction.to_be_bytes());
+  }
+  Ok(bytes)
+}
+
+pub fn build_lis(register: u32, immediate: u16) -> u32 {
+  build_d(15, register, 0, immediate as u32)
+}
+
+pub fn build_addi(register_dst: u32, register_src: u32, immediate: u16) -> u32 {
+  build_d(14, register_dst, register_src, immediate as u32)
+}
+
+/// Build a relative `b`/`bl` from `addr` to `target`, which must be within ±32 MiB
+pub fn build_b_rel24(addr: u32, target: u32, link: bool) -> Result<u32> {
+  if !branch_in_range(addr, target) {
+    return Err(anyhow::anyhow!("Branch from 0x{:08X} to 0x{:08X} is out of range", addr, target));
+  }
+  let rel = (target.wrapping_sub(addr)) & 0x03FF_FFFC;
+  let op = if link { 0x4800_0001 } else { 0x4800_0000 };
+  Ok(op | rel)
+}
+
+/// Whether a relative `b`/`bl` at `addr` can reach `target`
+pub fn branch_in_range(addr: u32, target: u32) -> bool {
+  let rel = target.wrapping_sub(addr) as i32;
+  rel & 3 == 0 && (-0x0200_0000..0x0200_0000).contains(&rel)
+}
+
+fn build_d(op: u32, rt: u32, ra: u32, imm: u32) -> u32 {
+  (op << 26) | (rt << 21) | (ra << 16) | (imm & 0xFFFF)
+}
+
+fn build_x(op: u32, rt: u32, ra: u32, rb: u32, xo: u32, rc: bool) -> u32 {
+  (op << 26) | (rt << 21) | (ra << 16) | (rb << 11) | (xo << 1) | rc as u32
+}
+
+fn build_m(op: u32, rs: u32, ra: u32, sh: u32, mb: u32, me: u32, rc: bool) -> u32 {
+  (op << 26) | (rs << 21) | (ra << 16) | (sh << 11) | (mb << 6) | (me << 1) | rc as u32
+}
+
+fn build_bc(bo: u32, bi: u32, rel: u32, link: bool) -> u32 {
+  (16 << 26) | (bo << 21) | (bi << 16) | (rel & 0xFFFC) | link as u32
+}
+
+fn build_spr(xo: u32, rt: u32, spr: u32) -> u32 {
+  // the two halves of the SPR number are swapped in the encoding
+  let spr = ((spr & 0x1F) << 5) | (spr >> 5);
+  (31 << 26) | (rt << 21) | (spr << 11) | (xo << 1)
+}
+
+struct Operands<'a, F> {
+  operands: Vec<&'a str>,
+  resolve: &'a F,
+}
+
+impl<'a, F> Operands<'a, F>
+where
+  F: Fn(&str) -> Option<u32>,
+{
+  fn expect_count(&self, counts: &[usize]) -> Result<()> {
+    if counts.contains(&self.operands.len()) {
+      Ok(())
+    } else {
+      Err(anyhow::anyhow!("expected {:?} operands, got {}", counts, self.operands.len()))
+    }
+  }
+
+  fn get(&self, index: usize) -> Result<&'a str> {
+    self.operands.get(index).copied()
+      .ok_or_else(|| anyhow::anyhow!("missing operand {}", index + 1))
+  }
+
+  fn gpr(&self, index: usize) -> Result<u32> {
+    let operand = self.get(index)?;
+    match operand {
+      "sp" => return Ok(1),
+      "rtoc" => return Ok(2),
+      _ => {}
+    }
+    parse_register(operand.strip_prefix('r').unwrap_or(operand), 31)
+      .ok_or_else(|| anyhow::anyhow!("invalid register {}", operand))
+  }
+
+  fn fpr(&self, index: usize) -> Result<u32> {
+    let operand = self.get(index)?;
+    parse_register(operand.strip_prefix('f').unwrap_or(operand), 31)
+      .ok_or_else(|| anyhow::anyhow!("invalid float register {}", operand))
+  }
+
+  fn cr(&self, index: usize) -> Result<u32> {
+    let operand = self.get(index)?;
+    parse_register(operand.strip_prefix("cr").unwrap_or(operand), 7)
+      .ok_or_else(|| anyhow::anyhow!("invalid condition register {}", operand))
+  }
+
+  /// A number, symbol, or either with an `@ha`, `@h` or `@l` suffix
+  fn value(&self, index: usize) -> Result<u32> {
+    let operand = self.get(index)?;
+    let (base, suffix) = match operand.split_once('@') {
+      Some((base, suffix)) => (base, Some(suffix)),
+      None => (operand, None),
+    };
+    let value = parse_int(base)
+      .or_else(|| (self.resolve)(base))
+      .ok_or_else(|| anyhow::anyhow!("unknown symbol or invalid number {}", base))?;
+    match suffix {
+      None => Ok(value),
+      Some("ha") => Ok(value.wrapping_add(0x8000) >> 16),
+      Some("h") => Ok(value >> 16),
+      Some("l") => Ok(value & 0xFFFF),
+      Some(suffix) => Err(anyhow::anyhow!("unknown suffix @{}", suffix)),
+    }
+  }
+
+  fn simm(&self, index: usize) -> Result<u32> {
+    let value = self.value(index)?;
+    // allow both signed and unsigned spellings of a 16 bit immediate
+    if (value as i32) < -0x8000 || (value as i32 > 0xFFFF) {
+      return Err(anyhow::anyhow!("immediate {} out of range", value as i32));
+    }
+    Ok(value & 0xFFFF)
+  }
+
+  fn uimm(&self, index: usize) -> Result<u32> {
+    let value = self.value(index)?;
+    if value > 0xFFFF {
+      return Err(anyhow::anyhow!("immediate 0x{:X} out of range", value));
+    }
+    Ok(value)
+  }
+
+  fn small(&self, index: usize, max: u32) -> Result<u32> {
+    let value = self.value(index)?;
+    if value > max {
+      return Err(anyhow::anyhow!("value {} out of range 0-{}", value, max));
+    }
+    Ok(value)
+  }
+
+  /// `d(rA)`
+  fn memory(&self, index: usize) -> Result<(u32, u32)> {
+    let operand = self.get(index)?;
+    let (offset, register) = operand.strip_suffix(')')
+      .and_then(|o| o.split_once('('))
+      .ok_or_else(|| anyhow::anyhow!("expected d(rA), got {}", operand))?;
+    let nested = Operands { operands: vec![offset, register], resolve: self.resolve };
+    let offset = if offset.is_empty() { 0 } else { nested.simm(0)? };
+    Ok((offset, nested.gpr(1)?))
+  }
+}
+
+fn parse_register(value: &str, max: u32) -> Option<u32> {
+  value.parse::<u32>().ok().filter(|r| *r <= max)
+}
+
+fn parse_int(value: &str) -> Option<u32> {
+  let (negative, value) = match value.strip_prefix('-') {
+    Some(value) => (true, value),
+    None => (false, value),
+  };
+  let parsed = if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+    u32::from_str_radix(hex, 16).ok()?
+  } else {
+    value.parse::<u32>().ok()?
+  };
+  Some(if negative { parsed.wrapping_neg() } else { parsed })
+}
+
+/// BO and BI fields of the simplified conditional branch mnemonics
+fn condition(name: &str) -> Option<(u32, u32)> {
+  Some(match name {
+    "lt" => (12, 0),
+    "gt" => (12, 1),
+    "eq" => (12, 2),
+    "so" => (12, 3),
+    "ge" => (4, 0),
+    "le" => (4, 1),
+    "ne" => (4, 2),
+    "ns" => (4, 3),
+    "dnz" => (16, 0),
+    "dz" => (18, 0),
+    _ => return None,
+  })
+}
+
+/// Mnemonics that may take a `.` suffix to set cr0 (cr1 for floating point)
+const RECORD_FORMS: &[&str] = &[
+  "addic", "andi", "andis",
+  "add", "subf", "mullw", "divw", "divwu", "addc", "subfc", "adde", "subfe", "sub", "neg", "addze",
+  "and", "or", "xor", "nor", "andc", "orc", "slw", "srw", "sraw", "mr", "not", "srawi", "extsb", "extsh", "cntlzw",
+  "rlwinm", "rlwimi", "rlwnm", "slwi", "srwi", "clrlwi", "clrrwi", "rotlwi",
+  "fadd", "fadds", "fsub", "fsubs", "fdiv", "fdivs", "fmul", "fmuls", "fmr", "fneg", "fabs", "frsp", "fctiwz",
+];
+
+pub fn assemble_instruction<F>(line: &str, pc: u32, resolve: &F) -> Result<u32>
+where
+  F: Fn(&str) -> Option<u32>,
+{
+  let (mnemonic, rest) = match line.split_once(char::is_whitespace) {
+    Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+    None => (line, ""),
+  };
+  let mnemonic = mnemonic.to_lowercase();
+  let operands = Operands {
+    operands: if rest.is_empty() { Vec::new() } else { rest.split(',').map(|o| o.trim()).collect() },
+    resolve,
+  };
+  let o = &operands;
+
+  let branch_rel = |target: u32, bits: u32| -> Result<u32> {
+    let rel = target.wrapping_sub(pc) as i32;
+    let limit = 1i32 << (bits - 1);
+    if rel & 3 != 0 || rel < -limit || rel >= limit {
+      return Err(anyhow::anyhow!("branch target 0x{:08X} out of range from 0x{:08X}", target, pc));
+    }
+    Ok(rel as u32)
+  };
+
+  // record forms set cr0
+  let (base, rc) = match mnemonic.strip_suffix('.') {
+    Some(base) => (base, true),
+    None => (mnemonic.as_str(), false),
+  };
+  if rc && !RECORD_FORMS.contains(&base) {
+    return Err(anyhow::anyhow!("{} has no record form", base));
+  }
+
+  let instruction = match base {
+    "nop" => 0x6000_0000,
+    "blr" => 0x4E80_0020,
+    "blrl" => 0x4E80_0021,
+    "bctr" => 0x4E80_0420,
+    "bctrl" => 0x4E80_0421,
+    "rfi" => 0x4C00_0064,
+    "sync" => 0x7C00_04AC,
+    "isync" => 0x4C00_012C,
+    "trap" => 0x7FE0_0008,
+    ".long" | ".word" => o.value(0)?,
+
+    "b" | "bl" | "ba" | "bla" => {
+      o.expect_count(&[1])?;
+      let target = o.value(0)?;
+      let absolute = base.ends_with('a');
+      let link = base == "bl" || base == "bla";
+      let field = if absolute {
+        if target > 0x01FF_FFFF && target < 0xFE00_0000 {
+          return Err(anyhow::anyhow!("absolute branch target 0x{:08X} out of range", target));
+        }
+        target
+      } else {
+        branch_rel(target, 26)?
+      };
+      (18 << 26) | (field & 0x03FF_FFFC) | ((absolute as u32) << 1) | link as u32
+    }
+    "bc" | "bcl" => {
+      o.expect_count(&[3])?;
+      let rel = branch_rel(o.value(2)?, 16)?;
+      build_bc(o.small(0, 31)?, o.small(1, 31)?, rel, base == "bcl")
+    }
+
+    "li" => { o.expect_count(&[2])?; build_d(14, o.gpr(0)?, 0, o.simm(1)?) }
+    "lis" => { o.expect_count(&[2])?; build_d(15, o.gpr(0)?, 0, o.simm(1)?) }
+    "addi" => { o.expect_count(&[3])?; build_d(14, o.gpr(0)?, o.gpr(1)?, o.simm(2)?) }
+    "addis" => { o.expect_count(&[3])?; build_d(15, o.gpr(0)?, o.gpr(1)?, o.simm(2)?) }
+    "subi" => { o.expect_count(&[3])?; build_d(14, o.gpr(0)?, o.gpr(1)?, o.value(2)?.wrapping_neg() & 0xFFFF) }
+    "addic" => { o.expect_count(&[3])?; build_d(if rc { 13 } else { 12 }, o.gpr(0)?, o.gpr(1)?, o.simm(2)?) }
+    "mulli" => { o.expect_count(&[3])?; build_d(7, o.gpr(0)?, o.gpr(1)?, o.simm(2)?) }
+    "subfic" => { o.expect_count(&[3])?; build_d(8, o.gpr(0)?, o.gpr(1)?, o.simm(2)?) }
+    // logical immediates take rA, rS but encode rS first
+    "ori" | "oris" | "xori" | "xoris" => {
+      o.expect_count(&[3])?;
+      let op = match base { "ori" => 24, "oris" => 25, "xori" => 26, _ => 27 };
+      build_d(op, o.gpr(1)?, o.gpr(0)?, o.uimm(2)?)
+    }
+    "andi" | "andis" if rc => {
+      o.expect_count(&[3])?;
+      build_d(if base == "andi" { 28 } else { 29 }, o.gpr(1)?, o.gpr(0)?, o.uimm(2)?)
+    }
+
+    "cmpwi" | "cmplwi" | "cmpw" | "cmplw" => {
+      o.expect_count(&[2, 3])?;
+      let (crf, first) = if o.operands.len() == 3 { (o.cr(0)?, 1) } else { (0, 0) };
+      let ra = o.gpr(first)?;
+      match base {
+        "cmpwi" => build_d(11, crf << 2, ra, o.simm(first + 1)?),
+        "cmplwi" => build_d(10, crf << 2, ra, o.uimm(first + 1)?),
+        "cmpw" => build_x(31, crf << 2, ra, o.gpr(first + 1)?, 0, false),
+        _ => build_x(31, crf << 2, ra, o.gpr(first + 1)?, 32, false),
+      }
+    }
+
+    "add" | "subf" | "mullw" | "divw" | "divwu" | "addc" | "subfc" | "adde" | "subfe" => {
+      o.expect_count(&[3])?;
+      let xo = match base {
+        "add" => 266, "subf" => 40, "mullw" => 235, "divw" => 491, "divwu" => 459,
+        "addc" => 10, "subfc" => 8, "adde" => 138, _ => 136,
+      };
+      build_x(31, o.gpr(0)?, o.gpr(1)?, o.gpr(2)?, xo, rc)
+    }
+    "sub" => { o.expect_count(&[3])?; build_x(31, o.gpr(0)?, o.gpr(2)?, o.gpr(1)?, 40, rc) }
+    "neg" => { o.expect_count(&[2])?; build_x(31, o.gpr(0)?, o.gpr(1)?, 0, 104, rc) }
+    "addze" => { o.expect_count(&[2])?; build_x(31, o.gpr(0)?, o.gpr(1)?, 0, 202, rc) }
+    // logical ops take rA, rS, rB but encode rS first
+    "and" | "or" | "xor" | "nor" | "andc" | "orc" | "slw" | "srw" | "sraw" => {
+      o.expect_count(&[3])?;
+      let xo = match base {
+        "and" => 28, "or" => 444, "xor" => 316, "nor" => 124, "andc" => 60,
+        "orc" => 412, "slw" => 24, "srw" => 536, _ => 792,
+      };
+      build_x(31, o.gpr(1)?, o.gpr(0)?, o.gpr(2)?, xo, rc)
+    }
+    "mr" => { o.expect_count(&[2])?; let rs = o.gpr(1)?; build_x(31, rs, o.gpr(0)?, rs, 444, rc) }
+    "not" => { o.expect_count(&[2])?; let rs = o.gpr(1)?; build_x(31, rs, o.gpr(0)?, rs, 124, rc) }
+    "srawi" => { o.expect_count(&[3])?; build_x(31, o.gpr(1)?, o.gpr(0)?, o.small(2, 31)?, 824, rc) }
+    "extsb" | "extsh" | "cntlzw" => {
+      o.expect_count(&[2])?;
+      let xo = match base { "extsb" => 954, "extsh" => 922, _ => 26 };
+      build_x(31, o.gpr(1)?, o.gpr(0)?, 0, xo, rc)
+    }
+
+    "rlwinm" | "rlwimi" => {
+      o.expect_count(&[5])?;
+      let op = if base == "rlwinm" { 21 } else { 20 };
+      build_m(op, o.gpr(1)?, o.gpr(0)?, o.small(2, 31)?, o.small(3, 31)?, o.small(4, 31)?, rc)
+    }
+    "rlwnm" => {
+      o.expect_count(&[5])?;
+      build_m(23, o.gpr(1)?, o.gpr(0)?, o.gpr(2)?, o.small(3, 31)?, o.small(4, 31)?, rc)
+    }
+    "slwi" | "srwi" | "clrlwi" | "clrrwi" | "rotlwi" => {
+      o.expect_count(&[3])?;
+      let n = o.small(2, 31)?;
+      let (sh, mb, me) = match base {
+        "slwi" => (n, 0, 31 - n),
+        "srwi" => ((32 - n) % 32, n, 31),
+        "clrlwi" => (0, n, 31),
+        "clrrwi" => (0, 0, 31 - n),
+        _ => (n, 0, 31),
+      };
+      build_m(21, o.gpr(1)?, o.gpr(0)?, sh, mb, me, rc)
+    }
+
+    "lwz" | "lwzu" | "lbz" | "lbzu" | "lhz" | "lhzu" | "lha" | "lhau" | "stw" | "stwu" | "stb" | "stbu"
+    | "sth" | "sthu" | "lmw" | "stmw" => {
+      o.expect_count(&[2])?;
+      let op = match base {
+        "lwz" => 32, "lwzu" => 33, "lbz" => 34, "lbzu" => 35, "stw" => 36, "stwu" => 37,
+        "stb" => 38, "stbu" => 39, "lhz" => 40, "lhzu" => 41, "lha" => 42, "lhau" => 43,
+        "sth" => 44, "sthu" => 45, "lmw" => 46, _ => 47,
+      };
+      let (offset, ra) = o.memory(1)?;
+      build_d(op, o.gpr(0)?, ra, offset)
+    }
+    "lfs" | "lfsu" | "lfd" | "lfdu" | "stfs" | "stfsu" | "stfd" | "stfdu" => {
+      o.expect_count(&[2])?;
+      let op = match base {
+        "lfs" => 48, "lfsu" => 49, "lfd" => 50, "lfdu" => 51,
+        "stfs" => 52, "stfsu" => 53, "stfd" => 54, _ => 55,
+      };
+      let (offset, ra) = o.memory(1)?;
+      build_d(op, o.fpr(0)?, ra, offset)
+    }
+    "lwzx" | "lbzx" | "lhzx" | "lhax" | "stwx" | "stbx" | "sthx" => {
+      o.expect_count(&[3])?;
+      let xo = match base {
+        "lwzx" => 23, "lbzx" => 87, "lhzx" => 279, "lhax" => 343, "stwx" => 151, "stbx" => 215, _ => 407,
+      };
+      build_x(31, o.gpr(0)?, o.gpr(1)?, o.gpr(2)?, xo, false)
+    }
+    "lfsx" | "lfdx" | "stfsx" | "stfdx" => {
+      o.expect_count(&[3])?;
+      let xo = match base { "lfsx" => 535, "lfdx" => 599, "stfsx" => 663, _ => 727 };
+      build_x(31, o.fpr(0)?, o.gpr(1)?, o.gpr(2)?, xo, false)
+    }
+
+    "mflr" => { o.expect_count(&[1])?; build_spr(339, o.gpr(0)?, 8) }
+    "mfctr" => { o.expect_count(&[1])?; build_spr(339, o.gpr(0)?, 9) }
+    "mtlr" => { o.expect_count(&[1])?; build_spr(467, o.gpr(0)?, 8) }
+    "mtctr" => { o.expect_count(&[1])?; build_spr(467, o.gpr(0)?, 9) }
+    "mfspr" => { o.expect_count(&[2])?; build_spr(339, o.gpr(0)?, o.small(1, 1023)?) }
+    "mtspr" => { o.expect_count(&[2])?; build_spr(467, o.gpr(1)?, o.small(0, 1023)?) }
+    "mfcr" => { o.expect_count(&[1])?; build_x(31, o.gpr(0)?, 0, 0, 19, false) }
+    "mtcr" => { o.expect_count(&[1])?; (31 << 26) | (o.gpr(0)? << 21) | (0xFF << 12) | (144 << 1) }
+    "mtcrf" => { o.expect_count(&[2])?; (31 << 26) | (o.gpr(1)? << 21) | (o.small(0, 0xFF)? << 12) | (144 << 1) }
+
+    "fadd" | "fadds" | "fsub" | "fsubs" | "fdiv" | "fdivs" => {
+      o.expect_count(&[3])?;
+      let op = if base.ends_with('s') { 59 } else { 63 };
+      let xo = match base.trim_end_matches('s') { "fadd" => 21, "fsub" => 20, _ => 18 };
+      build_x(op, o.fpr(0)?, o.fpr(1)?, o.fpr(2)?, xo, rc)
+    }
+    "fmul" | "fmuls" => {
+      o.expect_count(&[3])?;
+      let op = if base == "fmuls" { 59 } else { 63 };
+      (op << 26) | (o.fpr(0)? << 21) | (o.fpr(1)? << 16) | (o.fpr(2)? << 6) | (25 << 1) | rc as u32
+    }
+    "fmr" | "fneg" | "fabs" | "frsp" | "fctiwz" => {
+      o.expect_count(&[2])?;
+      let xo = match base { "fmr" => 72, "fneg" => 40, "fabs" => 264, "frsp" => 12, _ => 15 };
+      build_x(63, o.fpr(0)?, 0, o.fpr(1)?, xo, rc)
+    }
+    "fcmpu" | "fcmpo" => {
+      o.expect_count(&[3])?;
+      build_x(63, o.cr(0)? << 2, o.fpr(1)?, o.fpr(2)?, if base == "fcmpu" { 0 } else { 32 }, false)
+    }
+
+    _ => {
+      // simplified conditional branches: beq, bnelr, bdnzl, bgectr, ...
+      let (cond_name, link) = match base.strip_suffix('l') {
+        Some(stripped) if condition(stripped.trim_start_matches('b')).is_some()
+          || stripped.ends_with("lr") || stripped.ends_with("ctr") => (stripped, true),
+        _ => (base, false),
+      };
+      let (cond_name, target_register) = if let Some(c) = cond_name.strip_suffix("lr") {
+        (c, Some(16))
+      } else if let Some(c) = cond_name.strip_suffix("ctr") {
+        (c, Some(528))
+      } else {
+        (cond_name, None)
+      };
+      let (bo, bit) = cond_name.strip_prefix('b')
+        .and_then(condition)
+        .ok_or_else(|| anyhow::anyhow!("unknown instruction {}", mnemonic))?;
+      // the condition register field is optional
+      let (crf, first) = if o.operands.first().is_some_and(|op| op.starts_with("cr")) {
+        (o.cr(0)?, 1)
+      } else {
+        (0, 0)
+      };
+      let bi = if bo >= 16 { 0 } else { crf * 4 + bit };
+      match target_register {
+        Some(xo) => {
+          o.expect_count(&[first])?;
+          (19 << 26) | (bo << 21) | (bi << 16) | (xo << 1) | link as u32
+        }
+        None => {
+          o.expect_count(&[first + 1])?;
+          let rel = branch_rel(o.value(first)?, 16)?;
+          build_bc(bo, bi, rel, link)
+        }
+      }
+    }
+  };
+  Ok(instruction)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn symbols(name: &str) -> Option<u32> {
+    match name {
+      "target" => Some(0x8000_3100),
+      "data" => Some(0x8041_8000),
+      _ => None,
+    }
+  }
+
+  fn asm(line: &str, pc: u32) -> Result<u32> {
+    assemble_instruction(line, pc, &symbols)
+  }
+
+  #[test]
+  fn load_immediates() {
+    assert_eq!(asm("li r3, 1", 0).unwrap(), 0x3860_0001);
+    assert_eq!(asm("li r3, -1", 0).unwrap(), 0x3860_FFFF);
+    assert_eq!(asm("li r0, 0x7FFF", 0).unwrap(), 0x3800_7FFF);
+    assert_eq!(asm("lis r3, 0x8000", 0).unwrap(), 0x3C60_8000);
+    assert_eq!(asm("lis r4, data@ha", 0).unwrap(), 0x3C80_8042);
+    assert_eq!(asm("addi r4, r4, data@l", 0).unwrap(), 0x3884_8000);
+    assert!(asm("li r3, 0x10000", 0).is_err());
+    assert!(asm("li r32, 1", 0).is_err());
+  }
+
+  #[test]
+  fn branches_to_symbols() {
+    assert_eq!(asm("bl target", 0x8000_3000).unwrap(), 0x4800_0101);
+    assert_eq!(asm("b target", 0x8000_3000).unwrap(), 0x4800_0100);
+    assert_eq!(asm("bl target", 0x8000_4100).unwrap(), 0x4BFF_F001);
+    assert_eq!(asm("bl 0x80003100", 0x8000_3000).unwrap(), 0x4800_0101);
+    assert!(asm("bl target", 0x8300_0000).is_err());
+    assert!(asm("bl unknown", 0x8000_3000).is_err());
+  }
+
+  #[test]
+  fn conditional_branches() {
+    assert_eq!(asm("beq 0x80003010", 0x8000_3000).unwrap(), 0x4182_0010);
+    assert_eq!(asm("bc 12, 2, 0x80003010", 0x8000_3000).unwrap(), 0x4182_0010);
+    assert_eq!(asm("bne cr1, 0x80002FF8", 0x8000_3000).unwrap(), 0x4086_FFF8);
+    assert_eq!(asm("bdnz 0x80002FF0", 0x8000_3000).unwrap(), 0x4200_FFF0);
+    assert_eq!(asm("beqlr", 0).unwrap(), 0x4D82_0020);
+    assert!(asm("beq 0x80003002", 0x8000_3000).is_err());
+    assert!(asm("beq 0x8000B000", 0x8000_3000).is_err());
+  }
+
+  #[test]
+  fn stores_with_displacement() {
+    assert_eq!(asm("stw r0, 4(r1)", 0).unwrap(), 0x9001_0004);
+    assert_eq!(asm("stw r31, 0x1C(sp)", 0).unwrap(), 0x93E1_001C);
+    assert_eq!(asm("stwu r1, -16(r1)", 0).unwrap(), 0x9421_FFF0);
+    assert_eq!(asm("lwz r3, 0(r3)", 0).unwrap(), 0x8063_0000);
+    assert_eq!(asm("stfs f1, 8(r31)", 0).unwrap(), 0xD03F_0008);
+    assert!(asm("stw r0, r1", 0).is_err());
+  }
+
+  #[test]
+  fn record_forms() {
+    assert_eq!(asm("or. r3, r4, r5", 0).unwrap(), 0x7C83_2B79);
+    assert_eq!(asm("rlwinm. r3, r4, 0, 31, 31", 0).unwrap(), 0x5483_07FF);
+    assert_eq!(asm("addic. r3, r3, -1", 0).unwrap(), 0x3463_FFFF);
+    assert_eq!(asm("andi. r3, r4, 1", 0).unwrap(), 0x7083_0001);
+    assert!(asm("andi r3, r4, 1", 0).is_err());
+    for line in ["li. r3, 1", "lwz. r3, 0(r1)", "cmpwi. r3, 0", "b. target", "blr.", "mtctr. r3", "fcmpu. cr0, f1, f2"] {
+      assert_eq!(asm(line, 0).unwrap_err().to_string(), format!("{} has no record form", line.split('.').next().unwrap()));
+    }
+  }
+
+  #[test]
+  fn assembles_sequences() {
+    let bytes = assemble("mflr r0; stw r0, 4(r1) # save lr\nbl target\nmtlr r0; blr", 0x8000_3000, symbols).unwrap();
+    let expected: Vec<u8> = [0x7C08_02A6u32, 0x9001_0004, 0x4800_00F9, 0x7C08_03A6, 0x4E80_0020]
+      .iter()
+      .flat_map(|word| word.to_be_bytes())
+      .collect();
+    assert_eq!(bytes, expected);
+    assert!(assemble("nop\nfrobnicate r3", 0, symbols).unwrap_err().to_string().contains("frobnicate"));
+  }
+}
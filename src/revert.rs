@@ -0,0 +1,418 @@
+//! Turns patched DOLs and ISOs back into the originals, using the revert record the patcher embeds
+use crate::binstream::{BinStreamRead, BinStreamReadable, BinStreamWritable, BinStreamWrite};
+use crate::error::PatchError;
+use crate::embedded::{read_embedded, EmbeddedData, PatchSignature, TAG_REVERT_DOL, TAG_REVERT_ISO, TAG_SIGNATURE};
+use crate::gcdisc::{FSTEntry, GCDiscHeader, FST};
+use crate::progress::Progress;
+use anyhow::Result;
+use log::info;
+use md5::Digest;
+use std::fs;
+use std::ops::Range;
+use std::io::{Cursor, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Differences closer than this are stored as one chunk
+const MERGE_GAP: usize = 16;
+
+/// Everything needed to rebuild an original file from the patched one
+#[derive(Debug, Clone)]
+pub struct RevertRecord {
+  pub original_size: u32,
+  /// md5 of the original file, if the patcher knew it
+  pub original_hash: Option<String>,
+  pub chunks: Vec<RevertChunk>,
+}
+
+#[derive(Debug, Clone)]
+pub enum RevertChunk {
+  /// Original bytes at `offset`
+  Bytes { offset: u32, data: Vec<u8> },
+  /// `length` bytes at `offset` that were all `value`
+  Fill { offset: u32, length: u32, value: u8 },
+  /// Original bytes at `offset` that the patcher copied to `from`, after the end of the original
+  Moved { offset: u32, length: u32, from: u32 },
+}
+
+impl RevertChunk {
+  /// Record the original bytes of a range, as a fill when they are all the same
+  pub fn capture(original: &[u8], offset: u32, length: u32) -> RevertChunk {
+    let data = &original[offset as usize..(offset + length) as usize];
+    match data.first() {
+      Some(&value) if data.iter().all(|&b| b == value) => RevertChunk::Fill { offset, length, value },
+      _ => RevertChunk::Bytes { offset, data: data.to_vec() },
+    }
+  }
+
+  /// Restore the range in `bytes`, `patched` is the file the record came from
+  fn apply(&self, patched: &[u8], bytes: &mut [u8]) {
+    match self {
+      RevertChunk::Bytes { offset, data } => {
+        bytes[*offset as usize..*offset as usize + data.len()].copy_from_slice(data);
+      }
+      RevertChunk::Fill { offset, length, value } => {
+        bytes[*offset as usize..(*offset + *length) as usize].fill(*value);
+      }
+      RevertChunk::Moved { offset, length, from } => {
+        bytes[*offset as usize..(*offset + *length) as usize]
+          .copy_from_slice(&patched[*from as usize..(*from + *length) as usize]);
+      }
+    }
+  }
+}
+
+impl RevertRecord {
+  /// Record every byte of `original` that is different in `patched`.
+  /// Data appended after the end of the original is dropped when reverting.
+  pub fn diff(original: &[u8], patched: &[u8], original_hash: Option<String>) -> RevertRecord {
+    let chunks = changed_ranges(original, patched, MERGE_GAP)
+      .into_iter()
+      .map(|range| RevertChunk::Bytes { offset: range.start as u32, data: original[range].to_vec() })
+      .collect();
+    RevertRecord {
+      original_size: original.len() as u32,
+      original_hash,
+      chunks,
+    }
+  }
+
+  /// Restore a patched file in memory
+  pub fn apply(&self, bytes: &mut Vec<u8>) {
+    let patched = bytes.clone();
+    bytes.resize(self.original_size as usize, 0);
+    self.apply_chunks(&patched, bytes);
+  }
+
+  /// Restore the recorded ranges of `bytes`, which starts out as a copy of `patched`
+  pub fn apply_chunks(&self, patched: &[u8], bytes: &mut [u8]) {
+    for chunk in &self.chunks {
+      chunk.apply(patched, bytes);
+    }
+  }
+
+  pub fn to_bytes(&self) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    self.write_to_stream(&mut Cursor::new(&mut bytes))?;
+    Ok(bytes)
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<RevertRecord> {
+    RevertRecord::read_from_stream(&mut Cursor::new(bytes))
+      .map_err(|e| anyhow::anyhow!("Failed to read revert record: {}", e))
+  }
+}
+
+impl BinStreamReadable for RevertRecord {
+  fn read_from_stream<T: BinStreamRead>(stream: &mut T) -> std::io::Result<Self> {
+    let original_size = stream.read_u32()?;
+    let hash_len = stream.read_u8()?;
+    let hash = read_exact_bytes(stream, hash_len as usize)?;
+    let original_hash = (hash_len > 0).then(|| String::from_utf8_lossy(&hash).to_string());
+    let count = stream.read_u32()?;
+    let mut chunks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+      let kind = stream.read_u8()?;
+      let offset = stream.read_u32()?;
+      let length = stream.read_u32()?;
+      chunks.push(match kind {
+        0 => RevertChunk::Bytes { offset, data: read_exact_bytes(stream, length as usize)? },
+        1 => RevertChunk::Fill { offset, length, value: stream.read_u8()? },
+        2 => RevertChunk::Moved { offset, length, from: stream.read_u32()? },
+        _ => return Err(std::io::Error::other(format!("Unknown revert chunk kind {}", kind))),
+      });
+    }
+    Ok(RevertRecord { original_size, original_hash, chunks })
+  }
+}
+
+impl BinStreamWritable for RevertRecord {
+  fn write_to_stream<T: BinStreamWrite>(&self, stream: &mut T) -> std::io::Result<()> {
+    stream.write_u32(self.original_size)?;
+    let hash = self.original_hash.as_deref().unwrap_or_default();
+    stream.write_u8(hash.len() as u8)?;
+    stream.write_string(hash)?;
+    stream.write_u32(self.chunks.len() as u32)?;
+    for chunk in &self.chunks {
+      match chunk {
+        RevertChunk::Bytes { offset, data } => {
+          stream.write_u8(0)?;
+          stream.write_u32(*offset)?;
+          stream.write_u32(data.len() as u32)?;
+          stream.write_all(data)?;
+        }
+        RevertChunk::Fill { offset, length, value } => {
+          stream.write_u8(1)?;
+          stream.write_u32(*offset)?;
+          stream.write_u32(*length)?;
+          stream.write_u8(*value)?;
+        }
+        RevertChunk::Moved { offset, length, from } => {
+          stream.write_u8(2)?;
+          stream.write_u32(*offset)?;
+          stream.write_u32(*length)?;
+          stream.write_u32(*from)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+fn read_exact_bytes<T: BinStreamRead>(stream: &mut T, len: usize) -> std::io::Result<Vec<u8>> {
+  let mut buf = vec![0u8; len];
+  stream.read_exact(&mut buf)?;
+  Ok(buf)
+}
+
+/// Ranges of `patched` that differ from `original`, up to the end of the shorter one.
+/// Differences at most `merge_gap` bytes apart end up in the same range.
+pub fn changed_ranges(original: &[u8], patched: &[u8], merge_gap: usize) -> Vec<Range<usize>> {
+  let mut ranges = Vec::new();
+  let len = original.len().min(patched.len());
+  let mut i = 0;
+  while i < len {
+    if original[i] == patched[i] {
+      i += 1;
+      continue;
+    }
+    let start = i;
+    let mut end = i + 1;
+    while end < len {
+      let window = end..len.min(end + merge_gap + 1);
+      if original[window.clone()] != patched[window] {
+        end += 1;
+      } else {
+        break;
+      }
+    }
+    ranges.push(start..end);
+    i = end;
+  }
+  ranges
+}
+
+/// `game.iso` -> `game_original.iso`
+pub fn default_revert_path(in_path: &Path) -> PathBuf {
+  let stem = in_path.file_stem().unwrap_or_default().to_string_lossy();
+  let mut file_name = format!("{}_original", stem);
+  if let Some(ext) = in_path.extension() {
+    file_name.push('.');
+    file_name.push_str(&ext.to_string_lossy());
+  }
+  in_path.with_file_name(file_name)
+}
+
+/// Rebuild the original DOL or ISO from a patched one
+pub fn revert_file<F>(
+  progress_update: F,
+  in_path: &PathBuf,
+  out_path: &PathBuf,
+  overwrite: bool,
+) -> Result<()> where
+  F: Fn(Progress),
+{
+  if !overwrite && out_path.exists() {
+    return Err(PatchError::OutputExists { path: out_path.to_path_buf() }.into());
+  }
+  let ext = in_path.extension()
+    .and_then(|s| s.to_str())
+    .map(|s| s.to_lowercase());
+  match ext.as_deref() {
+    Some("dol") => revert_dol_file(progress_update, in_path, out_path),
+    Some("iso") | Some("gcm") => revert_iso_file(progress_update, in_path, out_path),
+    _ => Err(anyhow::anyhow!("Unsupported file type: {:?}", ext)),
+  }
+}
+
+fn revert_dol_file<F>(progress_update: F, in_path: &PathBuf, out_path: &PathBuf) -> Result<()> where
+  F: Fn(Progress),
+{
+  progress_update(Progress::new(0, 2, "Reading DOL".to_string()));
+  info!("Reading DOL file from {:?}", in_path);
+  let mut dol_bytes = fs::read(in_path)?;
+
+  progress_update(Progress::new(1, 2, "Reverting DOL".to_string()));
+  revert_dol_bytes(&mut dol_bytes)?;
+
+  info!("Writing original DOL file to {:?}", out_path);
+  fs::write(out_path, &dol_bytes)?;
+  progress_update(Progress::new(2, 2, "Done reverting DOL".to_string()));
+  Ok(())
+}
+
+pub fn revert_iso_file<F>(progress_update: F, in_path: &PathBuf, out_path: &PathBuf) -> Result<()> where
+  F: Fn(Progress),
+{
+  info!("Reading patched ISO {:?}", in_path);
+  let input_file = fs::File::open(in_path)?;
+  let input_file_mmap = unsafe { memmap2::MmapOptions::new().map(&input_file)? };
+
+  let disc_header = GCDiscHeader::read_from_stream(&mut Cursor::new(&input_file_mmap[..]))?;
+  info!("Disk name: {}", disc_header.name_string());
+  let record = read_iso_revert_record(&input_file_mmap)?;
+  // files the patcher appended are dropped
+  if (record.original_size as usize) > input_file_mmap.len() {
+    return Err(anyhow::anyhow!(
+      "Patched ISO is {} bytes but the original was {} bytes",
+      input_file_mmap.len(),
+      record.original_size
+    ));
+  }
+
+  info!("Copying ISO...");
+  let output_file = fs::File::options()
+    .create(true).write(true).read(true).truncate(true)
+    .open(out_path)?;
+  output_file.set_len(record.original_size as u64)?;
+  let mut output_file_mmap = unsafe { memmap2::MmapOptions::new().map_mut(&output_file)? };
+  {
+    const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+    let length = record.original_size as u64;
+    let mut processed_bytes = 0;
+    progress_update(Progress::new(0, length, "Copying ISO".to_string()));
+    for (in_chunk, out_chunk) in input_file_mmap[..length as usize].chunks(CHUNK_SIZE).zip(output_file_mmap.chunks_mut(CHUNK_SIZE)) {
+      out_chunk.copy_from_slice(in_chunk);
+      processed_bytes += in_chunk.len() as u64;
+      progress_update(Progress::new(processed_bytes, length, "Copying ISO".to_string()));
+    }
+  }
+
+  info!("Restoring {} ranges of the original ISO", record.chunks.len());
+  record.apply_chunks(&input_file_mmap, &mut output_file_mmap);
+  output_file_mmap.flush()?;
+
+  if record.original_hash.is_some() {
+    info!("Verifying reverted ISO hash...");
+    progress_update(Progress::new(0, 0, "Hashing ISO".to_string()));
+  }
+  if let Err(e) = check_reverted_hash(&record, &output_file_mmap) {
+    // don't leave a file that looks like the original around
+    drop(output_file_mmap);
+    drop(output_file);
+    fs::remove_file(out_path)?;
+    return Err(e);
+  }
+  progress_update(Progress::new(0, 0, "Done reverting ISO".to_string()));
+  Ok(())
+}
+
+/// Turn a patched DOL back into the original in memory
+pub fn revert_dol_bytes(dol_bytes: &mut Vec<u8>) -> Result<()> {
+  let record = read_revert_record(dol_bytes, TAG_REVERT_DOL)?;
+  info!("Restoring {} ranges of the original DOL", record.chunks.len());
+  record.apply(dol_bytes);
+  check_reverted_hash(&record, dol_bytes)
+}
+
+/// The ISO revert record in the DOL of a patched ISO
+pub fn read_iso_revert_record(iso_bytes: &[u8]) -> Result<RevertRecord> {
+  let Some(dol_bytes) = find_patched_dol(iso_bytes)? else {
+    return Err(anyhow::anyhow!("The ISO's DOL is not a file in the FST, it was not patched by this patcher"));
+  };
+  read_revert_record(dol_bytes, TAG_REVERT_ISO)
+}
+
+/// The DOL of an ISO the patcher wrote a DOL into. Unpatched discs don't list their DOL in the FST.
+pub fn find_patched_dol(iso_bytes: &[u8]) -> Result<Option<&[u8]>> {
+  let mut reader = Cursor::new(iso_bytes);
+  let disc_header = GCDiscHeader::read_from_stream(&mut reader)?;
+  reader.seek(SeekFrom::Start(disc_header.fst_offset as u64))?;
+  let fst = FST::read_from_stream(&mut reader)?;
+  let dol_offset = disc_header.dol_offset;
+  Ok(find_file_at(&fst.root, dol_offset)
+    .map(|dol_length| &iso_bytes[dol_offset as usize..(dol_offset + dol_length) as usize]))
+}
+
+/// The error for inputs the patcher already patched. `embedded` is `None` for outputs of
+/// patcher versions that didn't embed any data, `revert_tag` is the record re-patching needs.
+pub fn already_patched_error(in_path: &Path, embedded: Option<&EmbeddedData>, revert_tag: [u8; 4]) -> anyhow::Error {
+  PatchError::AlreadyPatched {
+    path: in_path.to_path_buf(),
+    patched_with: embedded
+      .and_then(|embedded| embedded.get(TAG_SIGNATURE))
+      .and_then(|bytes| PatchSignature::from_bytes(bytes).ok())
+      .map(|signature| signature.describe()),
+    can_repatch: embedded.is_some_and(|embedded| embedded.get(revert_tag).is_some()),
+  }.into()
+}
+
+/// Describes what an already patched file was patched with
+pub fn describe_patched(embedded: &EmbeddedData) -> String {
+  embedded.get(TAG_SIGNATURE)
+    .and_then(|bytes| PatchSignature::from_bytes(bytes).ok())
+    .map(|signature| signature.describe())
+    .unwrap_or_else(|| "an unknown mod".to_string())
+}
+
+fn read_revert_record(dol_bytes: &[u8], tag: [u8; 4]) -> Result<RevertRecord> {
+  let Some(embedded) = read_embedded(dol_bytes)? else {
+    return Err(anyhow::anyhow!("No patcher data found, the file was not patched by this patcher"));
+  };
+  let Some(record_bytes) = embedded.get(tag) else {
+    if tag == TAG_REVERT_ISO && embedded.get(TAG_REVERT_DOL).is_some() {
+      return Err(anyhow::anyhow!("The ISO was rebuilt when patching, it can't be reverted"));
+    }
+    return Err(anyhow::anyhow!("The patched file has no revert record"));
+  };
+  RevertRecord::from_bytes(record_bytes)
+}
+
+/// Fails when the reverted file is known to differ from the original
+pub fn check_reverted_hash(record: &RevertRecord, bytes: &[u8]) -> Result<()> {
+  let Some(expected_hash) = &record.original_hash else {
+    info!("No original hash stored, skipping verification");
+    return Ok(());
+  };
+  let hash = format!("{:x}", md5::Md5::digest(bytes));
+  if &hash != expected_hash {
+    return Err(anyhow::anyhow!(
+      "Reverted file does not match the original. Expected hash: {}, Got: {}",
+      expected_hash,
+      hash
+    ));
+  }
+  info!("Reverted file matches the original hash {}", hash);
+  Ok(())
+}
+
+/// Length of the file stored at `offset`
+fn find_file_at(entry: &FSTEntry, offset: u32) -> Option<u32> {
+  match entry {
+    FSTEntry::File { offset: file_offset, length, .. } => (*file_offset == offset && *length > 0).then_some(*length),
+    FSTEntry::Directory { children, .. } => children.iter().find_map(|child| find_file_at(child, offset)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn record_round_trips_and_restores_moved_data() {
+    let original: Vec<u8> = (0..64).collect();
+    let mut patched = original.clone();
+    patched[0..4].fill(0xAA);
+    patched[16..32].fill(0xBB);
+    patched[40..48].fill(0xCC);
+    // the patcher keeps what it overwrote at 16 after the end
+    patched.extend_from_slice(&original[16..32]);
+    let mut original_with_fill = original.clone();
+    original_with_fill[40..48].fill(7);
+
+    let record = RevertRecord {
+      original_size: 64,
+      original_hash: Some(format!("{:x}", md5::Md5::digest(&original_with_fill))),
+      chunks: vec![
+        RevertChunk::capture(&original, 0, 4),
+        RevertChunk::Moved { offset: 16, length: 16, from: 64 },
+        RevertChunk::Fill { offset: 40, length: 8, value: 7 },
+      ],
+    };
+    let record = RevertRecord::from_bytes(&record.to_bytes().unwrap()).unwrap();
+    let mut reverted = patched.clone();
+    record.apply(&mut reverted);
+    assert_eq!(reverted, original_with_fill);
+    check_reverted_hash(&record, &reverted).unwrap();
+    assert!(check_reverted_hash(&record, &original).is_err());
+  }
+}
@@ -0,0 +1,180 @@
+use crate::patch_config::{ModData, SymbolMapFormat};
+use anyhow::Result;
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+
+/// Load every symbol map that applies to the DOL with the given hash.
+/// Later maps take priority over earlier ones.
+pub fn load_symbol_maps(
+  mod_data: &ModData,
+  dol_hash: &str,
+) -> Result<HashMap<String, u32>> {
+  let mut symbols = HashMap::new();
+  for config in &mod_data.config.symbol_maps {
+    if config.dol_hash.as_deref().is_some_and(|expected_hash| expected_hash != dol_hash) {
+      continue;
+    }
+    let bytes = mod_data.read_file_or_section(&config.file, &config.section)
+      .map_err(|e| anyhow::anyhow!("Failed to load symbol map: {}", e))?;
+    symbols.extend(parse_symbol_map(&String::from_utf8_lossy(&bytes), config.format)?);
+  }
+  for path in &mod_data.symbol_map_files {
+    info!("Loading symbol map {:?}", path);
+    let text = fs::read_to_string(path)
+      .map_err(|e| anyhow::anyhow!("Failed to read symbol map {:?}: {}", path, e))?;
+    symbols.extend(parse_symbol_map(&text, SymbolMapFormat::Auto)?);
+  }
+  info!("Loaded {} game symbols from symbol maps", symbols.len());
+  Ok(symbols)
+}
+
+pub fn parse_symbol_map(text: &str, format: SymbolMapFormat) -> Result<HashMap<String, u32>> {
+  let format = match format {
+    SymbolMapFormat::Auto => detect_format(text),
+    format => format,
+  };
+  let mut symbols = HashMap::new();
+  match format {
+    SymbolMapFormat::SymbolsTxt => {
+      for line in text.lines() {
+        if let Some((name, address)) = parse_symbols_txt_line(line) {
+          symbols.insert(name, address);
+        }
+      }
+    }
+    _ => {
+      let mut linker_generated = false;
+      for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.ends_with("section layout") || trimmed.starts_with("Memory map:") {
+          linker_generated = false;
+          continue;
+        }
+        if trimmed.starts_with("Linker generated symbols:") {
+          linker_generated = true;
+          continue;
+        }
+        let parsed = if linker_generated {
+          parse_linker_generated_line(trimmed)
+        } else {
+          parse_map_line(trimmed, format == SymbolMapFormat::Dolphin)
+        };
+        if let Some((name, address)) = parsed {
+          symbols.insert(name, address);
+        }
+      }
+    }
+  }
+  if symbols.is_empty() {
+    return Err(anyhow::anyhow!("No symbols found in symbol map"));
+  }
+  Ok(symbols)
+}
+
+fn detect_format(text: &str) -> SymbolMapFormat {
+  if text.lines().any(|line| line.contains("section layout")) {
+    if text.lines().any(|line| line.contains("File") && line.contains("Starting")) {
+      SymbolMapFormat::CodeWarrior
+    } else {
+      SymbolMapFormat::Dolphin
+    }
+  } else {
+    SymbolMapFormat::SymbolsTxt
+  }
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+  let value = value.trim_start_matches("0x").trim_start_matches("0X");
+  u32::from_str_radix(value, 16).ok()
+}
+
+/// `__start = .init:0x80003100; // type:function size:0x220`
+fn parse_symbols_txt_line(line: &str) -> Option<(String, u32)> {
+  let line = line.split("//").next()?.trim();
+  let (name, rest) = line.split_once('=')?;
+  let value = rest.trim().trim_end_matches(';').trim();
+  // the section prefix is optional
+  let address = value.rsplit(':').next()?;
+  Some((name.trim().to_string(), parse_hex(address)?))
+}
+
+/// Dolphin: `80003100 00000220 80003100 0 __start`
+/// CodeWarrior: `00000000 000220 80003100 00000100  4 __start  crt0.o`
+/// (older CodeWarrior maps have no file offset column).
+/// Dolphin names are the rest of the line, demangled names can have spaces.
+fn parse_map_line(line: &str, name_to_end: bool) -> Option<(String, u32)> {
+  let tokens: Vec<&str> = line.split_whitespace().collect();
+  if tokens.len() < 5 || tokens[0] == "UNUSED" {
+    return None;
+  }
+  parse_hex(tokens[0])?;
+  parse_hex(tokens[1])?;
+  let address = parse_hex(tokens[2])?;
+  let name_index = if tokens.len() >= 6 && tokens[3].len() == 8 && parse_hex(tokens[3]).is_some() {
+    5
+  } else {
+    4
+  };
+  tokens[name_index - 1].parse::<u32>().ok()?;
+  let name = if name_to_end {
+    skip_tokens(line, name_index)?
+  } else {
+    tokens[name_index]
+  };
+  if name.starts_with('.') || address == 0 {
+    return None;
+  }
+  Some((name.to_string(), address))
+}
+
+/// The rest of `line` after `count` whitespace separated tokens
+fn skip_tokens(line: &str, count: usize) -> Option<&str> {
+  let mut rest = line.trim_start();
+  for _ in 0..count {
+    rest = rest[rest.find(char::is_whitespace)?..].trim_start();
+  }
+  Some(rest.trim_end())
+}
+
+/// `_db_stack_addr 804d1a48`
+fn parse_linker_generated_line(line: &str) -> Option<(String, u32)> {
+  let tokens: Vec<&str> = line.split_whitespace().collect();
+  if tokens.len() != 2 {
+    return None;
+  }
+  Some((tokens[0].to_string(), parse_hex(tokens[1])?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dolphin_names_keep_spaces() {
+    let text = "\
+.text section layout
+80003100 00000220 80003100 0 __start
+80012340 00000040 80012340 4 CGameState::Update(float)
+80012380 00000040 80012380 4 CGameState::SetFlag(unsigned int, bool)
+";
+    let symbols = parse_symbol_map(text, SymbolMapFormat::Auto).unwrap();
+    assert_eq!(symbols.get("__start"), Some(&0x80003100));
+    assert_eq!(symbols.get("CGameState::Update(float)"), Some(&0x80012340));
+    assert_eq!(symbols.get("CGameState::SetFlag(unsigned int, bool)"), Some(&0x80012380));
+  }
+
+  #[test]
+  fn codewarrior_names_stop_before_the_object_file() {
+    let text = "\
+.init section layout
+  Starting        Virtual  File
+  address  Size   address  offset
+  00000000 000220 80003100 00000100  4 __start \tcrt0.o
+  00000220 000040 80003320 00000320  4 Update__10CGameStateFf \tCGameState.o
+";
+    let symbols = parse_symbol_map(text, SymbolMapFormat::Auto).unwrap();
+    assert_eq!(symbols.get("__start"), Some(&0x80003100));
+    assert_eq!(symbols.get("Update__10CGameStateFf"), Some(&0x80003320));
+  }
+}
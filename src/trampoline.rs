@@ -0,0 +1,174 @@
+use crate::ppc_asm::{assemble, build_b_rel24};
+use anyhow::Result;
+
+/// Saves the volatile registers, calls the hook function, restores them
+const CALL_HOOK: &str = "
+  stwu r1, -0x40(r1)
+  stw r0, 0x8(r1)
+  mflr r0
+  stw r0, 0xC(r1)
+  mfctr r0
+  stw r0, 0x10(r1)
+  mfcr r0
+  stw r0, 0x14(r1)
+  stw r3, 0x18(r1)
+  stw r4, 0x1C(r1)
+  stw r5, 0x20(r1)
+  stw r6, 0x24(r1)
+  stw r7, 0x28(r1)
+  stw r8, 0x2C(r1)
+  stw r9, 0x30(r1)
+  stw r10, 0x34(r1)
+  stw r11, 0x38(r1)
+  stw r12, 0x3C(r1)
+  lis r12, hook_function@h
+  ori r12, r12, hook_function@l
+  mtctr r12
+  bctrl
+  lwz r3, 0x18(r1)
+  lwz r4, 0x1C(r1)
+  lwz r5, 0x20(r1)
+  lwz r6, 0x24(r1)
+  lwz r7, 0x28(r1)
+  lwz r8, 0x2C(r1)
+  lwz r9, 0x30(r1)
+  lwz r10, 0x34(r1)
+  lwz r11, 0x38(r1)
+  lwz r12, 0x3C(r1)
+  lwz r0, 0x14(r1)
+  mtcr r0
+  lwz r0, 0x10(r1)
+  mtctr r0
+  lwz r0, 0xC(r1)
+  mtlr r0
+  lwz r0, 0x8(r1)
+  addi r1, r1, 0x40
+";
+
+/// Build a trampoline placed at `address` for a hook at `hook_address`.
+///
+/// The trampoline calls `function` with the integer registers as they were at the hook, restores
+/// them (floating point registers are not saved), runs the overwritten `original` instruction and
+/// branches back to the instruction after the hook.
+pub fn build_trampoline(hook_address: u32, address: u32, original: u32, function: u32) -> Result<Vec<u8>> {
+  let mut code = assemble(CALL_HOOK, address, |name| (name == "hook_function").then_some(function))?;
+  let relocated_address = address + code.len() as u32;
+  for instruction in relocate_instruction(original, hook_address, relocated_address)? {
+    code.extend_from_slice(&instruction.to_be_bytes());
+  }
+  let branch_back_address = address + code.len() as u32;
+  code.extend_from_slice(&build_b_rel24(branch_back_address, hook_address + 4, false)?.to_be_bytes());
+  Ok(code)
+}
+
+/// Jumps to `target` from anywhere in memory. Clobbers r12 and ctr, which are volatile across calls,
+/// so a veneer can only stand in for branches at function boundaries.
+const VENEER: &str = "
+  lis r12, veneer_target@h
+  ori r12, r12, veneer_target@l
+  mtctr r12
+  bctr
+";
+
+/// Build a veneer placed at `address` that jumps to `target`.
+/// `bl` to the veneer keeps the return address, since `bctr` does not touch the link register.
+pub fn build_veneer(address: u32, target: u32) -> Result<Vec<u8>> {
+  assemble(VENEER, address, |name| (name == "veneer_target").then_some(target))
+}
+
+/// Move an instruction from `from` to `to`, fixing up PC-relative branches
+pub fn relocate_instruction(instruction: u32, from: u32, to: u32) -> Result<Vec<u32>> {
+  let opcode = instruction >> 26;
+  let absolute = instruction & 2 != 0;
+  let link = instruction & 1 != 0;
+  match opcode {
+    // b, bl
+    18 if !absolute => {
+      let target = from.wrapping_add(sign_extend(instruction & 0x03FF_FFFC, 26));
+      Ok(vec![build_b_rel24(to, target, link)?])
+    }
+    // bc, bcl
+    16 if !absolute => {
+      // the displacement is only 16 bits, so keep the condition but branch to a nearby
+      // unconditional branch that can reach the original target:
+      //   bc BO, BI, +8  (sets LR like the original when it links)
+      //   b +8
+      //   b target
+      let target = from.wrapping_add(sign_extend(instruction & 0xFFFC, 16));
+      let condition = (instruction & !0xFFFF) | 8 | link as u32;
+      Ok(vec![
+        condition,
+        build_b_rel24(to + 4, to + 12, false)?,
+        build_b_rel24(to + 8, target, false)?,
+      ])
+    }
+    _ => Ok(vec![instruction]),
+  }
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+  let shift = 32 - bits;
+  (((value << shift) as i32) >> shift) as u32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Target of a relative `b`/`bl` at `address`
+  fn b_target(instruction: u32, address: u32) -> u32 {
+    assert_eq!(instruction >> 26, 18, "not a branch: 0x{:08X}", instruction);
+    address.wrapping_add(sign_extend(instruction & 0x03FF_FFFC, 26))
+  }
+
+  #[test]
+  fn relocated_branches_keep_their_target() {
+    // b 0x80003200 and bl 0x80002000 at 0x80003100
+    let relocated = relocate_instruction(0x4800_0100, 0x80003100, 0x80400000).unwrap();
+    assert_eq!(relocated.len(), 1);
+    assert_eq!(relocated[0] & 3, 0);
+    assert_eq!(b_target(relocated[0], 0x80400000), 0x80003200);
+
+    let relocated = relocate_instruction(0x4BFF_EF01, 0x80003100, 0x80400000).unwrap();
+    assert_eq!(relocated[0] & 3, 1);
+    assert_eq!(b_target(relocated[0], 0x80400000), 0x80002000);
+  }
+
+  #[test]
+  fn relocated_conditional_branches_keep_their_condition_and_target() {
+    // beq 0x80003120 at 0x80003100
+    let relocated = relocate_instruction(0x4182_0020, 0x80003100, 0x80400000).unwrap();
+    assert_eq!(relocated.len(), 3);
+    // beq +8, to the branch to the original target
+    assert_eq!(relocated[0], 0x4182_0008);
+    assert_eq!(b_target(relocated[1], 0x80400004), 0x8040000C);
+    assert_eq!(b_target(relocated[2], 0x80400008), 0x80003120);
+
+    // bnel 0x800030F0 keeps setting the link register
+    let relocated = relocate_instruction(0x4082_FFF1, 0x80003100, 0x80400000).unwrap();
+    assert_eq!(relocated[0], 0x4082_0009);
+    assert_eq!(relocated[2] & 1, 0);
+    assert_eq!(b_target(relocated[2], 0x80400008), 0x800030F0);
+  }
+
+  #[test]
+  fn branches_out_of_range_after_relocation_are_errors() {
+    assert!(relocate_instruction(0x4182_0020, 0x80003100, 0x83000000).is_err());
+    assert!(relocate_instruction(0x4800_0100, 0x80003100, 0x83000000).is_err());
+  }
+
+  #[test]
+  fn other_instructions_are_copied() {
+    // addi r3, r3, 1 and an absolute ba
+    assert_eq!(relocate_instruction(0x3863_0001, 0x80003100, 0x80400000).unwrap(), [0x3863_0001]);
+    assert_eq!(relocate_instruction(0x4800_3102, 0x80003100, 0x80400000).unwrap(), [0x4800_3102]);
+  }
+
+  #[test]
+  fn veneers_load_the_target_into_ctr() {
+    let veneer = build_veneer(0x80400000, 0x8123_4567).unwrap();
+    let words: Vec<u32> = veneer.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect();
+    // lis r12, 0x8123; ori r12, r12, 0x4567; mtctr r12; bctr
+    assert_eq!(words, [0x3D80_8123, 0x618C_4567, 0x7D89_03A6, 0x4E80_0420]);
+  }
+}